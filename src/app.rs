@@ -1,25 +1,40 @@
 use std::marker::PhantomData;
-#[cfg(feature = "gpio")]
-use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 
 use futures::prelude::*;
-use rumqtt::QoS;
-use tokio::sync::{mpsc, watch};
+use rumqtt::Publish;
+use tokio::sync::{broadcast, mpsc, watch};
 
 use crate::communication::create_abortable_task;
-use crate::embedded::command::{LayoutCommand, LayoutCommandListener};
+use crate::communication::event::StatusEvent;
+use crate::control::configuration::ControlConfig;
+use crate::control::socket::ControlSocket;
+use crate::control::Controller;
+use crate::embedded::command::{LayoutCommandListener, LayoutRequest, ValveEvent};
 use crate::embedded::configuration::LayoutConfig;
+use crate::embedded::fake::{FakePinLayout, FakeToggleValve};
 #[cfg(feature = "gpio")]
 use crate::embedded::gpio::{GpioPinLayout, GpioToggleValve};
+use crate::embedded::simulator::spawn_console;
 use crate::embedded::{PinLayout, ToggleValve};
+#[cfg(feature = "http")]
+use crate::http::configuration::HttpConfig;
+#[cfg(feature = "http")]
+use crate::http::HttpServer;
 use crate::mqtt::command::MqttCommandListener;
-use crate::mqtt::configuration::MqttConfig;
-use crate::mqtt::status::{LayoutConfigStatus, PinLayoutStatus, WateringScheduleConfigStatus};
+use crate::mqtt::configuration::{MqttConfig, TopicSchemeConfig};
+use crate::mqtt::connection::MqttConnection;
+use crate::mqtt::home_assistant::HomeAssistantDiscovery;
+use crate::mqtt::status::{
+    DeviceStatus, LayoutConfigStatus, PinLayoutStatus, ValveEventStatus, WarningStatus,
+    WateringScheduleConfigStatus,
+};
 use crate::mqtt::MqttSession;
+use crate::options::Options;
+use crate::reload::ConfigReloader;
 use crate::schedule::{
-    WateringConfigCommand, WateringConfigCommandListener, WateringScheduleConfigs,
-    WateringScheduler,
+    Clock, ManualClock, WateringConfigCommandListener, WateringConfigRequest,
+    WateringScheduleConfigs, WateringScheduler,
 };
 
 pub struct App<T, U>
@@ -30,10 +45,13 @@ where
     ctrl_c_sender: watch::Sender<String>,
     ctrl_c_receiver: watch::Receiver<String>,
 
-    layout_command_sender: Option<mpsc::Sender<LayoutCommand>>,
+    layout_command_sender: Option<mpsc::Sender<LayoutRequest>>,
     layout_status_send_sender: Option<mpsc::Sender<()>>,
+    valve_event_sender: mpsc::Sender<ValveEvent>,
+    valve_event_receiver: Option<mpsc::Receiver<ValveEvent>>,
+    status_event_sender: broadcast::Sender<StatusEvent>,
 
-    watering_config_command_sender: Option<mpsc::Sender<WateringConfigCommand>>,
+    watering_config_command_sender: Option<mpsc::Sender<WateringConfigRequest>>,
     watering_config_status_sender: Option<mpsc::Sender<()>>,
 
     layout_config: Arc<Mutex<LayoutConfig>>,
@@ -42,18 +60,71 @@ where
 
     mqtt_config: Arc<Mutex<MqttConfig>>,
     mqtt_session: Arc<Mutex<MqttSession>>,
+    mqtt_reconnect_senders: Vec<mpsc::Sender<()>>,
+    mqtt_publish_sender: Option<mpsc::Sender<Publish>>,
 
     watering_schedule_config: Arc<Mutex<WateringScheduleConfigs>>,
     watering_scheduler: Option<Arc<Mutex<WateringScheduler>>>,
+    clock: Arc<dyn Clock>,
 }
 
 #[cfg(feature = "gpio")]
 impl App<GpioPinLayout, GpioToggleValve> {
     pub fn listen_to_button_presses(&self) {
-        let guard = self.layout.lock().unwrap();
-        guard
-            .deref()
-            .spawn_button_streams(self.ctrl_c_receiver.clone());
+        if let Some(layout_command_tx) = &self.layout_command_sender {
+            self.layout
+                .lock()
+                .unwrap()
+                .spawn_button_streams(self.ctrl_c_receiver.clone(), layout_command_tx.clone());
+        } else {
+            error!("layout command sender not defined");
+        }
+    }
+}
+
+impl App<FakePinLayout, FakeToggleValve> {
+    pub fn report_sensor_readings(&self) {
+        let status_event_tx = self.status_event_sender.clone();
+        let task = self
+            .layout
+            .lock()
+            .unwrap()
+            .get_simulator()
+            .subscribe_sensor_readings()
+            .into_stream()
+            .for_each(move |reading| {
+                match reading {
+                    Ok(reading) => {
+                        debug!("{:?}", reading);
+                        // fails only without live status subscribers
+                        let _ = status_event_tx.send(StatusEvent::SensorReading(reading));
+                    }
+                    Err(e) => warn!("sensor readings were missed = {:?}", e),
+                }
+                future::ready(())
+            });
+        spawn_task(
+            self.ctrl_c_receiver.clone(),
+            task,
+            String::from("report_sensor_readings"),
+        );
+    }
+
+    pub fn listen_to_simulator_console(&self, clock: ManualClock) {
+        if let Some(layout_command_tx) = &self.layout_command_sender {
+            spawn_console(
+                Arc::clone(&self.layout),
+                layout_command_tx.clone(),
+                clock.clone(),
+            );
+        } else {
+            error!("layout command sender not defined");
+        }
+        spawn_task(
+            self.ctrl_c_receiver.clone(),
+            clock.run_in_real_time(),
+            String::from("simulator_clock"),
+        );
     }
 }
 
@@ -69,18 +140,26 @@ where
         mqtt_session: Arc<Mutex<MqttSession>>,
         watering_schedule_config: WateringScheduleConfigs,
         valve_type: PhantomData<U>,
+        clock: Arc<dyn Clock>,
     ) -> Self
     where
         T: PinLayout<U> + Send + 'static,
         U: ToggleValve + Send + 'static,
     {
         let (ctrl_c_sender, ctrl_c_receiver) = watch::channel("hello".to_string());
+        // live status subscribers come and go, see `Controller`
+        let (status_event_sender, _) = broadcast::channel(64);
+        // the layout command listener sends valve events whether they are reported or not
+        let (valve_event_sender, valve_event_receiver) = mpsc::channel(64);
 
         App {
             ctrl_c_sender,
             ctrl_c_receiver,
             layout_command_sender: None,
             layout_status_send_sender: None,
+            valve_event_sender,
+            valve_event_receiver: Some(valve_event_receiver),
+            status_event_sender,
 
             watering_config_command_sender: None,
             watering_config_status_sender: None,
@@ -91,9 +170,12 @@ where
 
             mqtt_config: Arc::new(Mutex::new(mqtt_config)),
             mqtt_session,
+            mqtt_reconnect_senders: Vec::new(),
+            mqtt_publish_sender: None,
 
             watering_schedule_config: Arc::new(Mutex::new(watering_schedule_config)),
             watering_scheduler: None,
+            clock,
         }
     }
 
@@ -109,6 +191,64 @@ where
         );
     }
 
+    pub fn report_warnings(&self, warnings: &[String]) {
+        let warning_status =
+            WarningStatus::report(warnings.to_vec(), Arc::clone(&self.mqtt_session));
+        spawn_task(
+            self.ctrl_c_receiver.clone(),
+            warning_status,
+            String::from("report_warnings"),
+        );
+    }
+
+    pub fn report_device(&mut self) {
+        let (reconnect_sender, reconnect_receiver): (mpsc::Sender<()>, mpsc::Receiver<()>) =
+            mpsc::channel(16);
+
+        self.mqtt_reconnect_senders.push(reconnect_sender);
+
+        let task = DeviceStatus::report(
+            Arc::clone(&self.layout_config),
+            Arc::clone(&self.watering_schedule_config),
+            Arc::clone(&self.mqtt_session),
+            reconnect_receiver,
+        );
+        spawn_task(
+            self.ctrl_c_receiver.clone(),
+            task,
+            String::from("report_device"),
+        );
+    }
+
+    pub fn report_home_assistant_discovery(&mut self) {
+        if self.mqtt_config.lock().unwrap().home_assistant.is_none() {
+            return;
+        }
+        if self.mqtt_config.lock().unwrap().topic_scheme == Some(TopicSchemeConfig::Homie) {
+            warn!("home assistant discovery is not supported with the homie topic scheme");
+            return;
+        }
+
+        let (reconnect_sender, reconnect_receiver): (mpsc::Sender<()>, mpsc::Receiver<()>) =
+            mpsc::channel(16);
+
+        self.mqtt_reconnect_senders.push(reconnect_sender);
+
+        let task = HomeAssistantDiscovery::report(
+            Arc::clone(&self.layout_config),
+            Arc::clone(&self.watering_schedule_config),
+            Arc::clone(&self.mqtt_session),
+            Arc::clone(&self.mqtt_config),
+            reconnect_receiver,
+            self.status_event_sender.subscribe(),
+        );
+        spawn_task(
+            self.ctrl_c_receiver.clone(),
+            task,
+            String::from("report_home_assistant_discovery"),
+        );
+    }
+
     pub fn report_pin_layout_status(&mut self) {
         let (layout_status_send_sender, layout_status_send_receiver): (
             mpsc::Sender<()>,
@@ -122,6 +262,7 @@ where
             Arc::clone(&self.mqtt_session),
             Arc::clone(&self.mqtt_config),
             layout_status_send_receiver,
+            self.status_event_sender.clone(),
         )
         .map(|_| ());
         spawn_task(
@@ -131,6 +272,27 @@ where
         );
     }
 
+    pub fn report_valve_events(&mut self) {
+        let valve_event_receiver = match self.valve_event_receiver.take() {
+            Some(valve_event_receiver) => valve_event_receiver,
+            None => {
+                error!("valve events already reported");
+                return;
+            }
+        };
+
+        let task = ValveEventStatus::report(
+            Arc::clone(&self.mqtt_session),
+            valve_event_receiver,
+            self.status_event_sender.clone(),
+        );
+        spawn_task(
+            self.ctrl_c_receiver.clone(),
+            task,
+            String::from("report_valve_events"),
+        );
+    }
+
     pub fn report_watering_configuration(&mut self) {
         let (watering_configuration_status_sender, watering_configuration_status_receiver): (
             mpsc::Sender<()>,
@@ -144,6 +306,7 @@ where
             Arc::clone(&self.mqtt_session),
             Arc::clone(&self.mqtt_config),
             watering_configuration_status_receiver,
+            self.status_event_sender.clone(),
         );
 
         spawn_task(
@@ -155,8 +318,8 @@ where
 
     pub fn listen_to_layout_commands(&mut self) {
         let (layout_command_sender, layout_command_receiver): (
-            mpsc::Sender<LayoutCommand>,
-            mpsc::Receiver<LayoutCommand>,
+            mpsc::Sender<LayoutRequest>,
+            mpsc::Receiver<LayoutRequest>,
         ) = tokio::sync::mpsc::channel(16);
 
         self.layout_command_sender = Some(layout_command_sender);
@@ -166,6 +329,8 @@ where
                 Arc::clone(&self.layout),
                 layout_command_receiver,
                 layout_status_tx.clone(),
+                self.valve_event_sender.clone(),
+                Arc::clone(&self.clock),
             );
 
             spawn_task(
@@ -174,14 +339,14 @@ where
                 String::from("listen_to_layout_commands"),
             );
         } else {
-            println!("layout status sender not defined");
+            error!("layout status sender not defined");
         }
     }
 
     pub fn listen_to_watering_config_commands(&mut self) {
         let (watering_config_command_sender, watering_config_command_receiver): (
-            mpsc::Sender<WateringConfigCommand>,
-            mpsc::Receiver<WateringConfigCommand>,
+            mpsc::Sender<WateringConfigRequest>,
+            mpsc::Receiver<WateringConfigRequest>,
         ) = tokio::sync::mpsc::channel(16);
 
         self.watering_config_command_sender = Some(watering_config_command_sender);
@@ -191,6 +356,7 @@ where
                 let task = WateringConfigCommandListener::listen_to_commands(
                     Arc::clone(&self.watering_schedule_config),
                     Arc::clone(watering_scheduler),
+                    Arc::clone(&self.layout_config),
                     watering_config_command_receiver,
                     watering_config_status_tx.clone(),
                 );
@@ -201,34 +367,111 @@ where
                     String::from("listen_to_watering_config_commands"),
                 );
             } else {
-                println!("watering scheduler not defined")
+                error!("watering scheduler not defined")
             }
         } else {
-            println!("layout status sender not defined");
+            error!("layout status sender not defined");
         }
     }
 
-    pub fn report_online(&self) {
-        let mut session = self.mqtt_session.lock().unwrap();
-        let topic = format!("{}/garden-butler/status/health", session.get_client_id());
-        let message = "ONLINE";
-        match session.publish(topic, QoS::ExactlyOnce, true, message) {
-            Ok(_) => {
-                println!("Garden buttler started ...");
-            }
-            Err(e) => {
-                println!("error starting garden butler = {:?}", e);
+    pub fn listen_to_control_socket(&self, control_config: &ControlConfig) {
+        match ControlSocket::new(control_config, self.create_controller()) {
+            Ok(socket) => spawn_task(
+                self.ctrl_c_receiver.clone(),
+                socket,
+                String::from("listen_to_control_socket"),
+            ),
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    #[cfg(feature = "http")]
+    pub fn serve_http(&self, http_config: &HttpConfig) {
+        match HttpServer::new(http_config, self.create_controller()) {
+            Ok(server) => spawn_task(
+                self.ctrl_c_receiver.clone(),
+                server,
+                String::from("serve_http"),
+            ),
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    pub fn reload_on_hangup(&self, options: &Options) {
+        match (
+            &self.layout_status_send_sender,
+            &self.watering_config_status_sender,
+            &self.watering_scheduler,
+        ) {
+            (Some(layout_status_tx), Some(watering_config_status_tx), Some(watering_scheduler)) => {
+                let reloader = ConfigReloader::new(
+                    options.clone(),
+                    Arc::clone(&self.layout),
+                    Arc::clone(&self.layout_config),
+                    Arc::clone(&self.watering_schedule_config),
+                    Arc::clone(watering_scheduler),
+                    Arc::clone(&self.mqtt_session),
+                    &self.layout_command_sender,
+                    layout_status_tx.clone(),
+                    watering_config_status_tx.clone(),
+                    &self.mqtt_reconnect_senders,
+                );
+                spawn_task(
+                    self.ctrl_c_receiver.clone(),
+                    reloader.listen_to_hangups(),
+                    String::from("reload_on_hangup"),
+                );
             }
+            _ => error!("status senders or watering scheduler not defined"),
         }
     }
 
-    pub fn listen_to_mqtt_commands(&self) {
-        let mqtt_command_listener = MqttCommandListener::new(
+    fn create_controller(&self) -> Controller {
+        Controller::new(
+            Arc::clone(&self.layout),
+            Arc::clone(&self.layout_config),
+            Arc::clone(&self.watering_schedule_config),
             Arc::clone(&self.mqtt_session),
-            Arc::clone(&self.mqtt_config),
             &self.layout_command_sender,
             &self.watering_config_command_sender,
+            self.status_event_sender.clone(),
+            Arc::clone(&self.clock),
+        )
+    }
+
+    pub fn connect_to_mqtt(&self) {
+        let task = MqttConnection::run(
+            Arc::clone(&self.mqtt_session),
+            self.mqtt_publish_sender.clone(),
+            self.mqtt_reconnect_senders.clone(),
+            self.status_event_sender.clone(),
+        );
+        spawn_task(
+            self.ctrl_c_receiver.clone(),
+            task,
+            String::from("connect_to_mqtt"),
         );
+    }
+
+    pub fn listen_to_mqtt_commands(&mut self) {
+        let (mqtt_publish_sender, mqtt_publish_receiver): (
+            mpsc::Sender<Publish>,
+            mpsc::Receiver<Publish>,
+        ) = mpsc::channel(16);
+
+        let mqtt_command_listener = match MqttCommandListener::new(
+            Arc::clone(&self.mqtt_session),
+            mqtt_publish_receiver,
+            &self.layout_command_sender,
+            &self.watering_config_command_sender,
+        ) {
+            Ok(mqtt_command_listener) => mqtt_command_listener,
+            Err(e) => {
+                error!("mqtt commands are disabled = {}", e);
+                return;
+            }
+        };
+        self.mqtt_publish_sender = Some(mqtt_publish_sender);
         spawn_task(
             self.ctrl_c_receiver.clone(),
             mqtt_command_listener,
@@ -239,19 +482,22 @@ where
     pub fn start_watering_schedules(&mut self) {
         //spawn preconfigured automatic watering tasks
         if let Some(layout_command_tx) = &self.layout_command_sender {
-            let mut scheduler =
-                WateringScheduler::new(layout_command_tx.clone(), self.ctrl_c_receiver.clone());
+            let mut scheduler = WateringScheduler::new(
+                layout_command_tx.clone(),
+                self.ctrl_c_receiver.clone(),
+                Arc::clone(&self.clock),
+            );
             scheduler.start(&self.watering_schedule_config);
             self.watering_scheduler = Some(Arc::new(Mutex::new(scheduler)));
         } else {
-            println!("layout command sender not defined");
+            error!("layout command sender not defined");
         }
     }
 
     pub async fn wait_for_termination(self) -> Result<(), ()> {
         // listen for program termination
         tokio::signal::ctrl_c()
-            .map_err(|e| println!("ctrlc-error = {:?}", e))
+            .map_err(|e| error!("ctrlc-error = {:?}", e))
             .await?;
 
         // send shut off commands to running tasks
@@ -266,7 +512,7 @@ fn spawn_task(
     task: impl Future<Output = ()> + Sized + Send + 'static,
     task_name: String,
 ) {
-    println!("spawning task {}", task_name);
+    debug!("spawning task {}", task_name);
     let task1 = create_abortable_task(task, task_name, ctrl_c_receiver);
     tokio::task::spawn(task1);
 }
@@ -0,0 +1,359 @@
+//! Controls a running garden butler over its control socket, e.g.
+//! `garden-butler-ctl open 27 10` waters with valve 27 for ten minutes.
+
+extern crate serde_json;
+
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process;
+
+use serde_json::{json, Value};
+
+#[path = "../control/defaults.rs"]
+mod defaults;
+
+use defaults::DEFAULT_SOCKET_PATH;
+
+const USAGE: &str = "usage: garden-butler-ctl [--socket <path>] <command>
+
+commands:
+    valves                                  valves and their state
+    health                                  mqtt connection state
+    layout                                  layout config
+    open <valve> [<minutes>]                opens the valve, closes it after <minutes> if given
+    close <valve>                           closes the valve
+    schedules                               all schedules
+    schedule <id>                           one schedule, e.g. 27-0600-0630
+    create <valve> <hh:mm> <hh:mm> [disabled]
+                                            creates a schedule from start to end
+    enable <id>                             enables the schedule
+    disable <id>                            disables the schedule
+    delete <id>                             deletes the schedule
+    runs [<count>]                          the next scheduled runs, 5 by default
+    events                                  prints status changes until interrupted
+
+The socket is taken from --socket, GARDEN_BUTLER_SOCKET or";
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let socket_path = match take_socket_path(&mut args) {
+        Ok(path) => path,
+        Err(e) => exit_with(&e),
+    };
+    let request = match parse_request(&args) {
+        Ok(request) => request,
+        Err(e) => exit_with(&e),
+    };
+    if let Err(e) = run(&socket_path, &request) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn exit_with(error: &str) -> ! {
+    eprintln!("{}\n\n{} {}.", error, USAGE, DEFAULT_SOCKET_PATH);
+    process::exit(2);
+}
+
+fn take_socket_path(args: &mut Vec<String>) -> Result<String, String> {
+    match args.iter().position(|arg| arg == "--socket") {
+        Some(i) if i + 1 < args.len() => {
+            let path = args.remove(i + 1);
+            args.remove(i);
+            Ok(path)
+        }
+        Some(_) => Err(String::from("--socket needs a path")),
+        None => {
+            Ok(env::var("GARDEN_BUTLER_SOCKET")
+                .unwrap_or_else(|_| String::from(DEFAULT_SOCKET_PATH)))
+        }
+    }
+}
+
+fn parse_request(args: &[String]) -> Result<Value, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let request = match args.as_slice() {
+        ["valves"] => json!({"command": "valves"}),
+        ["health"] => json!({"command": "health"}),
+        ["layout"] => json!({"command": "layout"}),
+        ["open", valve] => json!({"command": "open", "valve": parse_number::<u8>(valve)?}),
+        ["open", valve, minutes] => json!({
+            "command": "open",
+            "valve": parse_number::<u8>(valve)?,
+            "minutes": parse_number::<u64>(minutes)?
+        }),
+        ["close", valve] => json!({"command": "close", "valve": parse_number::<u8>(valve)?}),
+        ["schedules"] => json!({"command": "schedules"}),
+        ["schedule", id] => json!({"command": "schedule", "id": id}),
+        ["create", valve, start, end] => create_request(valve, start, end, true)?,
+        ["create", valve, start, end, "disabled"] => create_request(valve, start, end, false)?,
+        ["enable", id] => json!({"command": "enable", "id": id}),
+        ["disable", id] => json!({"command": "disable", "id": id}),
+        ["delete", id] => json!({"command": "delete", "id": id}),
+        ["runs"] => json!({"command": "runs"}),
+        ["runs", count] => json!({"command": "runs", "count": parse_number::<usize>(count)?}),
+        ["events"] => json!({"command": "events"}),
+        [] => return Err(String::from("command is missing")),
+        _ => return Err(format!("unknown command {}", args.join(" "))),
+    };
+    Ok(request)
+}
+
+fn create_request(valve: &str, start: &str, end: &str, enabled: bool) -> Result<Value, String> {
+    let (start_hour, start_minute) = parse_time(start)?;
+    let (end_hour, end_minute) = parse_time(end)?;
+    Ok(json!({
+        "command": "create",
+        "schedule": {
+            "valve": parse_number::<u8>(valve)?,
+            "enabled": enabled,
+            "schedule": {
+                "start_hour": start_hour,
+                "start_minute": start_minute,
+                "end_hour": end_hour,
+                "end_minute": end_minute
+            }
+        }
+    }))
+}
+
+fn parse_time(time: &str) -> Result<(u8, u8), String> {
+    let (hour, minute) = time
+        .split_once(':')
+        .ok_or_else(|| format!("invalid time {}, expected hh:mm", time))?;
+    let hour = parse_number::<u8>(hour)?;
+    let minute = parse_number::<u8>(minute)?;
+    if hour > 23 || minute > 59 {
+        return Err(format!("invalid time {}", time));
+    }
+    Ok((hour, minute))
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid number {}", value))
+}
+
+fn run(socket_path: &str, request: &Value) -> Result<(), String> {
+    let mut stream = UnixStream::connect(socket_path)
+        .map_err(|e| format!("could not connect to {} = {}", socket_path, e))?;
+    writeln!(stream, "{}", request).map_err(|e| e.to_string())?;
+    let mut lines = BufReader::new(stream).lines();
+
+    if request["command"] == "events" {
+        for line in lines {
+            println!("{}", line.map_err(|e| e.to_string())?);
+        }
+        return Ok(());
+    }
+
+    let line = lines
+        .next()
+        .ok_or_else(|| String::from("the butler closed the connection"))?
+        .map_err(|e| e.to_string())?;
+    let response: Value = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+    if response["success"] != true {
+        return Err(format!(
+            "{}: {}",
+            response["error_code"].as_str().unwrap_or("ERROR"),
+            response["error"].as_str().unwrap_or("unknown error")
+        ));
+    }
+    print_result(&request["command"], &response["result"]);
+    Ok(())
+}
+
+fn print_result(command: &Value, result: &Value) {
+    match command.as_str() {
+        Some("valves") | Some("open") | Some("close") => print_valves(result),
+        Some("schedules") | Some("create") | Some("enable") | Some("disable") | Some("delete") => {
+            print_schedules(result)
+        }
+        Some("schedule") => print_schedule(result),
+        Some("runs") => print_runs(result),
+        _ => println!("{}", serde_json::to_string_pretty(result).unwrap()),
+    }
+}
+
+fn print_valves(status: &Value) {
+    for valve in status["valves"].as_array().into_iter().flatten() {
+        println!(
+            "valve {:>3}  {}",
+            valve["valve_pin_number"],
+            text(&valve["status"])
+        );
+    }
+    if let Some(master_valve) = status["master_valve"].as_str() {
+        println!("master valve {}", master_valve);
+    }
+    if let Some(pump) = status["pump"].as_str() {
+        println!("pump {}", pump);
+    }
+}
+
+fn print_schedules(configs: &Value) {
+    let schedules: Vec<&Value> = configs["schedules"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .collect();
+    if schedules.is_empty() {
+        println!("no schedules");
+    }
+    for schedule in schedules {
+        print_schedule(schedule);
+    }
+}
+
+fn print_schedule(schedule: &Value) {
+    let t = &schedule["schedule"];
+    let (start_hour, start_minute) = (number(&t["start_hour"]), number(&t["start_minute"]));
+    let (end_hour, end_minute) = (number(&t["end_hour"]), number(&t["end_minute"]));
+    println!(
+        "{}  valve {:>3}  {:02}:{:02} - {:02}:{:02}  {}",
+        text(&schedule["id"]),
+        schedule["valve"],
+        start_hour,
+        start_minute,
+        end_hour,
+        end_minute,
+        if schedule["enabled"] == true {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+}
+
+fn print_runs(runs: &Value) {
+    let runs: Vec<&Value> = runs.as_array().into_iter().flatten().collect();
+    if runs.is_empty() {
+        println!("nothing scheduled");
+    }
+    for run in runs {
+        println!(
+            "{}  to  {}  valve {:>3}  ({})",
+            text(&run["start"]).replace('T', " "),
+            text(&run["end"]).replace('T', " "),
+            run["valve"],
+            text(&run["id"])
+        );
+    }
+}
+
+fn text(value: &Value) -> String {
+    value
+        .as_str()
+        .map(String::from)
+        .unwrap_or_else(|| value.to_string())
+}
+
+fn number(value: &Value) -> u64 {
+    value.as_u64().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn parse(line: &str) -> Result<Value, String> {
+        parse_request(&args(line))
+    }
+
+    #[test]
+    fn commands_are_parsed_into_requests() {
+        assert_eq!(parse("valves").unwrap(), json!({"command": "valves"}));
+        assert_eq!(parse("health").unwrap(), json!({"command": "health"}));
+        assert_eq!(parse("layout").unwrap(), json!({"command": "layout"}));
+        assert_eq!(
+            parse("open 27").unwrap(),
+            json!({"command": "open", "valve": 27})
+        );
+        assert_eq!(
+            parse("open 27 10").unwrap(),
+            json!({"command": "open", "valve": 27, "minutes": 10})
+        );
+        assert_eq!(
+            parse("close 27").unwrap(),
+            json!({"command": "close", "valve": 27})
+        );
+        assert_eq!(parse("schedules").unwrap(), json!({"command": "schedules"}));
+        for command in &["schedule", "enable", "disable", "delete"] {
+            assert_eq!(
+                parse(&format!("{} 27-0600-0630", command)).unwrap(),
+                json!({"command": command, "id": "27-0600-0630"})
+            );
+        }
+        assert_eq!(parse("runs").unwrap(), json!({"command": "runs"}));
+        assert_eq!(
+            parse("runs 3").unwrap(),
+            json!({"command": "runs", "count": 3})
+        );
+        assert_eq!(parse("events").unwrap(), json!({"command": "events"}));
+    }
+
+    #[test]
+    fn schedules_are_created_enabled_unless_disabled() {
+        let schedule = |enabled| {
+            json!({
+                "command": "create",
+                "schedule": {
+                    "valve": 27,
+                    "enabled": enabled,
+                    "schedule": {
+                        "start_hour": 6,
+                        "start_minute": 5,
+                        "end_hour": 21,
+                        "end_minute": 30
+                    }
+                }
+            })
+        };
+        assert_eq!(parse("create 27 06:05 21:30").unwrap(), schedule(true));
+        assert_eq!(
+            parse("create 27 6:05 21:30 disabled").unwrap(),
+            schedule(false)
+        );
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        for line in &[
+            "",
+            "water",
+            "open",
+            "open x",
+            "open 300",
+            "open 27 ten",
+            "open 27 10 20",
+            "close",
+            "runs -1",
+            "enable",
+            "create 27 06:00",
+            "create 27 0600 0630",
+            "create 27 24:00 06:30",
+            "create 27 06:00 06:60",
+            "create 27 06:00 06:30 off",
+        ] {
+            assert!(parse(line).is_err(), "{} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn the_socket_path_is_taken_from_the_arguments() {
+        let mut arguments = args("open --socket /tmp/butler.sock 27");
+        assert_eq!(
+            take_socket_path(&mut arguments).unwrap(),
+            "/tmp/butler.sock"
+        );
+        assert_eq!(arguments, args("open 27"));
+
+        assert!(take_socket_path(&mut args("open 27 --socket")).is_err());
+    }
+}
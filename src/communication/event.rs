@@ -0,0 +1,52 @@
+use chrono::NaiveDateTime;
+
+use crate::embedded::command::ValveEvent;
+use crate::embedded::LayoutStatus;
+use crate::mqtt::ConnectionState;
+use crate::schedule::WateringScheduleConfigs;
+
+/// Live status for local clients, the same data the status reporters publish over mqtt.
+/// Serialized as `{"type": "valve_event", "data": {...}}`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum StatusEvent {
+    LayoutStatus(LayoutStatus),
+    ValveEvent(ValveEvent),
+    Schedules(WateringScheduleConfigs),
+    /// Sent whenever the mqtt connection state changes.
+    Health(HealthStatus),
+    SensorReading(SensorReading),
+}
+
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+impl StatusEvent {
+    pub fn get_name(&self) -> &'static str {
+        match *self {
+            StatusEvent::LayoutStatus(_) => "layout_status",
+            StatusEvent::ValveEvent(_) => "valve_event",
+            StatusEvent::Schedules(_) => "schedules",
+            StatusEvent::Health(_) => "health",
+            StatusEvent::SensorReading(_) => "sensor_reading",
+        }
+    }
+}
+
+/// A new value of a sensor, e.g. the soil moisture, so far only from the simulator.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SensorReading {
+    pub sensor: String,
+    pub value: f64,
+    pub timestamp: NaiveDateTime,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HealthStatus {
+    pub status: &'static str,
+    pub mqtt: ConnectionState,
+}
+
+impl HealthStatus {
+    pub fn new(mqtt: ConnectionState) -> Self {
+        HealthStatus { status: "UP", mqtt }
+    }
+}
@@ -1,8 +1,12 @@
 use futures::future::Fuse;
 use futures::prelude::*;
 use std::pin::Pin;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 use tokio::sync::watch::Receiver;
 
+pub mod event;
+
 pub async fn create_abortable_task(
     task: impl Future<Output = ()> + Sized + Send,
     task_name: String,
@@ -11,7 +15,7 @@ pub async fn create_abortable_task(
     let mut receiver = get_ctrl_c_future(rx);
     let mut tmp_task = task
         .then(|_| {
-            println!("task {} finished", task_name);
+            debug!("task {} finished", task_name);
             future::ready(())
         })
         .boxed()
@@ -26,9 +30,63 @@ pub fn get_ctrl_c_future(rx: Receiver<String>) -> Fuse<Pin<Box<dyn Future<Output
     futures::StreamExt::take(rx, 2)
         .for_each(|_| future::ready(()))
         .then(|_| {
-            println!("ctrl_c received");
+            info!("ctrl_c received");
             future::ready(())
         })
         .boxed()
         .fuse()
 }
+
+/// A command together with an optional channel on which the outcome of its execution is sent
+/// back to the issuer.
+pub struct Request<C, R> {
+    pub command: C,
+    responder: Option<oneshot::Sender<R>>,
+}
+
+impl<C, R> Request<C, R> {
+    /// Fire and forget, nobody is interested in the outcome.
+    pub fn new(command: C) -> Self {
+        Request {
+            command,
+            responder: None,
+        }
+    }
+
+    pub fn with_response(command: C) -> (Self, oneshot::Receiver<R>) {
+        let (sender, receiver) = oneshot::channel();
+        let request = Request {
+            command,
+            responder: Some(sender),
+        };
+        (request, receiver)
+    }
+
+    pub fn respond(self, response: R) {
+        if let Some(responder) = self.responder {
+            // the issuer may have stopped waiting, that's fine
+            let _ = responder.send(response);
+        }
+    }
+}
+
+/// Hands the command to its listener, the receiver gets the outcome once it was executed.
+pub fn send_request<C, R>(
+    command_tx: &Option<Sender<Request<C, R>>>,
+    command: C,
+) -> Result<oneshot::Receiver<R>, String>
+where
+    C: std::fmt::Debug,
+{
+    match command_tx {
+        Some(tx) => {
+            let (request, receiver) = Request::with_response(command);
+            tx.clone()
+                .try_send(request)
+                .map(|_| debug!("command send"))
+                .map_err(|e| e.to_string())?;
+            Ok(receiver)
+        }
+        None => Err(format!("no listener for command {:?}", command)),
+    }
+}
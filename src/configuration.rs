@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use config::{Source, Value};
+use serde::de::DeserializeOwned;
+
+use crate::control::configuration::ControlConfig;
+use crate::embedded::configuration::LayoutConfig;
+#[cfg(feature = "http")]
+use crate::http::configuration::HttpConfig;
+use crate::migration::{self, Migrations};
+use crate::mqtt::configuration::MqttConfig;
+use crate::options::Options;
+use crate::persistence;
+use crate::schedule::WateringScheduleConfigs;
+
+const SECTIONS: [&str; 5] = ["layout", "mqtt", "watering", "http", "control"];
+
+/// Everything the butler is configured with. Each section of the unified config file, e.g.
+/// `[layout]`, takes the place of its split file, e.g. `layout.json`. Environment variables
+/// like `MQTT_PASSWORD` override both.
+#[derive(Debug, Clone)]
+pub struct Configuration {
+    pub layout: LayoutConfig,
+    pub mqtt: MqttConfig,
+    pub watering: WateringScheduleConfigs,
+    /// Only set if `[http]` or `http.json` exists, the api is not served otherwise.
+    #[cfg(feature = "http")]
+    pub http: Option<HttpConfig>,
+    pub control: ControlConfig,
+    /// Problems that were worked around, e.g. a broken state file replaced by its backup.
+    pub warnings: Vec<String>,
+}
+
+impl Configuration {
+    /// Collects every problem instead of stopping at the first, so that a broken config can be
+    /// fixed in one go.
+    pub fn load(options: &Options) -> Result<Configuration, Vec<String>> {
+        let mut loader = Loader::new(options.get_config_file(), options.is_check_config());
+        let layout = loader.load_versioned::<LayoutConfig>(
+            "layout",
+            &options.get_layout_file(),
+            &migration::LAYOUT,
+        );
+        let mqtt = loader.load::<MqttConfig>("mqtt", &options.get_mqtt_file(), true);
+        let state_file = options.get_schedules_state_file();
+        let watering = if loader.is_state_file_used(options, &state_file) {
+            loader.load_state_file::<WateringScheduleConfigs>("watering", &state_file)
+        } else {
+            loader.load_versioned::<WateringScheduleConfigs>(
+                "watering",
+                &options.get_schedules_file(),
+                &migration::SCHEDULES,
+            )
+        };
+        #[cfg(feature = "http")]
+        let http = if loader.is_configured("http", &options.get_http_file()) {
+            loader
+                .load::<HttpConfig>("http", &options.get_http_file(), true)
+                .map(Some)
+        } else {
+            Some(None)
+        };
+        let control = loader.load::<ControlConfig>("control", &options.get_control_file(), false);
+
+        let mut problems = loader.problems;
+        if let Some(layout) = &layout {
+            problems.extend(layout.validate());
+            if let Some(watering) = &watering {
+                problems.extend(watering.validate(layout));
+            }
+        }
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        let configuration = Configuration {
+            layout: layout.unwrap(),
+            mqtt: mqtt.unwrap(),
+            watering: watering.unwrap().with_file(state_file),
+            #[cfg(feature = "http")]
+            http: http.unwrap(),
+            control: control.unwrap(),
+            warnings: loader.warnings,
+        };
+        debug!("{:?}", configuration);
+        Ok(configuration)
+    }
+}
+
+/// Reads the sections and remembers the problems on the way.
+struct Loader {
+    file: Option<PathBuf>,
+    sections: HashMap<String, Value>,
+    problems: Vec<String>,
+    warnings: Vec<String>,
+    /// Nothing is written, e.g. for `--check-config`.
+    dry_run: bool,
+}
+
+impl Loader {
+    fn new(file: Option<PathBuf>, dry_run: bool) -> Self {
+        let mut loader = Loader {
+            file: None,
+            sections: HashMap::new(),
+            problems: Vec::new(),
+            warnings: Vec::new(),
+            dry_run,
+        };
+        if let Some(file) = file {
+            // the format follows the extension, e.g. toml or json
+            match config::File::from(file.as_path()).collect() {
+                Ok(sections) => loader.sections = sections,
+                Err(e) => loader.problems.push(format!("{}: {}", file.display(), e)),
+            }
+            for name in loader
+                .sections
+                .keys()
+                .filter(|s| !SECTIONS.contains(&s.as_str()))
+            {
+                loader.problems.push(format!(
+                    "{}: unknown section [{}], expected one of {}",
+                    file.display(),
+                    name,
+                    SECTIONS.join(", ")
+                ));
+            }
+            loader.file = Some(file);
+        }
+        loader
+    }
+
+    /// From the section of the unified file if there is one, from the split `file` otherwise.
+    fn load<T: DeserializeOwned>(
+        &mut self,
+        section: &str,
+        file: &Path,
+        required: bool,
+    ) -> Option<T> {
+        match (&self.file, self.sections.get(section)) {
+            (Some(unified_file), Some(value)) => {
+                let origin = format!("{} [{}]", unified_file.display(), section);
+                let result = value
+                    .clone()
+                    .into_table()
+                    .map_err(|e| format!("{}: {}", origin, e))
+                    .and_then(|table| read(section, Section(table), origin));
+                self.keep(result)
+            }
+            _ => self.keep(read_file(section, file, required)),
+        }
+    }
+
+    /// True if there is a section in the unified file or the split `file` exists.
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    fn is_configured(&self, section: &str, file: &Path) -> bool {
+        self.has_section(section) || file.exists()
+    }
+
+    fn has_section(&self, section: &str) -> bool {
+        self.file.is_some() && self.sections.contains_key(section)
+    }
+
+    /// Schedules changed at run time are saved to the state file, which wins over the split
+    /// schedules file from then on. A `[watering]` section only gives way to a state file of the
+    /// `--state-dir`, otherwise its edits would go unnoticed.
+    fn is_state_file_used(&mut self, options: &Options, state_file: &Path) -> bool {
+        if !state_file.exists() {
+            return false;
+        }
+        let section = match &self.file {
+            Some(file) if self.has_section("watering") => format!("{} [watering]", file.display()),
+            _ => return true,
+        };
+        if options.has_state_dir() {
+            self.warnings.push(format!(
+                "{} is shadowed by the schedules saved to {}, remove it to use the section again",
+                section,
+                state_file.display()
+            ));
+            true
+        } else {
+            self.warnings.push(format!(
+                "{} is ignored in favour of {}, changed schedules are only kept with --state-dir",
+                state_file.display(),
+                section
+            ));
+            false
+        }
+    }
+
+    /// Like `load`, a file written by an older release is upgraded before it is read.
+    fn load_versioned<T: DeserializeOwned>(
+        &mut self,
+        section: &str,
+        file: &Path,
+        migrations: &Migrations,
+    ) -> Option<T> {
+        if self.sections.contains_key(section) {
+            return self.load(section, file, true);
+        }
+        match self.upgrade(file, migrations) {
+            Some(json) => self.keep(read_json(section, &json, file)),
+            None => self.load(section, file, true),
+        }
+    }
+
+    /// A broken state file is replaced by its newest readable backup instead of stopping the
+    /// butler, the schedules changed since are lost but watering goes on.
+    fn load_state_file<T: DeserializeOwned>(&mut self, section: &str, file: &Path) -> Option<T> {
+        let result = match self.upgrade(file, &migration::SCHEDULES) {
+            Some(json) => read_json(section, &json, file),
+            None => read_file(section, file, true),
+        };
+        match result {
+            Ok(value) => Some(value),
+            Err(problem) => {
+                let backup = persistence::get_backup_files(file)
+                    .into_iter()
+                    .filter(|backup| backup.exists())
+                    .find_map(|backup| {
+                        read_file(section, &backup, true)
+                            .ok()
+                            .map(|value| (backup, value))
+                    });
+                match backup {
+                    Some((backup, value)) => {
+                        // saving the schedules must not rotate the broken file into the backups
+                        let restored = if self.dry_run {
+                            String::from("it is not restored for the check")
+                        } else {
+                            match persistence::restore_backup(file, &backup) {
+                                Ok(broken) => {
+                                    format!("the broken file is kept as {}", broken.display())
+                                }
+                                Err(e) => format!("it could not be restored = {}", e),
+                            }
+                        };
+                        self.warnings.push(format!(
+                            "{}, using the backup {} instead, {}",
+                            problem,
+                            backup.display(),
+                            restored
+                        ));
+                        Some(value)
+                    }
+                    None => self.keep(Err(problem)),
+                }
+            }
+        }
+    }
+
+    /// Saves the upgraded file, the old one is kept as its newest backup. A dry run upgrades it
+    /// in memory only. Returns the upgraded contents if the file was older.
+    fn upgrade(&mut self, file: &Path, migrations: &Migrations) -> Option<String> {
+        // missing and broken files are reported once they are read
+        let mut value: serde_json::Value = fs::read_to_string(file)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())?;
+        match migrations.migrate(&mut value) {
+            Ok(Some(version)) if self.dry_run => {
+                info!(
+                    "{}: would be upgraded from version {} to {}",
+                    file.display(),
+                    version,
+                    migrations.get_version()
+                );
+                Some(serde_json::to_string_pretty(&value).unwrap())
+            }
+            Ok(Some(version)) => {
+                let json = serde_json::to_string_pretty(&value).unwrap();
+                match persistence::write_atomically(file, json.as_bytes()) {
+                    Ok(_) => info!(
+                        "{}: upgraded from version {} to {}",
+                        file.display(),
+                        version,
+                        migrations.get_version()
+                    ),
+                    Err(e) => self.warnings.push(format!(
+                        "{}: could not save the upgrade from version {} = {}",
+                        file.display(),
+                        version,
+                        e
+                    )),
+                }
+                Some(json)
+            }
+            Ok(None) => None,
+            Err(problem) => {
+                self.problems
+                    .push(format!("{}: {}", file.display(), problem));
+                None
+            }
+        }
+    }
+
+    fn keep<T>(&mut self, result: Result<T, String>) -> Option<T> {
+        result.map_err(|e| self.problems.push(e)).ok()
+    }
+}
+
+fn read_file<T: DeserializeOwned>(section: &str, file: &Path, required: bool) -> Result<T, String> {
+    let source = config::File::from(file)
+        .format(config::FileFormat::Json)
+        .required(required);
+    read(section, source, file.display().to_string())
+}
+
+/// Contents of `file` that were upgraded in memory.
+fn read_json<T: DeserializeOwned>(section: &str, json: &str, file: &Path) -> Result<T, String> {
+    let source = config::File::from_str(json, config::FileFormat::Json);
+    read(section, source, file.display().to_string())
+}
+
+fn read<T, S>(section: &str, source: S, origin: String) -> Result<T, String>
+where
+    T: DeserializeOwned,
+    S: Source + Send + Sync + 'static,
+{
+    let mut settings = config::Config::default();
+    settings
+        .merge(source)
+        .and_then(|s| s.merge(config::Environment::with_prefix(&section.to_uppercase())))
+        .and_then(|s| s.clone().try_into::<T>())
+        .map_err(|e| format!("{}: {}", origin, e))
+}
+
+/// A section of the unified config file, read like a split file.
+#[derive(Debug, Clone)]
+struct Section(HashMap<String, Value>);
+
+impl Source for Section {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<HashMap<String, Value>, config::ConfigError> {
+        Ok(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedules(valve: u8) -> String {
+        format!(
+            r#"{{"schedules": [{{"schedule": {{"start_hour": 18, "start_minute": 0, "end_hour": 18, "end_minute": 30}}, "valve": {}, "enabled": true}}]}}"#,
+            valve
+        )
+    }
+
+    #[test]
+    fn upgraded_files_keep_the_old_one_as_backup() {
+        let dir =
+            std::env::temp_dir().join(format!("garden-butler-{}-upgrade", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("watering-schedules.json");
+        fs::write(&file, schedules(27)).unwrap();
+
+        let mut loader = Loader::new(None, false);
+        loader.upgrade(&file, &migration::SCHEDULES);
+        assert!(loader.problems.is_empty());
+        assert_eq!(
+            fs::read_to_string(dir.join("watering-schedules.json.1")).unwrap(),
+            schedules(27)
+        );
+        let upgraded: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&file).unwrap()).unwrap();
+        assert_eq!(upgraded["version"], 1);
+        assert_eq!(upgraded["schedules"][0]["valve"], 27);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checks_upgrade_files_in_memory_only() {
+        let dir = std::env::temp_dir().join(format!("garden-butler-{}-check", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("layout.json");
+        let layout = r#"{"valves": [], "pump": {"power_pin": 5, "status_led": 5}}"#;
+        fs::write(&file, layout).unwrap();
+
+        let mut loader = Loader::new(None, true);
+        let config = loader
+            .load_versioned::<LayoutConfig>("layout", &file, &migration::LAYOUT)
+            .unwrap();
+        assert!(loader.problems.is_empty());
+        assert!(config.validate().is_empty());
+        assert_eq!(fs::read_to_string(&file).unwrap(), layout);
+        assert!(!dir.join("layout.json.1").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn broken_state_files_fall_back_to_the_newest_readable_backup() {
+        let dir = std::env::temp_dir().join(format!("garden-butler-{}-state", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let file = dir.join("watering-schedules.json");
+        for contents in &[
+            schedules(10),
+            schedules(27),
+            String::from("{"),
+            schedules(4),
+        ] {
+            persistence::write_atomically(&file, contents.as_bytes()).unwrap();
+        }
+        fs::write(&file, "broken").unwrap();
+
+        let mut loader = Loader::new(None, false);
+        let watering = loader
+            .load_state_file::<WateringScheduleConfigs>("watering", &file)
+            .unwrap();
+        assert!(loader.problems.is_empty());
+        assert_eq!(loader.warnings.len(), 1);
+        // the newest backup is broken as well
+        assert_eq!(watering.get_schedules()[0].get_valve(), 27);
+        assert_eq!(
+            fs::read_to_string(dir.join("watering-schedules.json.broken")).unwrap(),
+            "broken"
+        );
+        assert_eq!(fs::read_to_string(&file).unwrap(), schedules(27));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
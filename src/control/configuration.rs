@@ -0,0 +1,13 @@
+use crate::control::defaults::DEFAULT_SOCKET_PATH;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ControlConfig {
+    socket_path: Option<String>,
+}
+
+impl ControlConfig {
+    /// Where `garden-butler-ctl` looks by default, too.
+    pub fn get_socket_path(&self) -> &str {
+        self.socket_path.as_deref().unwrap_or(DEFAULT_SOCKET_PATH)
+    }
+}
@@ -0,0 +1,4 @@
+/// Where the butler listens and `garden-butler-ctl` connects unless configured otherwise. Unlike
+/// e.g. `/tmp` nobody but the butler can create files in its runtime directory, so the path can
+/// not be taken over by another user.
+pub const DEFAULT_SOCKET_PATH: &str = "/run/garden-butler/control.sock";
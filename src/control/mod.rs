@@ -0,0 +1,268 @@
+use core::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Sender;
+
+use crate::communication;
+use crate::communication::event::{HealthStatus, StatusEvent};
+use crate::embedded::command::{close_after, LayoutCommand, LayoutRequest, Origin};
+use crate::embedded::configuration::LayoutConfig;
+use crate::embedded::{LayoutStatus, PinLayout, ToggleValve, ValvePinNumber};
+use crate::mqtt::MqttSession;
+use crate::schedule::{
+    Clock, ScheduledRun, WateringConfigCommand, WateringConfigRequest, WateringScheduleConfig,
+    WateringScheduleConfigs,
+};
+
+pub mod configuration;
+pub mod defaults;
+pub mod socket;
+
+const MAX_RUN_COUNT: usize = 100;
+/// A valve opened by hand is closed again within a day at the latest.
+const MAX_OPEN_MINUTES: u64 = 24 * 60;
+
+#[derive(Debug)]
+pub enum Error {
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    NotFound(String),
+    InvalidRequest(String),
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    Unauthorized(String),
+    CommandDispatch(String),
+    Configuration(String),
+    Layout(crate::embedded::Error),
+    Schedule(crate::schedule::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound(ref s) => write!(f, "Not found: {}", s),
+            Error::InvalidRequest(ref s) => write!(f, "Invalid request: {}", s),
+            Error::Unauthorized(ref s) => write!(f, "Unauthorized: {}", s),
+            Error::CommandDispatch(ref s) => write!(f, "Command could not be dispatched: {}", s),
+            Error::Configuration(ref s) => write!(f, "Invalid configuration: {}", s),
+            Error::Layout(ref e) => write!(f, "{}", e),
+            Error::Schedule(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error {
+    /// Stable identifier of the error kind for remote callers.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::NotFound(_) => "NOT_FOUND",
+            Error::InvalidRequest(_) => "INVALID_REQUEST",
+            Error::Unauthorized(_) => "UNAUTHORIZED",
+            Error::CommandDispatch(_) => "COMMAND_DISPATCH",
+            Error::Configuration(_) => "CONFIGURATION",
+            Error::Layout(ref e) => e.code(),
+            Error::Schedule(ref e) => e.code(),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<crate::embedded::Error> for Error {
+    fn from(e: crate::embedded::Error) -> Error {
+        Error::Layout(e)
+    }
+}
+
+impl From<crate::schedule::Error> for Error {
+    fn from(e: crate::schedule::Error) -> Error {
+        Error::Schedule(e)
+    }
+}
+
+/// Local control without the mqtt broker, shared by the http api and the control socket.
+/// Commands go through the same channels as mqtt commands.
+pub struct Controller {
+    layout_status: Box<dyn Fn() -> LayoutStatus + Send + Sync>,
+    layout_config: Arc<Mutex<LayoutConfig>>,
+    watering_schedule_config: Arc<Mutex<WateringScheduleConfigs>>,
+    mqtt_session: Arc<Mutex<MqttSession>>,
+    layout_command_sender: Option<Sender<LayoutRequest>>,
+    watering_config_command_sender: Option<Sender<WateringConfigRequest>>,
+    status_event_sender: broadcast::Sender<StatusEvent>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Controller {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<T, U>(
+        layout: Arc<Mutex<T>>,
+        layout_config: Arc<Mutex<LayoutConfig>>,
+        watering_schedule_config: Arc<Mutex<WateringScheduleConfigs>>,
+        mqtt_session: Arc<Mutex<MqttSession>>,
+        layout_command_sender: &Option<Sender<LayoutRequest>>,
+        watering_config_command_sender: &Option<Sender<WateringConfigRequest>>,
+        status_event_sender: broadcast::Sender<StatusEvent>,
+        clock: Arc<dyn Clock>,
+    ) -> Controller
+    where
+        T: PinLayout<U> + Send + 'static,
+        U: ToggleValve + Send + 'static,
+    {
+        Controller {
+            layout_status: Box::new(move || layout.lock().unwrap().get_layout_status()),
+            layout_config,
+            watering_schedule_config,
+            mqtt_session,
+            layout_command_sender: layout_command_sender.as_ref().cloned(),
+            watering_config_command_sender: watering_config_command_sender.as_ref().cloned(),
+            status_event_sender,
+            clock,
+        }
+    }
+
+    pub fn get_health(&self) -> HealthStatus {
+        HealthStatus::new(self.mqtt_session.lock().unwrap().get_connection_state())
+    }
+
+    pub fn get_layout_config(&self) -> LayoutConfig {
+        self.layout_config.lock().unwrap().clone()
+    }
+
+    pub fn get_layout_status(&self) -> LayoutStatus {
+        (self.layout_status)()
+    }
+
+    pub fn get_schedules(&self) -> WateringScheduleConfigs {
+        self.watering_schedule_config.lock().unwrap().clone()
+    }
+
+    pub fn get_schedule(&self, id: &str) -> Result<WateringScheduleConfig, Error> {
+        let schedule = parse_schedule_id(id)?;
+        self.watering_schedule_config
+            .lock()
+            .unwrap()
+            .get_schedules()
+            .iter()
+            .find(|s| s.get_id() == schedule.get_id())
+            .cloned()
+            .ok_or(Error::Schedule(crate::schedule::Error::ScheduleNotFound(
+                schedule,
+            )))
+    }
+
+    /// At most `MAX_RUN_COUNT` runs.
+    pub fn get_next_runs(&self, count: usize) -> Vec<ScheduledRun> {
+        self.watering_schedule_config
+            .lock()
+            .unwrap()
+            .get_next_runs(self.clock.now(), count.min(MAX_RUN_COUNT))
+    }
+
+    /// Every status change from now on, see `StatusEvent`.
+    pub fn subscribe(&self) -> broadcast::Receiver<StatusEvent> {
+        self.status_event_sender.subscribe()
+    }
+
+    /// Opens the valve and closes it again after `minutes` if given.
+    pub async fn open_valve(
+        &self,
+        valve: ValvePinNumber,
+        minutes: Option<u64>,
+        origin: Origin,
+    ) -> Result<LayoutStatus, Error> {
+        let duration = minutes.map(get_open_duration).transpose()?;
+        let status = self
+            .send_layout_command(LayoutCommand::Open(valve, origin))
+            .await?;
+        if let (Some(duration), Some(tx)) = (duration, &self.layout_command_sender) {
+            info!(
+                "closing valve {} in {} minutes",
+                valve.0,
+                duration.as_secs() / 60
+            );
+            tokio::spawn(close_after(
+                tx.clone(),
+                self.subscribe(),
+                valve,
+                duration,
+                origin,
+            ));
+        }
+        Ok(status)
+    }
+
+    pub async fn close_valve(
+        &self,
+        valve: ValvePinNumber,
+        origin: Origin,
+    ) -> Result<LayoutStatus, Error> {
+        self.send_layout_command(LayoutCommand::Close(valve, origin))
+            .await
+    }
+
+    pub async fn create_schedule(
+        &self,
+        schedule: WateringScheduleConfig,
+    ) -> Result<WateringScheduleConfigs, Error> {
+        self.send_schedule_command(WateringConfigCommand::Create(schedule))
+            .await
+    }
+
+    pub async fn switch_schedule(
+        &self,
+        id: &str,
+        enabled: bool,
+    ) -> Result<WateringScheduleConfigs, Error> {
+        let schedule = parse_schedule_id(id)?;
+        let command = if enabled {
+            WateringConfigCommand::Enable(schedule)
+        } else {
+            WateringConfigCommand::Disable(schedule)
+        };
+        self.send_schedule_command(command).await
+    }
+
+    pub async fn delete_schedule(&self, id: &str) -> Result<WateringScheduleConfigs, Error> {
+        let schedule = parse_schedule_id(id)?;
+        self.send_schedule_command(WateringConfigCommand::Delete(schedule))
+            .await
+    }
+
+    async fn send_layout_command(&self, command: LayoutCommand) -> Result<LayoutStatus, Error> {
+        let receiver = communication::send_request(&self.layout_command_sender, command)
+            .map_err(Error::CommandDispatch)?;
+        Ok(receiver.await.map_err(|_| command_dropped())??)
+    }
+
+    async fn send_schedule_command(
+        &self,
+        command: WateringConfigCommand,
+    ) -> Result<WateringScheduleConfigs, Error> {
+        let receiver = communication::send_request(&self.watering_config_command_sender, command)
+            .map_err(Error::CommandDispatch)?;
+        Ok(receiver.await.map_err(|_| command_dropped())??)
+    }
+}
+
+fn command_dropped() -> Error {
+    Error::CommandDispatch(String::from("command was dropped before it was executed"))
+}
+
+fn parse_schedule_id(id: &str) -> Result<WateringScheduleConfig, Error> {
+    WateringScheduleConfig::from_id(id)
+        .ok_or_else(|| Error::InvalidRequest(format!("invalid schedule id {}", id)))
+}
+
+fn get_open_duration(minutes: u64) -> Result<Duration, Error> {
+    minutes
+        .checked_mul(60)
+        .filter(|_| minutes <= MAX_OPEN_MINUTES)
+        .map(Duration::from_secs)
+        .ok_or_else(|| {
+            Error::InvalidRequest(format!(
+                "{} minutes is more than the maximum of {}",
+                minutes, MAX_OPEN_MINUTES
+            ))
+        })
+}
@@ -0,0 +1,407 @@
+use std::fs::{self, Permissions};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use futures::FutureExt;
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::RecvError;
+
+use crate::communication::event::StatusEvent;
+use crate::control::configuration::ControlConfig;
+use crate::control::{Controller, Error};
+use crate::embedded::command::Origin;
+use crate::embedded::ValvePinNumber;
+use crate::schedule::{WateringScheduleConfig, WateringScheduleConfigs};
+
+const DEFAULT_RUN_COUNT: usize = 5;
+
+/// One json object per line, e.g. `{"command":"open","valve":27,"minutes":10}`.
+/// Schedules are addressed by their id, e.g. `{"command":"enable","id":"27-0600-0630"}`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum ControlRequest {
+    Health,
+    Layout,
+    Valves,
+    Open {
+        valve: u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        minutes: Option<u64>,
+    },
+    Close {
+        valve: u8,
+    },
+    Schedules,
+    Schedule {
+        id: String,
+    },
+    Create {
+        schedule: WateringScheduleConfig,
+    },
+    Enable {
+        id: String,
+    },
+    Disable {
+        id: String,
+    },
+    Delete {
+        id: String,
+    },
+    Runs {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        count: Option<usize>,
+    },
+    /// Answered with a `StatusEvent` per line until the connection is closed.
+    Events,
+}
+
+/// Answers every request but `events` with one line.
+#[derive(Serialize, Debug)]
+struct ControlResponse {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+}
+
+impl From<Result<serde_json::Value, Error>> for ControlResponse {
+    fn from(result: Result<serde_json::Value, Error>) -> Self {
+        match result {
+            Ok(value) => ControlResponse {
+                success: true,
+                error_code: None,
+                error: None,
+                result: Some(value),
+            },
+            Err(e) => ControlResponse {
+                success: false,
+                error_code: Some(e.code()),
+                error: Some(e.to_string()),
+                result: None,
+            },
+        }
+    }
+}
+
+/// Unix domain socket for `garden-butler-ctl` and scripts on the same machine, see
+/// `ControlRequest` for the protocol.
+pub struct ControlSocket {
+    inner: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl ControlSocket {
+    pub fn new(
+        control_config: &ControlConfig,
+        controller: Controller,
+    ) -> Result<ControlSocket, Error> {
+        let path = control_config.get_socket_path();
+        create_socket_dir(path)?;
+        remove_stale_socket(path)?;
+        let mut listener = UnixListener::bind(path)
+            .map_err(|e| Error::Configuration(format!("could not bind {} = {}", path, e)))?;
+        // the owner and its group may control the butler, nobody else
+        fs::set_permissions(path, Permissions::from_mode(0o660)).map_err(|e| {
+            Error::Configuration(format!("could not restrict access to {} = {}", path, e))
+        })?;
+        info!("control socket listening on {}", path);
+
+        let controller = Arc::new(controller);
+        let inner = async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(handle_connection(Arc::clone(&controller), stream));
+                    }
+                    Err(e) => error!("control socket error = {}", e),
+                }
+            }
+        }
+        .boxed();
+        Ok(ControlSocket { inner })
+    }
+}
+
+impl Future for ControlSocket {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.poll_unpin(cx)
+    }
+}
+
+/// The runtime directory is usually created by the service manager, e.g. `RuntimeDirectory=` of
+/// systemd, but not when the butler is started by hand.
+fn create_socket_dir(path: &str) -> Result<(), Error> {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.exists() => fs::create_dir_all(dir)
+            .map_err(|e| {
+                Error::Configuration(format!("could not create {} = {}", dir.display(), e))
+            }),
+        _ => Ok(()),
+    }
+}
+
+/// The socket file outlives the butler, but must not be taken from a running one.
+fn remove_stale_socket(path: &str) -> Result<(), Error> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(Error::Configuration(format!(
+                    "another butler is listening on {}",
+                    path
+                )));
+            }
+            std::fs::remove_file(path).map_err(|e| {
+                Error::Configuration(format!("could not remove stale socket {} = {}", path, e))
+            })
+        }
+        Ok(_) => Err(Error::Configuration(format!(
+            "{} exists and is not a socket",
+            path
+        ))),
+        Err(_) => Ok(()),
+    }
+}
+
+async fn handle_connection(controller: Arc<Controller>, stream: UnixStream) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                error!("control socket read error = {}", e);
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(ControlRequest::Events) => return stream_events(&controller, &mut writer).await,
+            Ok(request) => execute(&controller, request).await,
+            Err(e) => Err(Error::InvalidRequest(e.to_string())),
+        };
+        if let Err(e) = &result {
+            warn!("control socket request {}: error = {}", line, e);
+        }
+        if write_line(&mut writer, &ControlResponse::from(result))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+async fn execute(
+    controller: &Controller,
+    request: ControlRequest,
+) -> Result<serde_json::Value, Error> {
+    match request {
+        ControlRequest::Health => Ok(to_json(&controller.get_health())),
+        ControlRequest::Layout => Ok(to_json(&controller.get_layout_config())),
+        ControlRequest::Valves => Ok(to_json(&controller.get_layout_status())),
+        ControlRequest::Open { valve, minutes } => {
+            let valve = ValvePinNumber(valve);
+            let status = controller.open_valve(valve, minutes, Origin::Cli).await?;
+            Ok(to_json(&status))
+        }
+        ControlRequest::Close { valve } => {
+            let valve = ValvePinNumber(valve);
+            Ok(to_json(&controller.close_valve(valve, Origin::Cli).await?))
+        }
+        ControlRequest::Schedules => Ok(schedules_to_json(&controller.get_schedules())),
+        ControlRequest::Schedule { id } => Ok(schedule_to_json(&controller.get_schedule(&id)?)),
+        ControlRequest::Create { schedule } => Ok(schedules_to_json(
+            &controller.create_schedule(schedule).await?,
+        )),
+        ControlRequest::Enable { id } => Ok(schedules_to_json(
+            &controller.switch_schedule(&id, true).await?,
+        )),
+        ControlRequest::Disable { id } => Ok(schedules_to_json(
+            &controller.switch_schedule(&id, false).await?,
+        )),
+        ControlRequest::Delete { id } => {
+            Ok(schedules_to_json(&controller.delete_schedule(&id).await?))
+        }
+        ControlRequest::Runs { count } => Ok(to_json(
+            &controller.get_next_runs(count.unwrap_or(DEFAULT_RUN_COUNT)),
+        )),
+        ControlRequest::Events => unreachable!("events are streamed by the connection"),
+    }
+}
+
+/// Starts with the current layout status and schedules like the server-sent events of the
+/// http api, followed by every change as it happens.
+async fn stream_events<W: AsyncWrite + Unpin>(controller: &Controller, writer: &mut W) {
+    let mut events = controller.subscribe();
+    let snapshot = vec![
+        StatusEvent::LayoutStatus(controller.get_layout_status()),
+        StatusEvent::Schedules(controller.get_schedules()),
+    ];
+    for event in snapshot {
+        if write_line(writer, &event).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                if write_line(writer, &event).await.is_err() {
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!("control socket subscriber missed {} events", missed)
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn write_line<W, S>(writer: &mut W, value: &S) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    S: Serialize,
+{
+    let mut line = serde_json::to_string(value).unwrap();
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
+fn to_json<S: Serialize>(value: &S) -> serde_json::Value {
+    serde_json::to_value(value).unwrap()
+}
+
+/// Schedules carry their `id`, clients address them with it.
+fn schedule_to_json(schedule: &WateringScheduleConfig) -> serde_json::Value {
+    let mut json = to_json(schedule);
+    json["id"] = serde_json::Value::from(schedule.get_id());
+    json
+}
+
+fn schedules_to_json(configs: &WateringScheduleConfigs) -> serde_json::Value {
+    let mut json = to_json(configs);
+    json["schedules"] = configs
+        .get_schedules()
+        .iter()
+        .map(schedule_to_json)
+        .collect();
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// The requests as `garden-butler-ctl` writes them.
+    fn requests() -> Vec<serde_json::Value> {
+        vec![
+            json!({"command": "health"}),
+            json!({"command": "layout"}),
+            json!({"command": "valves"}),
+            json!({"command": "open", "valve": 27}),
+            json!({"command": "open", "valve": 27, "minutes": 10}),
+            json!({"command": "close", "valve": 27}),
+            json!({"command": "schedules"}),
+            json!({"command": "schedule", "id": "27-0600-0630"}),
+            json!({
+                "command": "create",
+                "schedule": {
+                    "valve": 27,
+                    "enabled": false,
+                    "schedule": {
+                        "start_hour": 6,
+                        "start_minute": 0,
+                        "end_hour": 6,
+                        "end_minute": 30
+                    }
+                }
+            }),
+            json!({"command": "enable", "id": "27-0600-0630"}),
+            json!({"command": "disable", "id": "27-0600-0630"}),
+            json!({"command": "delete", "id": "27-0600-0630"}),
+            json!({"command": "runs"}),
+            json!({"command": "runs", "count": 3}),
+            json!({"command": "events"}),
+        ]
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for json in requests() {
+            let request: ControlRequest = serde_json::from_value(json.clone()).unwrap();
+            assert_eq!(to_json(&request), json);
+            let line = serde_json::to_string(&request).unwrap();
+            assert_eq!(
+                serde_json::from_str::<ControlRequest>(&line).unwrap(),
+                request
+            );
+        }
+    }
+
+    #[test]
+    fn requests_are_parsed_into_their_variants() {
+        let schedule = WateringScheduleConfig::from_id("27-0600-0630").unwrap();
+        let parse = |line: &str| serde_json::from_str::<ControlRequest>(line).unwrap();
+
+        assert_eq!(
+            parse(r#"{"command":"open","valve":27,"minutes":10}"#),
+            ControlRequest::Open {
+                valve: 27,
+                minutes: Some(10)
+            }
+        );
+        assert_eq!(
+            parse(r#"{"command":"open","valve":27}"#),
+            ControlRequest::Open {
+                valve: 27,
+                minutes: None
+            }
+        );
+        assert_eq!(
+            parse(r#"{"command":"runs"}"#),
+            ControlRequest::Runs { count: None }
+        );
+        assert_eq!(
+            parse(
+                &serde_json::to_string(&json!({"command": "create", "schedule": schedule}))
+                    .unwrap()
+            ),
+            ControlRequest::Create { schedule }
+        );
+    }
+
+    #[test]
+    fn invalid_requests_are_rejected() {
+        for line in &[
+            r#"{"command":"water"}"#,
+            r#"{"valve":27}"#,
+            r#"{"command":"open"}"#,
+            r#"{"command":"open","valve":300}"#,
+            r#"{"command":"close","valve":"27"}"#,
+            r#"{"command":"enable"}"#,
+            r#"{"command":"runs","count":-1}"#,
+            "open 27",
+        ] {
+            assert!(
+                serde_json::from_str::<ControlRequest>(line).is_err(),
+                "{} was accepted",
+                line
+            );
+        }
+    }
+}
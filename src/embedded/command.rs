@@ -1,19 +1,65 @@
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use chrono::NaiveDateTime;
 use futures::prelude::*;
 use futures::task::{Context, Poll};
 use futures::FutureExt;
+use tokio::sync::broadcast;
 use tokio::sync::mpsc::{Receiver, Sender};
 
-use crate::embedded::{PinLayout, ToggleValve, ValvePinNumber};
+use crate::communication::event::StatusEvent;
+use crate::communication::Request;
+use crate::embedded::{Error, LayoutStatus, PinLayout, ToggleValve, ValvePinNumber, ValveStatus};
+use crate::schedule::Clock;
 
 #[derive(Debug, Copy, Clone)]
 pub enum LayoutCommand {
-    Open(ValvePinNumber),
-    Close(ValvePinNumber),
+    Open(ValvePinNumber, Origin),
+    Close(ValvePinNumber, Origin),
 }
 
+impl LayoutCommand {
+    pub fn get_origin(&self) -> Origin {
+        match *self {
+            LayoutCommand::Open(_, origin) | LayoutCommand::Close(_, origin) => origin,
+        }
+    }
+}
+
+/// Who asked for a valve to be switched.
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    Schedule,
+    Button,
+    Mqtt,
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    Http,
+    Cli,
+    /// Valves closed because the layout changed underneath them.
+    Reload,
+}
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+pub enum ValveTransition {
+    OPENED,
+    CLOSED,
+}
+
+/// Published when a valve actually changed its state, not for commands that had no effect.
+#[derive(Serialize, Debug, Clone)]
+pub struct ValveEvent {
+    pub valve: ValvePinNumber,
+    pub transition: ValveTransition,
+    pub origin: Origin,
+    pub timestamp: NaiveDateTime,
+}
+
+/// Answered with the layout status after the command was executed.
+pub type LayoutRequest = Request<LayoutCommand, Result<LayoutStatus, Error>>;
+
 pub struct LayoutCommandListener {
     inner: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
@@ -21,48 +67,152 @@ pub struct LayoutCommandListener {
 impl LayoutCommandListener {
     pub fn new<T, U>(
         layout: Arc<Mutex<T>>,
-        receiver: Receiver<LayoutCommand>,
-        mut layout_status_sender: Sender<()>,
+        receiver: Receiver<LayoutRequest>,
+        layout_status_sender: Sender<()>,
+        valve_event_sender: Sender<ValveEvent>,
+        clock: Arc<dyn Clock>,
     ) -> Self
     where
         T: PinLayout<U> + Send + 'static,
         U: ToggleValve + Send + 'static,
     {
-        let inner =
-            receiver
-                .inspect(|n| println!("{:?}", n))
-                .then(move |command| {
-                    match command {
-                        LayoutCommand::Open(pin_num) => {
-                            if let Err(_e) =
-                                layout.lock().unwrap().turn_on(pin_num).map_err(|e| {
-                                    println!("turn on: command execution error = {:?}", e)
-                                })
-                            {
-                                return future::err(());
-                            }
-                        }
-                        LayoutCommand::Close(pin_num) => {
-                            if let Err(_e) = layout.lock().unwrap().turn_off(pin_num).map_err(|e| {
-                                println!("turn off: command execution error = {:?}", e)
-                            }) {
-                                return future::err(());
-                            }
-                        }
+        // commands run one after the other, so no other command switches valves while one waits
+        // for the master valve
+        let inner = receiver
+            .inspect(|n| info!("{:?}", n.command))
+            .for_each(move |request| {
+                let layout = Arc::clone(&layout);
+                let mut layout_status_sender = layout_status_sender.clone();
+                let mut valve_event_sender = valve_event_sender.clone();
+                let clock = Arc::clone(&clock);
+                async move {
+                    let command = request.command;
+                    let before = layout.lock().unwrap().get_layout_status();
+                    let result = match command {
+                        LayoutCommand::Open(pin_num, _) => open_valve(&layout, pin_num).await,
+                        LayoutCommand::Close(pin_num, _) => close_valve(&layout, pin_num).await,
+                    };
+                    let after = layout.lock().unwrap().get_layout_status();
+                    // a failed command may still have switched valves, so always compare
+                    for event in valve_events(&before, &after, command.get_origin(), clock.now()) {
+                        let _ = valve_event_sender
+                            .try_send(event)
+                            .map_err(|e| error!("error sending valve event = {}", e));
+                    }
+                    if let Err(e) = &result {
+                        warn!("{:?}: command execution error = {}", command, e);
                     }
-                    future::ok(())
-                })
-                .for_each(move |_| {
+                    request.respond(result.map(|_| after));
+
                     let _ = layout_status_sender.try_send(()).map_err(|e| {
-                        println!("error sending signal for layout status update. = {}", e)
+                        error!("error sending signal for layout status update. = {}", e)
                     });
-                    future::ready(())
-                })
-                .boxed();
+                }
+            })
+            .boxed();
         LayoutCommandListener { inner }
     }
 }
 
+/// Waits for the master valve outside of the layout lock, so that status requests are answered
+/// in the meantime.
+async fn open_valve<T, U>(layout: &Arc<Mutex<T>>, valve: ValvePinNumber) -> Result<(), Error>
+where
+    T: PinLayout<U>,
+    U: ToggleValve,
+{
+    let open_delay = layout.lock().unwrap().acquire_master_valve(valve)?;
+    tokio::time::delay_for(open_delay).await;
+    let result = layout.lock().unwrap().turn_on(valve);
+    if result.is_err() {
+        // a valve that failed to open must not keep the master valve open
+        let _ = close_valve(layout, valve).await.map_err(|e| {
+            error!(
+                "could not close valve {} after failing to open it = {}",
+                valve.0, e
+            )
+        });
+    }
+    result
+}
+
+async fn close_valve<T, U>(layout: &Arc<Mutex<T>>, valve: ValvePinNumber) -> Result<(), Error>
+where
+    T: PinLayout<U>,
+    U: ToggleValve,
+{
+    let (result, close_delay) = {
+        let mut guard = layout.lock().unwrap();
+        (guard.turn_off(valve), guard.release_master_valve(valve))
+    };
+    // close the master valve even if the zone valve failed, it is the last line of defence
+    if let Some(close_delay) = close_delay {
+        tokio::time::delay_for(close_delay).await;
+        layout.lock().unwrap().close_master_valve()?;
+    }
+    result
+}
+
+fn valve_events(
+    before: &LayoutStatus,
+    after: &LayoutStatus,
+    origin: Origin,
+    timestamp: NaiveDateTime,
+) -> Vec<ValveEvent> {
+    after
+        .get_valves()
+        .iter()
+        .filter(|valve| {
+            before.get_valves().iter().any(|previous| {
+                previous.get_valve_pin_number() == valve.get_valve_pin_number()
+                    && previous.get_status() != valve.get_status()
+            })
+        })
+        .map(|valve| ValveEvent {
+            valve: valve.get_valve_pin_number(),
+            transition: match valve.get_status() {
+                ValveStatus::OPEN => ValveTransition::OPENED,
+                ValveStatus::CLOSED => ValveTransition::CLOSED,
+            },
+            origin,
+            timestamp,
+        })
+        .collect()
+}
+
+/// Closes the valve once `duration` passed, unless anyone closed it in the meantime. A valve that
+/// was closed and reopened, e.g. by a schedule, is left alone.
+pub async fn close_after(
+    mut layout_command_sender: Sender<LayoutRequest>,
+    status_events: broadcast::Receiver<StatusEvent>,
+    valve: ValvePinNumber,
+    duration: Duration,
+    origin: Origin,
+) {
+    let closed = status_events
+        .into_stream()
+        .filter(move |event| {
+            future::ready(match event {
+                Ok(StatusEvent::ValveEvent(e)) => {
+                    e.valve == valve && e.transition == ValveTransition::CLOSED
+                }
+                _ => false,
+            })
+        })
+        .boxed();
+    let mut closed = closed.into_future().fuse();
+    let mut timeout = tokio::time::delay_for(duration).fuse();
+
+    select! {
+        _ = timeout => {
+            let _ = layout_command_sender
+                .try_send(Request::new(LayoutCommand::Close(valve, origin)))
+                .map_err(|e| error!("error closing valve {} = {}", valve.0, e));
+        },
+        _ = closed => info!("valve {} was closed before its time ran out", valve.0),
+    };
+}
+
 impl Future for LayoutCommandListener {
     type Output = ();
 
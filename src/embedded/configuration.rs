@@ -1,30 +1,65 @@
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct LayoutConfig {
     power: Option<u8>,
     error: Option<u8>,
     pump: Option<PumpConfig>,
+    master_valve: Option<MasterValveConfig>,
     valves: Vec<ValveConfig>,
 }
 
-impl Default for LayoutConfig {
-    fn default() -> Self {
-        let mut settings = config::Config::default();
-        settings
-            .merge(config::File::new("layout", config::FileFormat::Json))
-            .unwrap()
-            // Add in settings from the environment (with a prefix of LAYOUT)
-            // Eg.. `LAYOUT_POWER=11 ./target/app` would set the `debug` key
-            .merge(config::Environment::with_prefix("LAYOUT"))
-            .unwrap();
-        let layout_config = settings
-            .try_into::<LayoutConfig>()
-            .expect("Layout config contains errors");
-        println!("{:?}", layout_config);
-        layout_config
-    }
-}
-
 impl LayoutConfig {
+    /// Every pin may only be used once, e.g. a valve pin must not also be a button.
+    pub fn validate(&self) -> Vec<String> {
+        let mut uses: Vec<(u8, String)> = Vec::new();
+        if let Some(pin) = self.power {
+            uses.push((pin, String::from("the power output")));
+        }
+        if let Some(pin) = self.error {
+            uses.push((pin, String::from("the error output")));
+        }
+        if let Some(pump) = &self.pump {
+            uses.push((pump.power_pin, String::from("the pump")));
+            if let Some(pin) = pump.status_led {
+                uses.push((pin, String::from("the pump status led")));
+            }
+        }
+        if let Some(master_valve) = &self.master_valve {
+            uses.push((master_valve.valve, String::from("the master valve")));
+            if let Some(pin) = master_valve.status_led {
+                uses.push((pin, String::from("the master valve status led")));
+            }
+        }
+        for valve in &self.valves {
+            uses.push((valve.valve, format!("valve {}", valve.valve)));
+            if let Some(pin) = valve.button {
+                uses.push((pin, format!("the button of valve {}", valve.valve)));
+            }
+            if let Some(pin) = valve.status_led {
+                uses.push((pin, format!("the status led of valve {}", valve.valve)));
+            }
+        }
+
+        let mut problems = Vec::new();
+        for (i, (pin, usage)) in uses.iter().enumerate() {
+            if let Some((_, first_usage)) = uses[..i].iter().find(|(other, _)| other == pin) {
+                problems.push(format!(
+                    "layout: pin {} is used by {} and by {}",
+                    pin, first_usage, usage
+                ));
+            }
+        }
+        problems
+    }
+    /// Valves of this layout that `other` removes or wires differently. The pump and the master
+    /// valve are shared, changing one of them changes every valve.
+    pub fn get_changed_valves(&self, other: &LayoutConfig) -> Vec<u8> {
+        let shared_changed = self.pump != other.pump || self.master_valve != other.master_valve;
+        self.valves
+            .iter()
+            .filter(|valve| shared_changed || !other.valves.contains(valve))
+            .map(|valve| valve.valve)
+            .collect()
+    }
     pub fn get_power_pin_num(&self) -> Option<u8> {
         self.power
     }
@@ -37,9 +72,12 @@ impl LayoutConfig {
     pub fn get_pump(&self) -> &Option<PumpConfig> {
         &self.pump
     }
+    pub fn get_master_valve(&self) -> &Option<MasterValveConfig> {
+        &self.master_valve
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ValveConfig {
     valve: u8,
     button: Option<u8>,
@@ -58,7 +96,7 @@ impl ValveConfig {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct PumpConfig {
     power_pin: u8,
     status_led: Option<u8>,
@@ -72,3 +110,28 @@ impl PumpConfig {
         self.status_led
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MasterValveConfig {
+    valve: u8,
+    status_led: Option<u8>,
+    open_delay_millis: Option<u64>,
+    close_delay_millis: Option<u64>,
+}
+
+impl MasterValveConfig {
+    pub fn get_valve_pin_num(&self) -> u8 {
+        self.valve
+    }
+    pub fn get_status_led_pin_num(&self) -> Option<u8> {
+        self.status_led
+    }
+    /// Time to wait after opening the master valve before a zone valve is opened.
+    pub fn get_open_delay_millis(&self) -> u64 {
+        self.open_delay_millis.unwrap_or(0)
+    }
+    /// Time to wait after the last zone valve closed before the master valve is closed.
+    pub fn get_close_delay_millis(&self) -> u64 {
+        self.close_delay_millis.unwrap_or(0)
+    }
+}
@@ -1,39 +1,59 @@
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::embedded::configuration::{LayoutConfig, ValveConfig};
+use crate::embedded::command::{LayoutCommand, Origin};
+use crate::embedded::configuration::{LayoutConfig, MasterValveConfig, PumpConfig, ValveConfig};
+use crate::embedded::simulator::{PinKind, Simulator};
 use crate::embedded::ValveStatus::{CLOSED, OPEN};
 use crate::embedded::{
-    Error, LayoutStatus, PinLayout, ToggleValve, ToggleValveStatus, ValvePinNumber, ValveStatus,
+    Error, LayoutStatus, PinLayout, PumpStatus, ToggleValve, ToggleValveStatus, ValvePinNumber,
 };
+use crate::schedule::SystemClock;
 
+/// In memory replacement for `GpioPinLayout`. It follows the same rules for pump, master valve
+/// and status leds so that anything tested against it behaves the same on the Pi.
 pub struct FakePinLayout {
+    power_pin: Option<FakePin>,
+    error_pin: Option<FakePin>,
+    pump: Option<Arc<Mutex<FakePumpPin>>>,
+    master_valve: Option<Arc<Mutex<FakeMasterValve>>>,
     toggle_valves: Vec<Arc<Mutex<FakeToggleValve>>>,
+    simulator: Simulator,
 }
 
 impl Drop for FakePinLayout {
     fn drop(&mut self) {
-        println!("Drop Pinlayout.")
+        debug!("Drop Pinlayout.")
     }
 }
 
 impl PinLayout<FakeToggleValve> for FakePinLayout {
     fn new(config: &LayoutConfig) -> Self {
-        FakePinLayout {
-            toggle_valves: config
-                .get_valves()
-                .iter()
-                .map(|valve_conf| Arc::new(Mutex::new(FakeToggleValve::from_config(valve_conf))))
-                .collect(),
+        let simulator = Simulator::new(FaultInjector::from_env(), Arc::new(SystemClock {}));
+        FakePinLayout::with_simulator(config, simulator)
+    }
+
+    fn reconfigure(&mut self, config: &LayoutConfig) -> Result<(), Error> {
+        // the running parts are kept if a pin can not be released, the ones before stay turned off
+        self.release_replaced_parts(config)?;
+        self.take_over(config);
+        if let Err(e) = self.power_on() {
+            warn!("power led could not be turned on = {}", e);
         }
+        Ok(())
     }
 
-    fn find_pin(&self, valve_pin_num: ValvePinNumber) -> Result<&Arc<Mutex<FakeToggleValve>>, ()> {
+    fn find_pin(
+        &self,
+        valve_pin_num: ValvePinNumber,
+    ) -> Result<&Arc<Mutex<FakeToggleValve>>, Error> {
         let result_option = self
             .toggle_valves
             .iter()
             .find(|ref valve_pin| valve_pin_num == *valve_pin.lock().unwrap().get_valve_pin_num());
         match result_option {
-            None => Err(()),
+            None => Err(Error::ValveNotFound(valve_pin_num)),
             Some(valve) => Ok(valve),
         }
     }
@@ -46,9 +66,16 @@ impl PinLayout<FakeToggleValve> for FakePinLayout {
                 .map(|tv| {
                     let valve = tv.lock().unwrap();
                     let valve_pin_number = ValvePinNumber(valve.valve_pin_number.0);
-                    let status = match valve.status {
-                        OPEN => OPEN,
-                        CLOSED => CLOSED,
+                    let status = match valve.get_valve_pin().get_value() {
+                        Ok(0) => CLOSED,
+                        Ok(1) => OPEN,
+                        _ => {
+                            warn!(
+                                "Could not get value for valve pin {}",
+                                valve.get_valve_pin_num().0
+                            );
+                            CLOSED
+                        }
                     };
                     ToggleValveStatus {
                         valve_pin_number,
@@ -56,44 +83,364 @@ impl PinLayout<FakeToggleValve> for FakePinLayout {
                     }
                 })
                 .collect(),
+            master_valve: self.master_valve.as_ref().map(|m| {
+                match m.lock().unwrap().get_valve_pin().get_value() {
+                    Ok(1) => OPEN,
+                    _ => CLOSED,
+                }
+            }),
+            pump: self
+                .pump
+                .as_ref()
+                .map(|p| match p.lock().unwrap().pump_pin.get_value() {
+                    Ok(1) => PumpStatus::ON,
+                    _ => PumpStatus::OFF,
+                }),
+        }
+    }
+
+    fn acquire_master_valve(&mut self, valve_pin_num: ValvePinNumber) -> Result<Duration, Error> {
+        self.find_pin(valve_pin_num)?;
+        match &self.master_valve {
+            Some(master_valve) => master_valve.lock().unwrap().acquire(valve_pin_num),
+            None => Ok(Duration::from_millis(0)),
+        }
+    }
+
+    fn release_master_valve(&mut self, valve_pin_num: ValvePinNumber) -> Option<Duration> {
+        self.master_valve
+            .as_ref()
+            .and_then(|master_valve| master_valve.lock().unwrap().release(valve_pin_num))
+    }
+
+    fn close_master_valve(&mut self) -> Result<(), Error> {
+        match &self.master_valve {
+            Some(master_valve) => master_valve.lock().unwrap().close(),
+            None => Ok(()),
         }
     }
 
     fn turn_on(&mut self, valve_pin_num: ValvePinNumber) -> Result<(), Error> {
-        if let Ok(valve) = self.find_pin(valve_pin_num) {
-            valve.lock().unwrap().turn_on()?;
+        let valve = self.find_pin(valve_pin_num)?;
+
+        if let Some(pump) = &self.pump {
+            pump.lock().unwrap().turn_on()?;
         }
-        Ok(())
+
+        valve.lock().unwrap().turn_on()
     }
 
     fn turn_off(&mut self, valve_pin_num: ValvePinNumber) -> Result<(), Error> {
-        if let Ok(valve) = self.find_pin(valve_pin_num) {
-            valve.lock().unwrap().turn_off()?;
+        if let Some(pump) = &self.pump {
+            // only turn off if no other valve is turned on
+            let other_valve_open = self.toggle_valves.iter().any(|v| {
+                let valve = v.lock().unwrap();
+                valve.valve_pin_number != valve_pin_num && valve.valve_pin.value == 1
+            });
+            if !other_valve_open {
+                pump.lock().unwrap().turn_off()?;
+            }
+        }
+
+        self.find_pin(valve_pin_num)
+            .and_then(|valve| valve.lock().unwrap().turn_off())
+    }
+}
+
+impl FakePinLayout {
+    /// Fake layout on simulated hardware. Clones of `simulator` inject failures and read the
+    /// timeline.
+    pub fn with_simulator(config: &LayoutConfig, simulator: Simulator) -> FakePinLayout {
+        let mut layout = FakePinLayout {
+            power_pin: config
+                .get_power_pin_num()
+                .map(|num| FakePin::new(num, PinKind::Power, &simulator)),
+            error_pin: config
+                .get_error_pin_num()
+                .map(|num| FakePin::new(num, PinKind::Error, &simulator)),
+            pump: config.get_pump().as_ref().map(|pump_config| {
+                Arc::new(Mutex::new(FakePumpPin::from_config(
+                    pump_config,
+                    &simulator,
+                )))
+            }),
+            master_valve: config.get_master_valve().as_ref().map(|master_config| {
+                Arc::new(Mutex::new(FakeMasterValve::from_config(
+                    master_config,
+                    &simulator,
+                )))
+            }),
+            toggle_valves: config
+                .get_valves()
+                .iter()
+                .map(|valve_conf| {
+                    Arc::new(Mutex::new(FakeToggleValve::from_config(
+                        valve_conf, &simulator,
+                    )))
+                })
+                .collect(),
+            simulator,
+        };
+
+        layout
+            .run_start_sequence()
+            .expect("StartSequence could not run.");
+        layout
+            .power_on()
+            .expect("Power Pin could not be turned on.");
+
+        layout
+    }
+
+    /// Same led sequence as on the Pi, without the pauses.
+    fn run_start_sequence(&mut self) -> Result<(), Error> {
+        for _ in 0..5 {
+            set_pin_value(&mut self.power_pin, 1)?;
+            set_pin_value(&mut self.error_pin, 1)?;
+            for v in self.toggle_valves.iter() {
+                set_pin_value(&mut v.lock().unwrap().status_led_pin, 1)?;
+            }
+            set_pin_value(&mut self.power_pin, 0)?;
+            set_pin_value(&mut self.error_pin, 0)?;
+            for v in self.toggle_valves.iter() {
+                set_pin_value(&mut v.lock().unwrap().status_led_pin, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns off the parts that are removed or rewired without changing the layout. Stops at the
+    /// first part that fails.
+    fn release_replaced_parts(&mut self, config: &LayoutConfig) -> Result<(), Error> {
+        for valve in &self.toggle_valves {
+            let mut valve = valve.lock().unwrap();
+            if !config.get_valves().contains(&valve.config) {
+                valve.turn_off()?;
+            }
+        }
+        if let Some(pump) = &self.pump {
+            let mut pump = pump.lock().unwrap();
+            if Some(&pump.config) != config.get_pump().as_ref() {
+                pump.turn_off()?;
+            }
+        }
+        if let Some(master_valve) = &self.master_valve {
+            let mut master_valve = master_valve.lock().unwrap();
+            if Some(&master_valve.config) != config.get_master_valve().as_ref() {
+                master_valve.turn_off()?;
+            }
+        }
+        if is_pin_replaced(&self.power_pin, config.get_power_pin_num()) {
+            set_pin_value(&mut self.power_pin, 0)?;
+        }
+        if is_pin_replaced(&self.error_pin, config.get_error_pin_num()) {
+            set_pin_value(&mut self.error_pin, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Sets up the new and rewired parts once the replaced ones are turned off, unchanged valves
+    /// keep their state.
+    fn take_over(&mut self, config: &LayoutConfig) {
+        let simulator = self.simulator.clone();
+        if self.pump.as_ref().map(|p| p.lock().unwrap().config.clone()) != *config.get_pump() {
+            self.pump = config.get_pump().as_ref().map(|pump_config| {
+                Arc::new(Mutex::new(FakePumpPin::from_config(
+                    pump_config,
+                    &simulator,
+                )))
+            });
+        }
+        if self
+            .master_valve
+            .as_ref()
+            .map(|m| m.lock().unwrap().config.clone())
+            != *config.get_master_valve()
+        {
+            self.master_valve = config.get_master_valve().as_ref().map(|master_config| {
+                Arc::new(Mutex::new(FakeMasterValve::from_config(
+                    master_config,
+                    &simulator,
+                )))
+            });
+        }
+        if is_pin_replaced(&self.power_pin, config.get_power_pin_num()) {
+            self.power_pin = config
+                .get_power_pin_num()
+                .map(|num| FakePin::new(num, PinKind::Power, &simulator));
+        }
+        if is_pin_replaced(&self.error_pin, config.get_error_pin_num()) {
+            self.error_pin = config
+                .get_error_pin_num()
+                .map(|num| FakePin::new(num, PinKind::Error, &simulator));
+        }
+        let toggle_valves = config
+            .get_valves()
+            .iter()
+            .map(|valve_conf| {
+                self.toggle_valves
+                    .iter()
+                    .find(|v| v.lock().unwrap().config == *valve_conf)
+                    .map(Arc::clone)
+                    .unwrap_or_else(|| {
+                        Arc::new(Mutex::new(FakeToggleValve::from_config(
+                            valve_conf, &simulator,
+                        )))
+                    })
+            })
+            .collect();
+        self.toggle_valves = toggle_valves;
+    }
+
+    fn power_on(&mut self) -> Result<(), Error> {
+        if let Some(pin) = &mut self.power_pin {
+            pin.set_value(1)?;
+        }
+        Ok(())
+    }
+
+    /// Handle to the simulated hardware, e.g. to inject failures or inspect the timeline.
+    pub fn get_simulator(&self) -> Simulator {
+        self.simulator.clone()
+    }
+
+    /// Simulates a press of a valve button, which toggles the valve like on the Pi.
+    /// Records the button press and returns the command that toggles its valve.
+    pub fn press_button(&self, button_pin_num: u8) -> Result<LayoutCommand, Error> {
+        let valve_pin_num = self
+            .toggle_valves
+            .iter()
+            .map(|v| v.lock().unwrap())
+            .find(|v| v.config.get_button_pin_num() == Some(button_pin_num))
+            .map(|v| v.valve_pin_number)
+            .ok_or(Error::ButtonNotFound(button_pin_num))?;
+        self.simulator.record(button_pin_num, PinKind::Button, 1);
+
+        let is_on = self.find_pin(valve_pin_num)?.lock().unwrap().is_on()?;
+        if is_on {
+            Ok(LayoutCommand::Close(valve_pin_num, Origin::Button))
+        } else {
+            Ok(LayoutCommand::Open(valve_pin_num, Origin::Button))
+        }
+    }
+}
+
+/// Failures of the fake pins. Clones share the failures, so a test can keep one to break pins of
+/// a running layout.
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    inner: Arc<Mutex<Faults>>,
+}
+
+#[derive(Default)]
+struct Faults {
+    faulty_pins: HashSet<u8>,
+    stuck_pins: HashSet<u8>,
+}
+
+impl FaultInjector {
+    /// E.g. `FAKE_FAULTY_PINS=27,24 ./target/app` lets valve 27 and led 24 fail.
+    pub fn from_env() -> FaultInjector {
+        let faults = FaultInjector::default();
+        if let Ok(pins) = std::env::var("FAKE_FAULTY_PINS") {
+            pins.split(',')
+                .filter_map(|p| p.trim().parse::<u8>().ok())
+                .for_each(|p| faults.fail_pin(p));
+        }
+        faults
+    }
+
+    /// Reads and writes of the pin fail with `Error::HardwareIo`.
+    pub fn fail_pin(&self, pin_num: u8) {
+        self.inner.lock().unwrap().faulty_pins.insert(pin_num);
+    }
+
+    /// Writes to the pin succeed but its value does not change, like a mechanically stuck valve.
+    pub fn stick_pin(&self, pin_num: u8) {
+        self.inner.lock().unwrap().stuck_pins.insert(pin_num);
+    }
+
+    /// Removes all failures injected for the pin.
+    pub fn repair_pin(&self, pin_num: u8) {
+        let mut faults = self.inner.lock().unwrap();
+        faults.faulty_pins.remove(&pin_num);
+        faults.stuck_pins.remove(&pin_num);
+    }
+
+    fn check_fault(&self, pin_num: u8) -> Result<(), Error> {
+        if self.inner.lock().unwrap().faulty_pins.contains(&pin_num) {
+            Err(Error::HardwareIo(format!(
+                "injected fault on pin {}",
+                pin_num
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn is_stuck(&self, pin_num: u8) -> bool {
+        self.inner.lock().unwrap().stuck_pins.contains(&pin_num)
+    }
+}
+
+pub struct FakePin {
+    pin_num: u8,
+    kind: PinKind,
+    value: u8,
+    simulator: Simulator,
+}
+
+impl FakePin {
+    fn new(pin_num: u8, kind: PinKind, simulator: &Simulator) -> FakePin {
+        FakePin {
+            pin_num,
+            kind,
+            value: 0,
+            simulator: simulator.clone(),
+        }
+    }
+
+    pub fn get_value(&self) -> Result<u8, Error> {
+        self.simulator
+            .get_fault_injector()
+            .check_fault(self.pin_num)?;
+        Ok(self.value)
+    }
+
+    pub fn set_value(&mut self, value: u8) -> Result<(), Error> {
+        let faults = self.simulator.get_fault_injector();
+        faults.check_fault(self.pin_num)?;
+        if faults.is_stuck(self.pin_num) || self.value == value {
+            return Ok(());
         }
+        self.value = value;
+        self.simulator.record(self.pin_num, self.kind, value);
         Ok(())
     }
 }
 
 pub struct FakeToggleValve {
     valve_pin_number: ValvePinNumber,
-    status: ValveStatus,
+    valve_pin: FakePin,
+    status_led_pin: Option<FakePin>,
+    config: ValveConfig,
 }
 
 impl ToggleValve for FakeToggleValve {
     fn turn_on(&mut self) -> Result<(), Error> {
-        println!("Turning on valve {}", self.valve_pin_number.0);
-        self.status = OPEN;
-        Ok(())
+        info!("Turning on valve {}", self.valve_pin_number.0);
+        self.valve_pin.set_value(1)?;
+        set_pin_value(&mut self.status_led_pin, 1)
     }
 
     fn turn_off(&mut self) -> Result<(), Error> {
-        println!("Turning off valve {}", self.valve_pin_number.0);
-        self.status = CLOSED;
-        Ok(())
+        info!("Turning off valve {}", self.valve_pin_number.0);
+        self.valve_pin.set_value(0)?;
+        set_pin_value(&mut self.status_led_pin, 0)
     }
 
     fn is_on(&self) -> Result<bool, Error> {
-        Ok(self.status == OPEN)
+        self.valve_pin.get_value().map(|value| value == 1)
     }
 
     fn get_valve_pin_num(&self) -> &ValvePinNumber {
@@ -102,10 +449,374 @@ impl ToggleValve for FakeToggleValve {
 }
 
 impl FakeToggleValve {
-    pub fn from_config(valve: &ValveConfig) -> FakeToggleValve {
+    pub fn from_config(valve: &ValveConfig, simulator: &Simulator) -> FakeToggleValve {
         FakeToggleValve {
-            status: CLOSED,
             valve_pin_number: ValvePinNumber(valve.get_valve_pin_num()),
+            valve_pin: FakePin::new(valve.get_valve_pin_num(), PinKind::Valve, simulator),
+            status_led_pin: valve
+                .get_status_led_pin_num()
+                .map(|p| FakePin::new(p, PinKind::StatusLed, simulator)),
+            config: valve.clone(),
+        }
+    }
+
+    pub fn get_valve_pin(&self) -> &FakePin {
+        &self.valve_pin
+    }
+}
+
+pub struct FakePumpPin {
+    pump_pin: FakePin,
+    status_led_pin: Option<FakePin>,
+    config: PumpConfig,
+}
+
+impl FakePumpPin {
+    pub fn from_config(pump_config: &PumpConfig, simulator: &Simulator) -> FakePumpPin {
+        FakePumpPin {
+            pump_pin: FakePin::new(pump_config.get_power_pin_num(), PinKind::Pump, simulator),
+            status_led_pin: pump_config
+                .get_status_led_pin_num()
+                .map(|p| FakePin::new(p, PinKind::StatusLed, simulator)),
+            config: pump_config.clone(),
+        }
+    }
+
+    pub fn turn_on(&mut self) -> Result<(), Error> {
+        self.pump_pin.set_value(1)?;
+        set_pin_value(&mut self.status_led_pin, 1)
+    }
+
+    pub fn turn_off(&mut self) -> Result<(), Error> {
+        self.pump_pin.set_value(0)?;
+        set_pin_value(&mut self.status_led_pin, 0)
+    }
+}
+
+pub struct FakeMasterValve {
+    valve_pin: FakePin,
+    status_led_pin: Option<FakePin>,
+    open_delay: Duration,
+    close_delay: Duration,
+    holders: HashSet<ValvePinNumber>,
+    config: MasterValveConfig,
+}
+
+impl FakeMasterValve {
+    pub fn from_config(
+        master_config: &MasterValveConfig,
+        simulator: &Simulator,
+    ) -> FakeMasterValve {
+        FakeMasterValve {
+            valve_pin: FakePin::new(
+                master_config.get_valve_pin_num(),
+                PinKind::MasterValve,
+                simulator,
+            ),
+            status_led_pin: master_config
+                .get_status_led_pin_num()
+                .map(|p| FakePin::new(p, PinKind::StatusLed, simulator)),
+            open_delay: Duration::from_millis(master_config.get_open_delay_millis()),
+            close_delay: Duration::from_millis(master_config.get_close_delay_millis()),
+            holders: HashSet::new(),
+            config: master_config.clone(),
+        }
+    }
+
+    /// Opens the valve for the first zone, returns the time the zone has to wait.
+    pub fn acquire(&mut self, zone: ValvePinNumber) -> Result<Duration, Error> {
+        let mut delay = Duration::from_millis(0);
+        if self.valve_pin.get_value()? == 0 {
+            info!("Turning on master valve {}", self.valve_pin.pin_num);
+            self.valve_pin.set_value(1)?;
+            set_pin_value(&mut self.status_led_pin, 1)?;
+            delay = self.open_delay;
         }
+        self.holders.insert(zone);
+        Ok(delay)
+    }
+
+    /// Returns the time to wait before `close` once the last zone let go.
+    pub fn release(&mut self, zone: ValvePinNumber) -> Option<Duration> {
+        if self.holders.remove(&zone) && self.holders.is_empty() {
+            Some(self.close_delay)
+        } else {
+            None
+        }
+    }
+
+    pub fn close(&mut self) -> Result<(), Error> {
+        if self.holders.is_empty() {
+            info!("Turning off master valve {}", self.valve_pin.pin_num);
+            self.valve_pin.set_value(0)?;
+            set_pin_value(&mut self.status_led_pin, 0)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_valve_pin(&self) -> &FakePin {
+        &self.valve_pin
+    }
+
+    /// Closes the master valve regardless of the zones holding it.
+    fn turn_off(&mut self) -> Result<(), Error> {
+        self.holders.clear();
+        self.valve_pin.set_value(0)?;
+        set_pin_value(&mut self.status_led_pin, 0)
+    }
+}
+
+fn set_pin_value(pin: &mut Option<FakePin>, value: u8) -> Result<(), Error> {
+    if let Some(p) = pin {
+        p.set_value(value)?;
+    }
+    Ok(())
+}
+
+/// Whether a power or error led moved to another pin.
+fn is_pin_replaced(pin: &Option<FakePin>, pin_num: Option<u8>) -> bool {
+    pin.as_ref().map(|p| p.pin_num) != pin_num
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALVE: ValvePinNumber = ValvePinNumber(27);
+    const OTHER_VALVE: ValvePinNumber = ValvePinNumber(10);
+
+    fn create_layout(faults: &FaultInjector) -> FakePinLayout {
+        let config: LayoutConfig = serde_json::from_str(
+            r#"{
+                "power": 23,
+                "valves": [
+                    {"valve": 27, "button": 22, "status_led": 24},
+                    {"valve": 10, "button": 9, "status_led": 11}
+                ],
+                "pump": {"power_pin": 5, "status_led": 6},
+                "master_valve": {"valve": 4, "open_delay_millis": 500, "close_delay_millis": 300}
+            }"#,
+        )
+        .unwrap();
+        let simulator = Simulator::new(faults.clone(), Arc::new(SystemClock {}));
+        FakePinLayout::with_simulator(&config, simulator)
+    }
+
+    fn open(layout: &mut FakePinLayout, valve: ValvePinNumber) -> Result<(), Error> {
+        layout.acquire_master_valve(valve)?;
+        layout.turn_on(valve)
+    }
+
+    fn close(layout: &mut FakePinLayout, valve: ValvePinNumber) -> Result<(), Error> {
+        let result = layout.turn_off(valve);
+        if layout.release_master_valve(valve).is_some() {
+            layout.close_master_valve()?;
+        }
+        result
+    }
+
+    fn get_value(layout: &FakePinLayout, pin_num: u8) -> Option<u8> {
+        layout
+            .get_simulator()
+            .get_pin_timeline(pin_num)
+            .last()
+            .map(|t| t.value)
+    }
+
+    #[test]
+    fn unknown_valves_are_rejected() {
+        let mut layout = create_layout(&FaultInjector::default());
+
+        let unknown = ValvePinNumber(42);
+        assert!(matches!(
+            layout.acquire_master_valve(unknown),
+            Err(Error::ValveNotFound(ValvePinNumber(42)))
+        ));
+        assert!(matches!(
+            layout.turn_on(unknown),
+            Err(Error::ValveNotFound(ValvePinNumber(42)))
+        ));
+        assert!(matches!(
+            layout.turn_off(unknown),
+            Err(Error::ValveNotFound(ValvePinNumber(42)))
+        ));
+        assert_eq!(layout.get_layout_status().get_master_valve(), &Some(CLOSED));
+    }
+
+    #[test]
+    fn pump_runs_while_any_valve_is_open() {
+        let mut layout = create_layout(&FaultInjector::default());
+
+        open(&mut layout, VALVE).unwrap();
+        open(&mut layout, OTHER_VALVE).unwrap();
+        close(&mut layout, VALVE).unwrap();
+        assert_eq!(layout.get_layout_status().get_pump(), &Some(PumpStatus::ON));
+        assert_eq!(get_value(&layout, 6), Some(1));
+
+        close(&mut layout, OTHER_VALVE).unwrap();
+        assert_eq!(
+            layout.get_layout_status().get_pump(),
+            &Some(PumpStatus::OFF)
+        );
+        assert_eq!(get_value(&layout, 6), Some(0));
+    }
+
+    #[test]
+    fn status_leds_follow_their_valve() {
+        let mut layout = create_layout(&FaultInjector::default());
+
+        open(&mut layout, VALVE).unwrap();
+        assert_eq!(get_value(&layout, 24), Some(1));
+        assert_eq!(get_value(&layout, 11), Some(0));
+
+        close(&mut layout, VALVE).unwrap();
+        assert_eq!(get_value(&layout, 24), Some(0));
+    }
+
+    #[test]
+    fn master_valve_waits_for_the_first_and_the_last_valve() {
+        let mut layout = create_layout(&FaultInjector::default());
+
+        assert_eq!(
+            layout.acquire_master_valve(VALVE).unwrap(),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            layout.acquire_master_valve(OTHER_VALVE).unwrap(),
+            Duration::from_millis(0)
+        );
+        assert_eq!(layout.release_master_valve(VALVE), None);
+        assert_eq!(
+            layout.release_master_valve(OTHER_VALVE),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(layout.get_layout_status().get_master_valve(), &Some(OPEN));
+
+        layout.close_master_valve().unwrap();
+        assert_eq!(layout.get_layout_status().get_master_valve(), &Some(CLOSED));
+    }
+
+    #[test]
+    fn faulty_pins_fail_until_repaired() {
+        let faults = FaultInjector::default();
+        let mut layout = create_layout(&faults);
+
+        faults.fail_pin(27);
+        assert!(matches!(
+            open(&mut layout, VALVE),
+            Err(Error::HardwareIo(_))
+        ));
+        assert!(matches!(
+            layout.find_pin(VALVE).unwrap().lock().unwrap().is_on(),
+            Err(Error::HardwareIo(_))
+        ));
+
+        faults.repair_pin(27);
+        open(&mut layout, VALVE).unwrap();
+        assert!(layout
+            .find_pin(VALVE)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .is_on()
+            .unwrap());
+    }
+
+    #[test]
+    fn stuck_valves_do_not_move() {
+        let faults = FaultInjector::default();
+        let mut layout = create_layout(&faults);
+
+        faults.stick_pin(27);
+        open(&mut layout, VALVE).unwrap();
+        assert!(!layout
+            .find_pin(VALVE)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .is_on()
+            .unwrap());
+        // the pump does not know that the valve is stuck
+        assert_eq!(layout.get_layout_status().get_pump(), &Some(PumpStatus::ON));
+    }
+
+    #[test]
+    fn failed_reconfiguration_keeps_the_running_layout() {
+        let faults = FaultInjector::default();
+        let mut layout = create_layout(&faults);
+        open(&mut layout, VALVE).unwrap();
+        let config: LayoutConfig = serde_json::from_str(
+            r#"{"power": 23, "valves": [{"valve": 27, "button": 22, "status_led": 24}]}"#,
+        )
+        .unwrap();
+
+        faults.fail_pin(10);
+        assert!(matches!(
+            layout.reconfigure(&config),
+            Err(Error::HardwareIo(_))
+        ));
+        assert!(layout.find_pin(OTHER_VALVE).is_ok());
+        assert_eq!(layout.get_layout_status().get_pump(), &Some(PumpStatus::ON));
+
+        faults.repair_pin(10);
+        layout.reconfigure(&config).unwrap();
+        assert!(matches!(
+            layout.find_pin(OTHER_VALVE),
+            Err(Error::ValveNotFound(_))
+        ));
+        assert_eq!(layout.get_layout_status().get_pump(), &None);
+        // the kept valve is still open
+        assert!(layout
+            .find_pin(VALVE)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .is_on()
+            .unwrap());
+    }
+
+    #[test]
+    fn parts_released_before_a_failure_stay_turned_off() {
+        let faults = FaultInjector::default();
+        let mut layout = create_layout(&faults);
+        open(&mut layout, VALVE).unwrap();
+        open(&mut layout, OTHER_VALVE).unwrap();
+        let config: LayoutConfig = serde_json::from_str(
+            r#"{"power": 23, "valves": [{"valve": 27, "button": 22, "status_led": 24}]}"#,
+        )
+        .unwrap();
+
+        // the removed valve is turned off before the removed pump fails
+        faults.fail_pin(5);
+        assert!(matches!(
+            layout.reconfigure(&config),
+            Err(Error::HardwareIo(_))
+        ));
+        assert!(!layout
+            .find_pin(OTHER_VALVE)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .is_on()
+            .unwrap());
+        assert_eq!(get_value(&layout, 11), Some(0));
+        assert!(layout.get_layout_status().get_pump().is_some());
+        assert!(layout
+            .find_pin(VALVE)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .is_on()
+            .unwrap());
+    }
+
+    #[test]
+    fn layouts_do_not_share_faults() {
+        let faults = FaultInjector::default();
+        let _broken = create_layout(&faults);
+        let mut healthy = create_layout(&FaultInjector::default());
+
+        faults.fail_pin(27);
+        open(&mut healthy, VALVE).unwrap();
     }
 }
@@ -1,22 +1,36 @@
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::Duration;
 
+use futures::future::AbortHandle;
 use futures::prelude::*;
 use sysfs_gpio::{Direction, Edge, Pin};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
 
-use crate::communication::create_abortable_task;
-use crate::embedded::configuration::{LayoutConfig, PumpConfig, ValveConfig};
+use crate::communication::{create_abortable_task, Request};
+use crate::embedded::command::{LayoutCommand, LayoutRequest, Origin};
+use crate::embedded::configuration::{LayoutConfig, MasterValveConfig, PumpConfig, ValveConfig};
 use crate::embedded::ValveStatus::{CLOSED, OPEN};
 use crate::embedded::{
-    Error, LayoutStatus, PinLayout, ToggleValve, ToggleValveStatus, ValvePinNumber,
+    Error, LayoutStatus, PinLayout, PumpStatus, ToggleValve, ToggleValveStatus, ValvePinNumber,
 };
 
 pub struct GpioPinLayout {
     power_pin: Option<Pin>,
     error_pin: Option<Pin>,
     pump: Option<Arc<Mutex<GpioPumpPin>>>,
+    master_valve: Option<Arc<Mutex<GpioMasterValve>>>,
     toggle_valves: Vec<Arc<Mutex<GpioToggleValve>>>,
+    button_streams: Option<ButtonStreams>,
+}
+
+/// The spawned button streams and what they need to be spawned again for a changed layout.
+struct ButtonStreams {
+    ctrl_c_receiver: watch::Receiver<String>,
+    layout_command_sender: Sender<LayoutRequest>,
+    abort_handles: Vec<AbortHandle>,
 }
 
 impl PinLayout<GpioToggleValve> for GpioPinLayout {
@@ -32,11 +46,15 @@ impl PinLayout<GpioToggleValve> for GpioPinLayout {
                 .get_pump()
                 .as_ref()
                 .map(|pump_config| Arc::new(Mutex::new(create_pump_pin(pump_config)))),
+            master_valve: config.get_master_valve().as_ref().map(|master_config| {
+                Arc::new(Mutex::new(GpioMasterValve::from_config(master_config)))
+            }),
             toggle_valves: config
                 .get_valves()
                 .iter()
                 .map(|valve_conf| Arc::new(Mutex::new(GpioToggleValve::from_config(valve_conf))))
                 .collect(),
+            button_streams: None,
         };
 
         layout
@@ -49,13 +67,42 @@ impl PinLayout<GpioToggleValve> for GpioPinLayout {
         layout
     }
 
-    fn find_pin(&self, valve_pin_num: ValvePinNumber) -> Result<&Arc<Mutex<GpioToggleValve>>, ()> {
+    fn reconfigure(&mut self, config: &LayoutConfig) -> Result<(), Error> {
+        // the streams hold on to the valves and button pins they were spawned for
+        let button_streams = self.button_streams.take();
+        if let Some(button_streams) = &button_streams {
+            button_streams
+                .abort_handles
+                .iter()
+                .for_each(AbortHandle::abort);
+        }
+
+        // the running parts are kept if a pin can not be released, the ones before stay unexported
+        let result = self
+            .release_replaced_parts(config)
+            .map(|_| self.take_over(config));
+
+        // spawned again for the valves of the new layout, or of the one that was kept
+        if let Some(button_streams) = button_streams {
+            self.spawn_button_streams(
+                button_streams.ctrl_c_receiver,
+                button_streams.layout_command_sender,
+            );
+        }
+        result?;
+        self.power_on()
+    }
+
+    fn find_pin(
+        &self,
+        valve_pin_num: ValvePinNumber,
+    ) -> Result<&Arc<Mutex<GpioToggleValve>>, Error> {
         let result_option = self
             .toggle_valves
             .iter()
             .find(|ref valve_pin| valve_pin_num == *valve_pin.lock().unwrap().get_valve_pin_num());
         match result_option {
-            None => Err(()),
+            None => Err(Error::ValveNotFound(valve_pin_num)),
             Some(valve) => Ok(valve),
         }
     }
@@ -72,7 +119,7 @@ impl PinLayout<GpioToggleValve> for GpioPinLayout {
                         Ok(0) => CLOSED,
                         Ok(1) => OPEN,
                         _ => {
-                            print!(
+                            warn!(
                                 "Could not get value for valve pin {}",
                                 valve.get_valve_pin_num().0
                             );
@@ -85,17 +132,51 @@ impl PinLayout<GpioToggleValve> for GpioPinLayout {
                     }
                 })
                 .collect(),
+            master_valve: self.master_valve.as_ref().map(|m| {
+                match m.lock().unwrap().get_valve_pin().get_value() {
+                    Ok(1) => OPEN,
+                    _ => CLOSED,
+                }
+            }),
+            pump: self
+                .pump
+                .as_ref()
+                .map(|p| match p.lock().unwrap().pump_pin.get_value() {
+                    Ok(1) => PumpStatus::ON,
+                    _ => PumpStatus::OFF,
+                }),
+        }
+    }
+
+    fn acquire_master_valve(&mut self, valve_pin_num: ValvePinNumber) -> Result<Duration, Error> {
+        self.find_pin(valve_pin_num)?;
+        match &self.master_valve {
+            Some(master_valve) => master_valve.lock().unwrap().acquire(valve_pin_num),
+            None => Ok(Duration::from_millis(0)),
+        }
+    }
+
+    fn release_master_valve(&mut self, valve_pin_num: ValvePinNumber) -> Option<Duration> {
+        self.master_valve
+            .as_ref()
+            .and_then(|master_valve| master_valve.lock().unwrap().release(valve_pin_num))
+    }
+
+    fn close_master_valve(&mut self) -> Result<(), Error> {
+        match &self.master_valve {
+            Some(master_valve) => master_valve.lock().unwrap().close(),
+            None => Ok(()),
         }
     }
 
     fn turn_on(&mut self, valve_pin_num: ValvePinNumber) -> Result<(), Error> {
+        let valve = self.find_pin(valve_pin_num)?;
+
         if let Some(pump) = &self.pump {
             pump.lock().unwrap().turn_on()?;
         }
 
-        self.find_pin(valve_pin_num)
-            .map_err(|_| Error::Unexpected(String::from("Valve not found.")))
-            .and_then(|valve| valve.lock().unwrap().turn_on())
+        valve.lock().unwrap().turn_on()
     }
 
     fn turn_off(&mut self, valve_pin_num: ValvePinNumber) -> Result<(), Error> {
@@ -111,14 +192,13 @@ impl PinLayout<GpioToggleValve> for GpioPinLayout {
         }
 
         self.find_pin(valve_pin_num)
-            .map_err(|_| Error::Unexpected(String::from("Valve not found.")))
             .and_then(|valve| valve.lock().unwrap().turn_off())
     }
 }
 
 impl Drop for GpioPinLayout {
     fn drop(&mut self) {
-        println!("Drop Pinlayout");
+        debug!("Drop Pinlayout");
         self.unexport_all()
             .expect("Unexport should always work but didn't for some reason.");
     }
@@ -149,61 +229,141 @@ impl GpioPinLayout {
         Ok(())
     }
 
+    /// Hands back the pins of the parts that are removed or rewired without changing the layout.
+    /// Stops at the first pin that fails.
+    fn release_replaced_parts(&self, config: &LayoutConfig) -> Result<(), Error> {
+        for valve in &self.toggle_valves {
+            let valve = valve.lock().unwrap();
+            if !config.get_valves().contains(&valve.config) {
+                valve.unexport()?;
+            }
+        }
+        if let Some(pump) = &self.pump {
+            let pump = pump.lock().unwrap();
+            if Some(&pump.config) != config.get_pump().as_ref() {
+                pump.unexport()?;
+            }
+        }
+        if let Some(master_valve) = &self.master_valve {
+            let master_valve = master_valve.lock().unwrap();
+            if Some(&master_valve.config) != config.get_master_valve().as_ref() {
+                master_valve.unexport()?;
+            }
+        }
+        if is_pin_replaced(&self.power_pin, config.get_power_pin_num()) {
+            unexport_pin(&self.power_pin)?;
+        }
+        if is_pin_replaced(&self.error_pin, config.get_error_pin_num()) {
+            unexport_pin(&self.error_pin)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the new and rewired parts once the replaced ones are released, unchanged valves
+    /// keep their state.
+    fn take_over(&mut self, config: &LayoutConfig) {
+        if self.pump.as_ref().map(|p| p.lock().unwrap().config.clone()) != *config.get_pump() {
+            self.pump = config
+                .get_pump()
+                .as_ref()
+                .map(|pump_config| Arc::new(Mutex::new(create_pump_pin(pump_config))));
+        }
+        if self
+            .master_valve
+            .as_ref()
+            .map(|m| m.lock().unwrap().config.clone())
+            != *config.get_master_valve()
+        {
+            self.master_valve = config.get_master_valve().as_ref().map(|master_config| {
+                Arc::new(Mutex::new(GpioMasterValve::from_config(master_config)))
+            });
+        }
+        if is_pin_replaced(&self.power_pin, config.get_power_pin_num()) {
+            self.power_pin = config
+                .get_power_pin_num()
+                .map(|num| create_pin(num, Direction::Out));
+        }
+        if is_pin_replaced(&self.error_pin, config.get_error_pin_num()) {
+            self.error_pin = config
+                .get_error_pin_num()
+                .map(|num| create_pin(num, Direction::Out));
+        }
+        let toggle_valves = config
+            .get_valves()
+            .iter()
+            .map(|valve_conf| {
+                self.toggle_valves
+                    .iter()
+                    .find(|v| v.lock().unwrap().config == *valve_conf)
+                    .map(Arc::clone)
+                    .unwrap_or_else(|| {
+                        Arc::new(Mutex::new(GpioToggleValve::from_config(valve_conf)))
+                    })
+            })
+            .collect();
+        self.toggle_valves = toggle_valves;
+    }
+
     fn power_on(&self) -> Result<(), Error> {
         set_pin_value(&self.power_pin, 1);
         Ok(())
     }
 
-    pub fn spawn_button_streams(&self, ctrl_c_receiver: tokio::sync::watch::Receiver<String>) {
-        let valve_pins = self.get_valve_pins();
-        let mut valves: Vec<Arc<Mutex<GpioToggleValve>>> = Vec::new();
-        for pin in valve_pins {
-            valves.push(Arc::clone(&pin))
-        }
-        for toggle_valve in valves {
+    /// Button presses toggle their valve through the layout command listener so that they are
+    /// reported like any other command. The streams are spawned again when the layout changes.
+    pub fn spawn_button_streams(
+        &mut self,
+        ctrl_c_receiver: watch::Receiver<String>,
+        layout_command_sender: Sender<LayoutRequest>,
+    ) {
+        let mut abort_handles = Vec::new();
+        for toggle_valve in self.get_valve_pins() {
             let toggle_valve_raw = toggle_valve.lock().unwrap();
             if let Some(button_pin) = toggle_valve_raw.get_button_pin() {
-                let clone = Arc::clone(&toggle_valve);
-                let pump_clone = self.pump.as_ref().map(|p| Arc::clone(p));
+                let clone = Arc::clone(toggle_valve);
+                let mut command_sender = layout_command_sender.clone();
                 let button_stream = button_pin
                     .get_value_stream()
                     .expect("Expect a valid value stream.")
                     .for_each(move |_val| {
-                        println!(
-                            "Button {} got value {:?}",
-                            clone.lock().unwrap().valve_pin_number.0,
-                            _val
-                        );
-                        let mut valve = clone.lock().unwrap();
-                        let is_on = valve.is_on();
-                        match is_on {
+                        let valve = clone.lock().unwrap();
+                        debug!("Button {} got value {:?}", valve.valve_pin_number.0, _val);
+                        let command = match valve.is_on() {
                             Ok(true) => {
-                                if let Some(pump) = &pump_clone {
-                                    let _ = pump.lock().unwrap().turn_off();
-                                }
-                                let _ = valve.turn_off();
+                                Some(LayoutCommand::Close(valve.valve_pin_number, Origin::Button))
                             }
                             Ok(false) => {
-                                if let Some(pump) = &pump_clone {
-                                    let _ = pump.lock().unwrap().turn_on();
-                                }
-                                let _ = valve.turn_on();
+                                Some(LayoutCommand::Open(valve.valve_pin_number, Origin::Button))
                             }
                             Err(_) => {
                                 // ignore errors
+                                None
                             }
+                        };
+                        if let Some(command) = command {
+                            command_sender
+                                .try_send(Request::new(command))
+                                .map_err(|e| error!("error = {}", e))
+                                .unwrap_or(());
                         }
                         future::ready(())
                     });
 
+                let (button_stream, abort_handle) = future::abortable(button_stream);
+                abort_handles.push(abort_handle);
                 let task = create_abortable_task(
-                    button_stream,
+                    button_stream.map(|_| ()),
                     "button_stream".to_string(),
                     ctrl_c_receiver.clone(),
                 );
                 tokio::spawn(task);
             }
         }
+        self.button_streams = Some(ButtonStreams {
+            ctrl_c_receiver,
+            layout_command_sender,
+            abort_handles,
+        });
     }
 
     pub fn get_valve_pins(&self) -> &Vec<Arc<Mutex<GpioToggleValve>>> {
@@ -211,27 +371,16 @@ impl GpioPinLayout {
     }
 
     fn unexport_all(&self) -> Result<(), Error> {
-        if let Some(pin) = self.power_pin {
-            pin.set_value(0)?;
-            pin.unexport()?;
+        unexport_pin(&self.power_pin)?;
+        unexport_pin(&self.error_pin)?;
+        if let Some(pump) = &self.pump {
+            pump.lock().unwrap().unexport()?;
         }
-        if let Some(pin) = self.error_pin {
-            pin.set_value(0)?;
-            pin.unexport()?;
+        if let Some(master_valve) = &self.master_valve {
+            master_valve.lock().unwrap().unexport()?;
         }
-
         for toggle_valve in &self.toggle_valves {
-            let tv = toggle_valve.lock().unwrap();
-            let v = tv.get_valve_pin();
-            v.set_value(0)?;
-            v.unexport()?;
-            if let Some(pin) = tv.get_button_pin() {
-                pin.unexport()?;
-            }
-            if let Some(pin) = tv.get_status_led_pin() {
-                pin.set_value(0)?;
-                pin.unexport()?;
-            }
+            toggle_valve.lock().unwrap().unexport()?;
         }
         Ok(())
     }
@@ -242,6 +391,7 @@ pub struct GpioToggleValve {
     valve_pin: Pin,
     status_led_pin: Option<Pin>,
     button_pin: Option<Pin>,
+    config: ValveConfig,
 }
 
 impl ToggleValve for GpioToggleValve {
@@ -261,7 +411,7 @@ impl ToggleValve for GpioToggleValve {
         self.valve_pin
             .get_value()
             .map(|value| value == 1)
-            .map_err(|e| Error::Unexpected(e.to_string()))
+            .map_err(|e| Error::HardwareIo(e.to_string()))
     }
 
     fn get_valve_pin_num(&self) -> &ValvePinNumber {
@@ -280,6 +430,7 @@ impl GpioToggleValve {
             button_pin: valve
                 .get_button_pin_num()
                 .map(|p| create_pin(p, Direction::In)),
+            config: valve.clone(),
         }
     }
 
@@ -304,11 +455,20 @@ impl GpioToggleValve {
         set_pin_value(&self.status_led_pin, 0);
         Ok(())
     }
+
+    fn unexport(&self) -> Result<(), Error> {
+        unexport_pin(&Some(self.valve_pin))?;
+        if let Some(pin) = self.button_pin {
+            pin.unexport()?;
+        }
+        unexport_pin(&self.status_led_pin)
+    }
 }
 
 pub struct GpioPumpPin {
     pump_pin: Pin,
     status_led_pin: Option<Pin>,
+    config: PumpConfig,
 }
 
 impl GpioPumpPin {
@@ -322,6 +482,75 @@ impl GpioPumpPin {
         set_pin_value(&self.status_led_pin, 0);
         Ok(())
     }
+
+    fn unexport(&self) -> Result<(), Error> {
+        unexport_pin(&Some(self.pump_pin))?;
+        unexport_pin(&self.status_led_pin)
+    }
+}
+
+/// Master valve upstream of all zone valves. It is held open as long as at least one zone valve
+/// is open.
+pub struct GpioMasterValve {
+    valve_pin: Pin,
+    status_led_pin: Option<Pin>,
+    open_delay: Duration,
+    close_delay: Duration,
+    holders: HashSet<ValvePinNumber>,
+    config: MasterValveConfig,
+}
+
+impl GpioMasterValve {
+    pub fn from_config(master_config: &MasterValveConfig) -> GpioMasterValve {
+        GpioMasterValve {
+            valve_pin: create_pin(master_config.get_valve_pin_num(), Direction::Out),
+            status_led_pin: master_config
+                .get_status_led_pin_num()
+                .map(|p| create_pin(p, Direction::Out)),
+            open_delay: Duration::from_millis(master_config.get_open_delay_millis()),
+            close_delay: Duration::from_millis(master_config.get_close_delay_millis()),
+            holders: HashSet::new(),
+            config: master_config.clone(),
+        }
+    }
+
+    /// Opens the valve for the first zone, returns the time the zone has to wait.
+    pub fn acquire(&mut self, zone: ValvePinNumber) -> Result<Duration, Error> {
+        let mut delay = Duration::from_millis(0);
+        if self.valve_pin.get_value()? == 0 {
+            self.valve_pin.set_value(1)?;
+            set_pin_value(&self.status_led_pin, 1);
+            delay = self.open_delay;
+        }
+        self.holders.insert(zone);
+        Ok(delay)
+    }
+
+    /// Returns the time to wait before `close` once the last zone let go.
+    pub fn release(&mut self, zone: ValvePinNumber) -> Option<Duration> {
+        if self.holders.remove(&zone) && self.holders.is_empty() {
+            Some(self.close_delay)
+        } else {
+            None
+        }
+    }
+
+    pub fn close(&mut self) -> Result<(), Error> {
+        if self.holders.is_empty() {
+            self.valve_pin.set_value(0)?;
+            set_pin_value(&self.status_led_pin, 0);
+        }
+        Ok(())
+    }
+
+    pub fn get_valve_pin(&self) -> &Pin {
+        &self.valve_pin
+    }
+
+    fn unexport(&self) -> Result<(), Error> {
+        unexport_pin(&Some(self.valve_pin))?;
+        unexport_pin(&self.status_led_pin)
+    }
 }
 
 fn create_pin(pin_num: u8, direction: Direction) -> Pin {
@@ -344,6 +573,7 @@ fn create_pump_pin(pump_config: &PumpConfig) -> GpioPumpPin {
     GpioPumpPin {
         pump_pin,
         status_led_pin,
+        config: pump_config.clone(),
     }
 }
 
@@ -353,3 +583,17 @@ fn set_pin_value(pin: &Option<Pin>, value: u8) {
             .expect("GPIO Pin is not working. Could not set value.")
     }
 }
+
+/// Switches the output off before it is handed back.
+fn unexport_pin(pin: &Option<Pin>) -> Result<(), Error> {
+    if let Some(p) = pin {
+        p.set_value(0)?;
+        p.unexport()?;
+    }
+    Ok(())
+}
+
+/// Whether a power or error led moved to another pin.
+fn is_pin_replaced(pin: &Option<Pin>, pin_num: Option<u8>) -> bool {
+    pin.map(|p| p.get_pin_num() as u8) != pin_num
+}
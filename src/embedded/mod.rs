@@ -2,23 +2,38 @@
 use core::convert;
 use core::fmt;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use crate::embedded::configuration::LayoutConfig;
 
 pub mod command;
 pub mod configuration;
-#[cfg(not(feature = "gpio"))]
 pub mod fake;
 #[cfg(feature = "gpio")]
 pub mod gpio;
+pub mod simulator;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Copy, Clone)]
 pub struct ValvePinNumber(pub u8);
 
 pub trait PinLayout<T> {
+    #[cfg_attr(not(feature = "gpio"), allow(dead_code))]
     fn new(config: &LayoutConfig) -> Self;
-    fn find_pin(&self, valve_pin_num: ValvePinNumber) -> Result<&Arc<Mutex<T>>, ()>;
+    /// Takes over a changed layout without the start sequence. Pins of removed or rewired parts
+    /// are released, unchanged valves keep their state. If a pin can not be released the parts of
+    /// the running layout are kept, but those released before stay released.
+    fn reconfigure(&mut self, config: &LayoutConfig) -> Result<(), Error>;
+    fn find_pin(&self, valve_pin_num: ValvePinNumber) -> Result<&Arc<Mutex<T>>, Error>;
     fn get_layout_status(&self) -> LayoutStatus;
+    /// Opens the master valve for the zone valve, if there is one. Returns how long to wait for
+    /// the pressure before the zone valve is turned on.
+    fn acquire_master_valve(&mut self, valve_pin_num: ValvePinNumber) -> Result<Duration, Error>;
+    /// Lets go of the master valve for the zone valve. Returns how long to wait before
+    /// `close_master_valve` if no other zone valve holds it.
+    fn release_master_valve(&mut self, valve_pin_num: ValvePinNumber) -> Option<Duration>;
+    /// Closes the master valve unless a zone valve acquired it in the meantime.
+    fn close_master_valve(&mut self) -> Result<(), Error>;
+    /// Turns on the pump and the zone valve, the master valve has to be acquired before.
     fn turn_on(&mut self, valve_pin_num: ValvePinNumber) -> Result<(), Error>;
     fn turn_off(&mut self, valve_pin_num: ValvePinNumber) -> Result<(), Error>;
 }
@@ -30,39 +45,85 @@ pub trait ToggleValve {
     fn get_valve_pin_num(&self) -> &ValvePinNumber;
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ValveStatus {
     OPEN,
     CLOSED,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PumpStatus {
+    ON,
+    OFF,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LayoutStatus {
     valves: Vec<ToggleValveStatus>,
+    master_valve: Option<ValveStatus>,
+    pump: Option<PumpStatus>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl LayoutStatus {
+    pub fn get_valves(&self) -> &Vec<ToggleValveStatus> {
+        &self.valves
+    }
+    pub fn get_master_valve(&self) -> &Option<ValveStatus> {
+        &self.master_valve
+    }
+    pub fn get_pump(&self) -> &Option<PumpStatus> {
+        &self.pump
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ToggleValveStatus {
     valve_pin_number: ValvePinNumber,
     status: ValveStatus,
 }
 
+impl ToggleValveStatus {
+    pub fn get_valve_pin_number(&self) -> ValvePinNumber {
+        self.valve_pin_number
+    }
+    pub fn get_status(&self) -> &ValveStatus {
+        &self.status
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
-    Unexpected(String),
+    ValveNotFound(ValvePinNumber),
+    ButtonNotFound(u8),
+    HardwareIo(String),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::Unexpected(ref s) => write!(f, "Unexpected: {}", s),
+            Error::ValveNotFound(ref pin) => write!(f, "Valve not found: {}", pin.0),
+            Error::ButtonNotFound(ref pin) => write!(f, "No button on pin: {}", pin),
+            Error::HardwareIo(ref s) => write!(f, "Hardware I/O: {}", s),
+        }
+    }
+}
+
+impl Error {
+    /// Stable identifier of the error kind for remote callers.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::ValveNotFound(_) => "VALVE_NOT_FOUND",
+            Error::ButtonNotFound(_) => "BUTTON_NOT_FOUND",
+            Error::HardwareIo(_) => "HARDWARE_IO",
         }
     }
 }
 
+impl std::error::Error for Error {}
+
 #[cfg(feature = "gpio")]
 impl convert::From<sysfs_gpio::Error> for Error {
     fn from(e: sysfs_gpio::Error) -> Error {
-        Error::Unexpected(e.to_string())
+        Error::HardwareIo(e.to_string())
     }
 }
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::sync::{Arc, Mutex};
+
+use chrono::{NaiveDateTime, NaiveTime};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Sender;
+
+use crate::communication::event::SensorReading;
+use crate::communication::Request;
+use crate::embedded::command::LayoutRequest;
+use crate::embedded::fake::{FakePinLayout, FaultInjector};
+use crate::embedded::PinLayout;
+use crate::schedule::{Clock, ManualClock};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PinKind {
+    Power,
+    Error,
+    Pump,
+    MasterValve,
+    Valve,
+    StatusLed,
+    Button,
+}
+
+/// A single recorded pin change of the simulated hardware.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub timestamp: NaiveDateTime,
+    pub pin_num: u8,
+    pub kind: PinKind,
+    pub value: u8,
+}
+
+/// Shared state of the simulated hardware behind a `FakePinLayout`. Records every pin change at
+/// the time of `clock` and lets tests or the simulator console inject failures. New sensor
+/// readings are sent to the subscribers.
+#[derive(Clone)]
+pub struct Simulator {
+    faults: FaultInjector,
+    clock: Arc<dyn Clock>,
+    sensor_reading_tx: broadcast::Sender<SensorReading>,
+    inner: Arc<Mutex<SimulatorState>>,
+}
+
+#[derive(Default)]
+struct SimulatorState {
+    sensor_readings: HashMap<String, f64>,
+    timeline: Vec<Transition>,
+}
+
+impl Simulator {
+    pub fn new(faults: FaultInjector, clock: Arc<dyn Clock>) -> Simulator {
+        let (sensor_reading_tx, _) = broadcast::channel(16);
+        Simulator {
+            faults,
+            clock,
+            sensor_reading_tx,
+            inner: Arc::new(Mutex::new(SimulatorState::default())),
+        }
+    }
+
+    pub fn get_fault_injector(&self) -> &FaultInjector {
+        &self.faults
+    }
+
+    pub fn set_sensor_reading(&self, sensor: &str, value: f64) {
+        self.inner
+            .lock()
+            .unwrap()
+            .sensor_readings
+            .insert(sensor.to_string(), value);
+        // fails only without subscribers
+        let _ = self.sensor_reading_tx.send(SensorReading {
+            sensor: sensor.to_string(),
+            value,
+            timestamp: self.clock.now(),
+        });
+    }
+
+    pub fn subscribe_sensor_readings(&self) -> broadcast::Receiver<SensorReading> {
+        self.sensor_reading_tx.subscribe()
+    }
+
+    pub fn get_sensor_reading(&self, sensor: &str) -> Option<f64> {
+        self.inner
+            .lock()
+            .unwrap()
+            .sensor_readings
+            .get(sensor)
+            .cloned()
+    }
+
+    pub fn get_timeline(&self) -> Vec<Transition> {
+        self.inner.lock().unwrap().timeline.clone()
+    }
+
+    /// Recorded transitions of a single pin, handy for asserting open/close sequences.
+    pub fn get_pin_timeline(&self, pin_num: u8) -> Vec<Transition> {
+        self.get_timeline()
+            .into_iter()
+            .filter(|t| t.pin_num == pin_num)
+            .collect()
+    }
+
+    pub fn clear_timeline(&self) {
+        self.inner.lock().unwrap().timeline.clear();
+    }
+
+    pub(in crate::embedded) fn record(&self, pin_num: u8, kind: PinKind, value: u8) {
+        self.inner.lock().unwrap().timeline.push(Transition {
+            timestamp: self.clock.now(),
+            pin_num,
+            kind,
+            value,
+        });
+    }
+}
+
+/// Reads simulator commands from stdin, one per line:
+/// `press <button>`, `fail <pin>`, `stick <pin>`, `repair <pin>`, `sensor <name> [<value>]`,
+/// `status`, `timeline [<pin>]`, `clear`, `time <hh:mm>` and `advance <minutes>`.
+pub fn spawn_console(
+    layout: Arc<Mutex<FakePinLayout>>,
+    mut layout_command_sender: Sender<LayoutRequest>,
+    clock: ManualClock,
+) {
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if let Err(e) =
+                        run_console_command(&layout, &mut layout_command_sender, &clock, &line)
+                    {
+                        println!("simulator: {}", e);
+                    }
+                }
+                Err(e) => {
+                    println!("simulator: could not read stdin = {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+fn run_console_command(
+    layout: &Arc<Mutex<FakePinLayout>>,
+    layout_command_sender: &mut Sender<LayoutRequest>,
+    clock: &ManualClock,
+    line: &str,
+) -> Result<(), String> {
+    let simulator = layout.lock().unwrap().get_simulator();
+    let faults = simulator.get_fault_injector();
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.as_slice() {
+        ["press", pin] => {
+            let command = layout
+                .lock()
+                .unwrap()
+                .press_button(parse_pin(pin)?)
+                .map_err(|e| e.to_string())?;
+            layout_command_sender
+                .try_send(Request::new(command))
+                .map_err(|e| e.to_string())
+        }
+        ["fail", pin] => {
+            faults.fail_pin(parse_pin(pin)?);
+            Ok(())
+        }
+        ["stick", pin] => {
+            faults.stick_pin(parse_pin(pin)?);
+            Ok(())
+        }
+        ["repair", pin] => {
+            faults.repair_pin(parse_pin(pin)?);
+            Ok(())
+        }
+        ["sensor", name, value] => {
+            let value = value.parse::<f64>().map_err(|e| e.to_string())?;
+            simulator.set_sensor_reading(name, value);
+            Ok(())
+        }
+        ["sensor", name] => {
+            println!("{} = {:?}", name, simulator.get_sensor_reading(name));
+            Ok(())
+        }
+        ["status"] => {
+            println!("{:?}", layout.lock().unwrap().get_layout_status());
+            Ok(())
+        }
+        ["timeline"] => {
+            print_timeline(&simulator.get_timeline());
+            Ok(())
+        }
+        ["timeline", pin] => {
+            print_timeline(&simulator.get_pin_timeline(parse_pin(pin)?));
+            Ok(())
+        }
+        ["clear"] => {
+            simulator.clear_timeline();
+            Ok(())
+        }
+        ["time", time] => {
+            let time = NaiveTime::parse_from_str(time, "%H:%M").map_err(|e| e.to_string())?;
+            clock.set(clock.now().date().and_time(time));
+            Ok(())
+        }
+        ["advance", minutes] => {
+            let minutes = minutes.parse::<i64>().map_err(|e| e.to_string())?;
+            clock.advance(chrono::Duration::minutes(minutes));
+            Ok(())
+        }
+        [] => Ok(()),
+        _ => Err(format!("unknown command '{}'", line)),
+    }
+}
+
+fn parse_pin(pin: &str) -> Result<u8, String> {
+    pin.parse::<u8>().map_err(|e| e.to_string())
+}
+
+fn print_timeline(timeline: &[Transition]) {
+    for t in timeline {
+        println!(
+            "{} {:?} {} -> {}",
+            t.timestamp.format("%Y-%m-%d][%H:%M:%S%.3f"),
+            t.kind,
+            t.pin_num,
+            t.value
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    use crate::embedded::command::LayoutCommand;
+    use crate::embedded::configuration::LayoutConfig;
+
+    use super::*;
+
+    struct Console {
+        layout: Arc<Mutex<FakePinLayout>>,
+        sender: Sender<LayoutRequest>,
+        receiver: Receiver<LayoutRequest>,
+        clock: ManualClock,
+        simulator: Simulator,
+    }
+
+    impl Console {
+        fn new() -> Console {
+            let config: LayoutConfig = serde_json::from_str(
+                r#"{
+                    "valves": [
+                        {"valve": 27, "button": 22, "status_led": 24},
+                        {"valve": 10, "button": 9}
+                    ],
+                    "pump": {"power_pin": 5}
+                }"#,
+            )
+            .unwrap();
+            let clock = ManualClock::new(NaiveDate::from_ymd(2020, 6, 1).and_hms(18, 0, 0));
+            let simulator = Simulator::new(FaultInjector::default(), Arc::new(clock.clone()));
+            let layout = FakePinLayout::with_simulator(&config, simulator.clone());
+            simulator.clear_timeline();
+            let (sender, receiver) = mpsc::channel(16);
+            Console {
+                layout: Arc::new(Mutex::new(layout)),
+                sender,
+                receiver,
+                clock,
+                simulator,
+            }
+        }
+
+        /// Runs the line like the console and executes the command it sent, if any.
+        fn run(&mut self, line: &str) -> Result<(), String> {
+            run_console_command(&self.layout, &mut self.sender, &self.clock, line)?;
+            match self.receiver.try_recv() {
+                Ok(request) => {
+                    let mut layout = self.layout.lock().unwrap();
+                    match request.command {
+                        LayoutCommand::Open(valve, _) => layout
+                            .acquire_master_valve(valve)
+                            .and_then(|_| layout.turn_on(valve)),
+                        LayoutCommand::Close(valve, _) => layout.turn_off(valve),
+                    }
+                    .map_err(|e| e.to_string())
+                }
+                Err(_) => Ok(()),
+            }
+        }
+
+        fn get_pin_timeline(&self, pin_num: u8) -> Vec<(String, u8)> {
+            self.simulator
+                .get_pin_timeline(pin_num)
+                .iter()
+                .map(|t| (t.timestamp.format("%H:%M").to_string(), t.value))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn button_presses_toggle_the_valve_at_the_time_of_the_clock() {
+        let mut console = Console::new();
+
+        console.run("press 22").unwrap();
+        console.run("advance 15").unwrap();
+        console.run("press 22").unwrap();
+
+        assert_eq!(
+            console.get_pin_timeline(27),
+            vec![(String::from("18:00"), 1), (String::from("18:15"), 0)]
+        );
+        assert_eq!(
+            console.get_pin_timeline(24),
+            vec![(String::from("18:00"), 1), (String::from("18:15"), 0)]
+        );
+        assert_eq!(
+            console.get_pin_timeline(22),
+            vec![(String::from("18:00"), 1), (String::from("18:15"), 1)]
+        );
+        assert_eq!(
+            console.get_pin_timeline(5),
+            vec![(String::from("18:00"), 1), (String::from("18:15"), 0)]
+        );
+    }
+
+    #[test]
+    fn failed_pins_record_nothing_until_repaired() {
+        let mut console = Console::new();
+
+        console.run("fail 27").unwrap();
+        assert!(console.run("press 22").is_err());
+        console.run("time 19:30").unwrap();
+        console.run("repair 27").unwrap();
+        console.run("press 22").unwrap();
+
+        assert_eq!(
+            console.get_pin_timeline(27),
+            vec![(String::from("19:30"), 1)]
+        );
+    }
+
+    #[test]
+    fn stuck_valves_stay_closed_while_the_pump_runs() {
+        let mut console = Console::new();
+
+        console.run("stick 10").unwrap();
+        console.run("press 9").unwrap();
+
+        assert_eq!(console.get_pin_timeline(10), vec![]);
+        assert_eq!(
+            console.get_pin_timeline(5),
+            vec![(String::from("18:00"), 1)]
+        );
+    }
+
+    #[test]
+    fn sensor_readings_are_kept_per_sensor() {
+        let mut console = Console::new();
+
+        console.run("sensor moisture 0.4").unwrap();
+
+        assert_eq!(console.simulator.get_sensor_reading("moisture"), Some(0.4));
+        assert_eq!(console.simulator.get_sensor_reading("rain"), None);
+    }
+
+    #[test]
+    fn sensor_readings_are_sent_at_the_time_of_the_clock() {
+        let mut console = Console::new();
+        let mut readings = console.simulator.subscribe_sensor_readings();
+
+        console.run("time 07:30").unwrap();
+        console.run("sensor moisture 0.25").unwrap();
+
+        assert_eq!(
+            readings.try_recv().unwrap(),
+            SensorReading {
+                sensor: String::from("moisture"),
+                value: 0.25,
+                timestamp: NaiveDate::from_ymd(2020, 6, 1).and_hms(7, 30, 0),
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        let mut console = Console::new();
+
+        assert!(console.run("flood 27").is_err());
+        assert!(console.run("press 99").is_err());
+    }
+}
@@ -0,0 +1,488 @@
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use hyper::body::HttpBody;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::control::{Controller, Error};
+use crate::embedded::command::Origin;
+use crate::embedded::ValvePinNumber;
+use crate::http::events::stream_events;
+use crate::http::ui;
+use crate::schedule::WateringScheduleConfig;
+
+#[derive(Serialize, Debug)]
+struct ErrorResponse {
+    error_code: &'static str,
+    error: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ScheduleUpdate {
+    enabled: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct ValveOpening {
+    minutes: Option<u64>,
+}
+
+const DEFAULT_RUN_COUNT: usize = 5;
+/// Bodies are small json objects, larger ones are rejected before they are read completely.
+const MAX_BODY_BYTES: usize = 16 * 1024;
+
+pub async fn handle(
+    controller: Arc<Controller>,
+    token: Arc<Option<String>>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    if method == Method::GET && path.trim_end_matches('/') == "/api/events" {
+        return Ok(stream_events(&controller));
+    }
+    if method == Method::GET {
+        if let Some(response) = ui::serve(&path) {
+            return Ok(response);
+        }
+    }
+    let result = match authorize(&token, &request) {
+        Ok(_) => route(&controller, &method, &path, request).await,
+        Err(e) => Err(e),
+    };
+    let response = match result {
+        Ok(body) => json_response(StatusCode::OK, &body),
+        Err(e) => {
+            warn!("{} {}: http error = {}", method, path, e);
+            let body = ErrorResponse {
+                error_code: e.code(),
+                error: e.to_string(),
+            };
+            json_response(get_status(&e), &body)
+        }
+    };
+    Ok(response)
+}
+
+/// Every request but GET changes something and needs `Authorization: Bearer {token}` with the
+/// configured token, without one the api is read only.
+fn authorize(token: &Option<String>, request: &Request<Body>) -> Result<(), Error> {
+    if request.method() == Method::GET {
+        return Ok(());
+    }
+    let token = token.as_ref().ok_or_else(|| {
+        Error::Unauthorized(String::from("no token is configured, the api is read only"))
+    })?;
+    let given = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if is_same_token(given.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => Err(Error::Unauthorized(String::from("missing or wrong token"))),
+    }
+}
+
+/// Compares in constant time, the token must not be guessable byte by byte.
+fn is_same_token(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len() && given.iter().zip(token).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Schedules are addressed by their id, e.g. `/api/schedules/27-0600-0630`.
+///
+/// | method | path | |
+/// |---|---|---|
+/// | GET | /api/health | `UP` and the mqtt connection state |
+/// | GET | /api/events | server-sent events with the live status, see `stream_events` |
+/// | GET | /api/layout | layout config |
+/// | GET | /api/valves | layout status |
+/// | POST | /api/valves/{pin}/open | opens the valve, closes it after `{"minutes": 10}` if given, answers the layout status |
+/// | POST | /api/valves/{pin}/close | closes the valve, answers the layout status |
+/// | GET | /api/schedules | all schedules |
+/// | POST | /api/schedules | creates the schedule in the body, answers all schedules |
+/// | GET | /api/schedules/{id} | one schedule |
+/// | PUT | /api/schedules/{id} | `{"enabled": true}` enables or disables it, answers all schedules |
+/// | DELETE | /api/schedules/{id} | deletes it, answers all schedules |
+/// | GET | /api/runs?count=5 | the next waterings of all enabled schedules |
+async fn route(
+    controller: &Controller,
+    method: &Method,
+    path: &str,
+    request: Request<Body>,
+) -> Result<serde_json::Value, Error> {
+    let query = request.uri().query().unwrap_or("").to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (&Method::GET, ["api", "health"]) => Ok(to_json(&controller.get_health())),
+        (&Method::GET, ["api", "layout"]) => Ok(to_json(&controller.get_layout_config())),
+        (&Method::GET, ["api", "valves"]) => Ok(to_json(&controller.get_layout_status())),
+        (&Method::POST, ["api", "valves", pin, "open"]) => {
+            let valve = parse_valve_pin(pin)?;
+            let opening: Option<ValveOpening> = read_optional_json(request).await?;
+            let minutes = opening.and_then(|o| o.minutes);
+            let status = controller.open_valve(valve, minutes, Origin::Http).await?;
+            Ok(to_json(&status))
+        }
+        (&Method::POST, ["api", "valves", pin, "close"]) => {
+            let valve = parse_valve_pin(pin)?;
+            let status = controller.close_valve(valve, Origin::Http).await?;
+            Ok(to_json(&status))
+        }
+        (&Method::GET, ["api", "schedules"]) => Ok(to_json(&controller.get_schedules())),
+        (&Method::POST, ["api", "schedules"]) => {
+            let schedule: WateringScheduleConfig = read_json(request).await?;
+            Ok(to_json(&controller.create_schedule(schedule).await?))
+        }
+        (&Method::GET, ["api", "schedules", id]) => Ok(to_json(&controller.get_schedule(id)?)),
+        (&Method::PUT, ["api", "schedules", id]) => {
+            let update: ScheduleUpdate = read_json(request).await?;
+            Ok(to_json(
+                &controller.switch_schedule(id, update.enabled).await?,
+            ))
+        }
+        (&Method::DELETE, ["api", "schedules", id]) => {
+            Ok(to_json(&controller.delete_schedule(id).await?))
+        }
+        (&Method::GET, ["api", "runs"]) => {
+            let count = get_run_count(&query)?;
+            Ok(to_json(&controller.get_next_runs(count)))
+        }
+        _ => Err(Error::NotFound(format!("{} {}", method, path))),
+    }
+}
+
+fn get_status(e: &Error) -> StatusCode {
+    match *e {
+        Error::NotFound(_) => StatusCode::NOT_FOUND,
+        Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        Error::CommandDispatch(_) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::Configuration(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        Error::Layout(crate::embedded::Error::ValveNotFound(_)) => StatusCode::NOT_FOUND,
+        Error::Layout(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        Error::Schedule(crate::schedule::Error::ScheduleNotFound(_)) => StatusCode::NOT_FOUND,
+        Error::Schedule(crate::schedule::Error::DuplicateSchedule(_)) => StatusCode::CONFLICT,
+        Error::Schedule(crate::schedule::Error::OverlappingSchedule(_, _)) => StatusCode::CONFLICT,
+        Error::Schedule(crate::schedule::Error::InvalidSchedule(_, _)) => StatusCode::BAD_REQUEST,
+        Error::Schedule(crate::schedule::Error::UnknownValve(_)) => StatusCode::BAD_REQUEST,
+        Error::Schedule(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn get_run_count(query: &str) -> Result<usize, Error> {
+    match get_query_param(query, "count") {
+        Some(count) => usize::from_str(count)
+            .map_err(|e| Error::InvalidRequest(format!("invalid count {} = {}", count, e))),
+        None => Ok(DEFAULT_RUN_COUNT),
+    }
+}
+
+fn parse_valve_pin(pin: &str) -> Result<ValvePinNumber, Error> {
+    u8::from_str(pin)
+        .map(ValvePinNumber)
+        .map_err(|e| Error::InvalidRequest(format!("invalid valve {} = {}", pin, e)))
+}
+
+async fn read_json<T: DeserializeOwned>(request: Request<Body>) -> Result<T, Error> {
+    read_optional_json(request)
+        .await?
+        .ok_or_else(|| Error::InvalidRequest(String::from("body is missing")))
+}
+
+/// An empty body is `None`.
+async fn read_optional_json<T: DeserializeOwned>(
+    request: Request<Body>,
+) -> Result<Option<T>, Error> {
+    let body = read_body(request.into_body()).await?;
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| Error::InvalidRequest(e.to_string()))
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Error::InvalidRequest(e.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(Error::InvalidRequest(format!(
+                "body is larger than {} bytes",
+                MAX_BODY_BYTES
+            )));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn get_query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn to_json<S: Serialize>(value: &S) -> serde_json::Value {
+    serde_json::to_value(value).unwrap()
+}
+
+fn json_response<S: Serialize>(status: StatusCode, body: &S) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(body).unwrap()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::embedded::configuration::LayoutConfig;
+    use crate::embedded::fake::{FakePinLayout, FaultInjector};
+    use crate::embedded::simulator::Simulator;
+    use crate::mqtt::configuration::MqttConfig;
+    use crate::mqtt::MqttSession;
+    use crate::schedule::{SystemClock, WateringScheduleConfigs};
+
+    const TOKEN: &str = "secret";
+
+    /// Without command senders, so that changes fail with `CommandDispatch` once routed.
+    fn create_controller() -> Controller {
+        let layout_config: LayoutConfig =
+            serde_json::from_str(r#"{"valves": [{"valve": 27, "button": 22}]}"#).unwrap();
+        let mqtt_config: MqttConfig =
+            serde_json::from_str(r#"{"client_id": "garden", "broker_hostname": "localhost"}"#)
+                .unwrap();
+        let schedules: WateringScheduleConfigs =
+            serde_json::from_str(r#"{"schedules": []}"#).unwrap();
+        let clock = Arc::new(SystemClock {});
+        let layout = FakePinLayout::with_simulator(
+            &layout_config,
+            Simulator::new(FaultInjector::default(), clock.clone()),
+        );
+        let (status_event_sender, _) = broadcast::channel(16);
+        Controller::new(
+            Arc::new(Mutex::new(layout)),
+            Arc::new(Mutex::new(layout_config.clone())),
+            Arc::new(Mutex::new(schedules)),
+            MqttSession::from_config(mqtt_config, &layout_config),
+            &None,
+            &None,
+            status_event_sender,
+            clock,
+        )
+    }
+
+    fn request(
+        method: Method,
+        uri: &str,
+        authorization: Option<&str>,
+        body: &str,
+    ) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(authorization) = authorization {
+            builder = builder.header(AUTHORIZATION, authorization);
+        }
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    async fn call(
+        controller: &Controller,
+        method: Method,
+        uri: &str,
+    ) -> Result<serde_json::Value, Error> {
+        let request = request(method.clone(), uri, None, "");
+        let path = request.uri().path().to_string();
+        route(controller, &method, &path, request).await
+    }
+
+    fn authorize_with(
+        token: Option<&str>,
+        method: Method,
+        authorization: Option<&str>,
+    ) -> Result<(), Error> {
+        authorize(
+            &token.map(String::from),
+            &request(method, "/api/valves/27/close", authorization, ""),
+        )
+    }
+
+    #[test]
+    fn reads_need_no_token() {
+        authorize_with(None, Method::GET, None).unwrap();
+        authorize_with(Some(TOKEN), Method::GET, None).unwrap();
+    }
+
+    #[test]
+    fn changes_need_the_configured_token() {
+        let bearer = format!("Bearer {}", TOKEN);
+        for method in &[Method::POST, Method::PUT, Method::DELETE] {
+            authorize_with(Some(TOKEN), method.clone(), Some(&bearer)).unwrap();
+            for authorization in &[
+                None,
+                Some("Bearer wrong"),
+                Some("Bearer secre"),
+                Some("Bearer secret2"),
+                Some("secret"),
+                Some("Basic c2VjcmV0"),
+            ] {
+                assert!(matches!(
+                    authorize_with(Some(TOKEN), method.clone(), *authorization),
+                    Err(Error::Unauthorized(_))
+                ));
+            }
+            // read only without token
+            assert!(matches!(
+                authorize_with(None, method.clone(), Some(&bearer)),
+                Err(Error::Unauthorized(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn tokens_are_compared_completely() {
+        assert!(is_same_token(b"secret", b"secret"));
+        assert!(!is_same_token(b"secreT", b"secret"));
+        assert!(!is_same_token(b"secret", b"secret "));
+        assert!(!is_same_token(b"", b"secret"));
+    }
+
+    #[test]
+    fn errors_map_to_http_status_codes() {
+        let schedule = WateringScheduleConfig::from_id("27-0600-0630").unwrap();
+        let cases = vec![
+            (Error::NotFound(String::new()), StatusCode::NOT_FOUND),
+            (
+                Error::InvalidRequest(String::new()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (Error::Unauthorized(String::new()), StatusCode::UNAUTHORIZED),
+            (
+                Error::CommandDispatch(String::new()),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                Error::Layout(crate::embedded::Error::ValveNotFound(ValvePinNumber(3))),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                Error::Schedule(crate::schedule::Error::ScheduleNotFound(schedule)),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                Error::Schedule(crate::schedule::Error::DuplicateSchedule(schedule)),
+                StatusCode::CONFLICT,
+            ),
+            (
+                Error::Schedule(crate::schedule::Error::UnknownValve(schedule)),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                Error::Schedule(crate::schedule::Error::Persistence(String::new())),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (error, status) in cases {
+            assert_eq!(get_status(&error), status, "{:?}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_are_routed_by_path() {
+        let controller = create_controller();
+
+        assert_eq!(
+            call(&controller, Method::GET, "/api/valves").await.unwrap()["valves"][0]
+                ["valve_pin_number"],
+            27
+        );
+        assert_eq!(
+            call(&controller, Method::GET, "/api/layout/")
+                .await
+                .unwrap()["valves"][0]["valve"],
+            27
+        );
+        assert!(call(&controller, Method::GET, "/api/schedules")
+            .await
+            .unwrap()["schedules"]
+            .is_array());
+        call(&controller, Method::GET, "/api/health").await.unwrap();
+        call(&controller, Method::GET, "/api/runs?count=3")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_and_bad_parameters_are_rejected() {
+        let controller = create_controller();
+
+        for (method, uri) in &[
+            (Method::GET, "/api"),
+            (Method::GET, "/api/pumps"),
+            (Method::GET, "/api/valves/27"),
+            (Method::PATCH, "/api/valves"),
+            (Method::DELETE, "/api/valves/27/open"),
+        ] {
+            assert!(
+                matches!(
+                    call(&controller, method.clone(), uri).await,
+                    Err(Error::NotFound(_))
+                ),
+                "{} {} was routed",
+                method,
+                uri
+            );
+        }
+        assert!(matches!(
+            call(&controller, Method::GET, "/api/schedules/27-0600-0630").await,
+            Err(Error::Schedule(crate::schedule::Error::ScheduleNotFound(_)))
+        ));
+        assert!(matches!(
+            call(&controller, Method::GET, "/api/schedules/27").await,
+            Err(Error::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            call(&controller, Method::POST, "/api/valves/x/open").await,
+            Err(Error::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            call(&controller, Method::GET, "/api/runs?count=many").await,
+            Err(Error::InvalidRequest(_))
+        ));
+        // routed, but nothing executes commands in the test
+        assert!(matches!(
+            call(&controller, Method::POST, "/api/valves/27/close").await,
+            Err(Error::CommandDispatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn bodies_are_limited() {
+        let read = |body: String| async move {
+            read_optional_json::<serde_json::Value>(request(Method::POST, "/", None, &body)).await
+        };
+
+        assert!(read(String::from(" \n")).await.unwrap().is_none());
+        assert_eq!(
+            read(String::from(r#"{"minutes": 10}"#)).await.unwrap(),
+            Some(serde_json::json!({"minutes": 10}))
+        );
+        let large = format!(
+            r#"{{"minutes": 10, "padding": "{}"}}"#,
+            "x".repeat(MAX_BODY_BYTES)
+        );
+        assert!(matches!(read(large).await, Err(Error::InvalidRequest(_))));
+    }
+}
@@ -0,0 +1,151 @@
+"use strict";
+
+// Talks to the rest api of the butler, see src/http/api.rs, and follows /api/events for live
+// updates.
+
+const pad = (n) => String(n).padStart(2, "0");
+
+function scheduleId(s) {
+    const t = s.schedule;
+    return `${s.valve}-${pad(t.start_hour)}${pad(t.start_minute)}-${pad(t.end_hour)}${pad(t.end_minute)}`;
+}
+
+function scheduleName(s) {
+    const t = s.schedule;
+    return `Valve ${s.valve}, ${pad(t.start_hour)}:${pad(t.start_minute)} - ${pad(t.end_hour)}:${pad(t.end_minute)}`;
+}
+
+function showError(message) {
+    const error = document.getElementById("error");
+    error.textContent = message;
+    error.hidden = !message;
+}
+
+// changes need the token configured in [http], it is asked for once and kept in the browser
+async function request(method, path, body, retry = true) {
+    const options = { method, headers: {} };
+    const token = localStorage.getItem("token");
+    if (token) {
+        options.headers["Authorization"] = `Bearer ${token}`;
+    }
+    if (body !== undefined) {
+        options.headers["Content-Type"] = "application/json";
+        options.body = JSON.stringify(body);
+    }
+    const response = await fetch(path, options);
+    const json = await response.json();
+    if (response.status === 401 && retry) {
+        const token = prompt("Token of the butler");
+        if (token) {
+            localStorage.setItem("token", token);
+            return request(method, path, body, false);
+        }
+    }
+    if (!response.ok) {
+        throw new Error(json.error || response.statusText);
+    }
+    return json;
+}
+
+async function run(action) {
+    try {
+        showError("");
+        await action();
+    } catch (e) {
+        showError(e.message);
+    }
+}
+
+function renderValves(layoutStatus) {
+    const list = document.getElementById("valves");
+    const template = document.getElementById("valve-template");
+    list.replaceChildren();
+    for (const valve of layoutStatus.valves) {
+        const pin = valve.valve_pin_number;
+        const item = template.content.cloneNode(true);
+        item.querySelector(".name").textContent = `Valve ${pin}`;
+        const state = item.querySelector(".state");
+        state.textContent = valve.status === "OPEN" ? "watering" : "off";
+        state.classList.toggle("on", valve.status === "OPEN");
+        const minutes = item.querySelector(".minutes");
+        item.querySelector(".open").addEventListener("click", () => run(async () => {
+            renderValves(await request("POST", `/api/valves/${pin}/open`, { minutes: Number(minutes.value) }));
+        }));
+        item.querySelector(".close").addEventListener("click", () => run(async () => {
+            renderValves(await request("POST", `/api/valves/${pin}/close`));
+        }));
+        list.appendChild(item);
+    }
+}
+
+function renderSchedules(configs) {
+    const list = document.getElementById("schedules");
+    const template = document.getElementById("schedule-template");
+    list.replaceChildren();
+    for (const schedule of configs.schedules) {
+        const item = template.content.cloneNode(true);
+        item.querySelector(".name").textContent = scheduleName(schedule);
+        const enabled = item.querySelector(".enabled");
+        enabled.checked = schedule.enabled;
+        enabled.addEventListener("change", () => run(async () => {
+            const id = scheduleId(schedule);
+            renderSchedules(await request("PUT", `/api/schedules/${id}`, { enabled: enabled.checked }));
+            await loadRuns();
+        }));
+        list.appendChild(item);
+    }
+    if (configs.schedules.length === 0) {
+        list.innerHTML = "<li>No schedules</li>";
+    }
+}
+
+function renderRuns(runs) {
+    const list = document.getElementById("runs");
+    list.replaceChildren();
+    for (const run of runs) {
+        const start = new Date(run.start);
+        const end = new Date(run.end);
+        const day = start.toLocaleDateString([], { weekday: "short", day: "numeric", month: "short" });
+        const from = start.toLocaleTimeString([], { hour: "2-digit", minute: "2-digit" });
+        const to = end.toLocaleTimeString([], { hour: "2-digit", minute: "2-digit" });
+        const item = document.createElement("li");
+        item.textContent = `${day} ${from} - ${to}, valve ${run.valve}`;
+        list.appendChild(item);
+    }
+    if (runs.length === 0) {
+        list.innerHTML = "<li>Nothing scheduled</li>";
+    }
+}
+
+function renderHealth(health) {
+    const badge = document.getElementById("connection");
+    badge.textContent = `mqtt ${health.mqtt}`;
+    badge.classList.toggle("on", health.mqtt === "connected");
+}
+
+async function loadRuns() {
+    renderRuns(await request("GET", "/api/runs?count=5"));
+}
+
+function listen() {
+    const events = new EventSource("/api/events");
+    events.addEventListener("layout_status", (e) => renderValves(JSON.parse(e.data).data));
+    events.addEventListener("schedules", (e) => {
+        renderSchedules(JSON.parse(e.data).data);
+        run(loadRuns);
+    });
+    events.addEventListener("health", (e) => renderHealth(JSON.parse(e.data).data));
+    events.onerror = () => {
+        const badge = document.getElementById("connection");
+        badge.textContent = "offline";
+        badge.classList.remove("on");
+    };
+}
+
+run(async () => {
+    renderValves(await request("GET", "/api/valves"));
+    renderSchedules(await request("GET", "/api/schedules"));
+    renderHealth(await request("GET", "/api/health"));
+    await loadRuns();
+});
+listen();
@@ -0,0 +1,37 @@
+use std::net::SocketAddr;
+
+use crate::control::Error;
+
+/// The http api is only served if `[http]` or `http.json` exists.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpConfig {
+    bind_address: Option<String>,
+    port: Option<u16>,
+    /// Bearer token of the requests that change something, e.g. open a valve. The api is read
+    /// only without one. Can also be set with `HTTP_TOKEN` or read from `token_file`.
+    token: Option<String>,
+    token_file: Option<String>,
+}
+
+impl HttpConfig {
+    /// Listens on localhost by default, set e.g. `0.0.0.0` to use the api from the LAN.
+    pub fn get_bind_address(&self) -> &str {
+        self.bind_address.as_deref().unwrap_or("127.0.0.1")
+    }
+    pub fn get_port(&self) -> u16 {
+        self.port.unwrap_or(8080)
+    }
+    pub fn get_socket_addr(&self) -> Result<SocketAddr, Error> {
+        format!("{}:{}", self.get_bind_address(), self.get_port())
+            .parse()
+            .map_err(|e| Error::Configuration(format!("invalid bind address = {}", e)))
+    }
+    pub fn get_token(&self) -> Result<Option<String>, Error> {
+        match &self.token_file {
+            Some(path) => std::fs::read_to_string(path)
+                .map(|token| Some(token.trim_end().to_string()))
+                .map_err(|e| Error::Configuration(format!("could not read {} = {}", path, e))),
+            None => Ok(self.token.clone()),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::prelude::*;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Response};
+
+use crate::communication::event::StatusEvent;
+use crate::control::Controller;
+
+const HEARTBEAT_SECS: u64 = 15;
+
+/// Server-sent events for live dashboards, e.g.
+/// `event: valve_event` `data: {"type":"valve_event","data":{"valve":27,...}}`.
+/// Starts with the current layout status and schedules, followed by every change as it happens
+/// and the health every `HEARTBEAT_SECS`, which also keeps idle connections open.
+pub fn stream_events(controller: &Arc<Controller>) -> Response<Body> {
+    // subscribes before the snapshot so that no change gets lost in between
+    let changes = controller.subscribe();
+    let snapshot = vec![
+        StatusEvent::LayoutStatus(controller.get_layout_status()),
+        StatusEvent::Schedules(controller.get_schedules()),
+    ];
+    let changes = changes.into_stream().filter_map(|event| {
+        future::ready(
+            event
+                .map_err(|e| warn!("live status subscriber missed events = {:?}", e))
+                .ok(),
+        )
+    });
+    let controller = Arc::clone(controller);
+    let heartbeat = tokio::time::interval(Duration::from_secs(HEARTBEAT_SECS))
+        .map(move |_| StatusEvent::Health(controller.get_health()));
+
+    let events = stream::iter(snapshot)
+        .chain(stream::select(changes, heartbeat))
+        .map(|event| Ok::<_, Infallible>(format_event(&event)));
+    Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(Body::wrap_stream(events))
+        .unwrap()
+}
+
+fn format_event(event: &StatusEvent) -> String {
+    format!(
+        "event: {}\ndata: {}\n\n",
+        event.get_name(),
+        serde_json::to_string(event).unwrap()
+    )
+}
@@ -0,0 +1,62 @@
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use futures::FutureExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Server;
+
+use crate::control::{Controller, Error};
+use crate::http::configuration::HttpConfig;
+
+mod api;
+pub mod configuration;
+mod events;
+mod ui;
+
+/// REST api for local control, works without the mqtt broker, see `api` for the routes and
+/// `Controller` for the commands. Serves the web ui of `ui` on `/`.
+pub struct HttpServer {
+    inner: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl HttpServer {
+    pub fn new(http_config: &HttpConfig, controller: Controller) -> Result<HttpServer, Error> {
+        let addr = http_config.get_socket_addr()?;
+        let token = Arc::new(http_config.get_token()?);
+        if token.is_none() {
+            warn!("no http token configured, the http api is read only");
+        }
+        let controller = Arc::new(controller);
+        let make_service = make_service_fn(move |_| {
+            let controller = Arc::clone(&controller);
+            let token = Arc::clone(&token);
+            future::ok::<_, Infallible>(service_fn(move |request| {
+                api::handle(Arc::clone(&controller), Arc::clone(&token), request)
+            }))
+        });
+        let server = Server::try_bind(&addr)
+            .map_err(|e| Error::Configuration(format!("could not bind {} = {}", addr, e)))?
+            .serve(make_service);
+        info!("http api listening on {}", addr);
+
+        let inner = server
+            .map(|result| {
+                if let Err(e) = result {
+                    error!("http server error = {}", e);
+                }
+            })
+            .boxed();
+        Ok(HttpServer { inner })
+    }
+}
+
+impl Future for HttpServer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.poll_unpin(cx)
+    }
+}
@@ -0,0 +1,36 @@
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Response};
+
+/// The web ui is compiled into the binary and needs nothing from the internet, it runs on the
+/// rest api and the live status stream.
+const ASSETS: [(&str, &str, &str); 3] = [
+    (
+        "/",
+        "text/html; charset=utf-8",
+        include_str!("assets/index.html"),
+    ),
+    (
+        "/app.js",
+        "application/javascript; charset=utf-8",
+        include_str!("assets/app.js"),
+    ),
+    (
+        "/style.css",
+        "text/css; charset=utf-8",
+        include_str!("assets/style.css"),
+    ),
+];
+
+pub fn serve(path: &str) -> Option<Response<Body>> {
+    let path = if path == "/index.html" { "/" } else { path };
+    ASSETS
+        .iter()
+        .find(|(asset_path, _, _)| *asset_path == path)
+        .map(|(_, content_type, content)| {
+            Response::builder()
+                .header(CONTENT_TYPE, *content_type)
+                .header(CACHE_CONTROL, "no-cache")
+                .body(Body::from(*content))
+                .unwrap()
+        })
+}
@@ -0,0 +1,41 @@
+use std::io::Write;
+
+use chrono::Local;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Writes to stdout, which ends up in the journal when running as a service. Dependencies only
+/// get to log warnings, their debug output would drown ours.
+struct StdoutLogger;
+
+static LOGGER: StdoutLogger = StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && (is_own_target(metadata.target()) || metadata.level() <= Level::Warn)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!(
+                "{} {:<5} {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                record.level(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+fn is_own_target(target: &str) -> bool {
+    target.split("::").next() == module_path!().split("::").next()
+}
+
+pub fn init(level: LevelFilter) {
+    log::set_logger(&LOGGER).expect("logger is initialized once");
+    log::set_max_level(level);
+}
@@ -4,11 +4,18 @@ extern crate core;
 extern crate crossbeam;
 #[macro_use]
 extern crate futures;
+extern crate hex;
+extern crate hmac;
+#[cfg(feature = "http")]
+extern crate hyper;
+#[macro_use]
+extern crate log;
 extern crate rumqtt;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate sha2;
 #[cfg(feature = "gpio")]
 extern crate sysfs_gpio;
 extern crate tokio;
@@ -17,76 +24,178 @@ use std::sync::{Arc, Mutex};
 
 use serde::export::PhantomData;
 
-#[cfg(not(feature = "gpio"))]
-use crate::embedded::fake::{FakePinLayout, FakeToggleValve};
+use crate::configuration::Configuration;
+use crate::embedded::fake::{FakePinLayout, FakeToggleValve, FaultInjector};
 #[cfg(feature = "gpio")]
 use crate::embedded::gpio::{GpioPinLayout, GpioToggleValve};
+use crate::embedded::simulator::Simulator;
 use crate::embedded::{PinLayout, ToggleValve};
+use crate::options::{Backend, Options};
 use app::App;
 use embedded::configuration::LayoutConfig;
-use mqtt::configuration::MqttConfig;
 use mqtt::MqttSession;
-use schedule::WateringScheduleConfigs;
+#[cfg(feature = "gpio")]
+use schedule::SystemClock;
+use schedule::{Clock, ManualClock};
 
 mod app;
 mod communication;
+mod configuration;
+mod control;
 mod embedded;
+#[cfg(feature = "http")]
+mod http;
+mod logging;
+mod migration;
 mod mqtt;
+mod options;
+mod persistence;
+mod reload;
 mod schedule;
 
 #[cfg(feature = "gpio")]
-pub const LAYOUT_TYPE: PhantomData<GpioPinLayout> = PhantomData;
-#[cfg(feature = "gpio")]
-pub const VALVE_TYPE: PhantomData<GpioToggleValve> = PhantomData;
-#[cfg(not(feature = "gpio"))]
-pub const LAYOUT_TYPE: PhantomData<FakePinLayout> = PhantomData;
-#[cfg(not(feature = "gpio"))]
-pub const VALVE_TYPE: PhantomData<FakeToggleValve> = PhantomData;
+pub const GPIO_VALVE_TYPE: PhantomData<GpioToggleValve> = PhantomData;
+pub const FAKE_VALVE_TYPE: PhantomData<FakeToggleValve> = PhantomData;
 
 #[tokio::main]
 async fn main() -> Result<(), ()> {
-    println!("Garden buttler starting ...");
+    let options = match Options::from_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, options::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if options.is_help() {
+        println!("{}", options::USAGE);
+        return Ok(());
+    }
+    logging::init(options.get_log_level());
+
+    let configuration = match Configuration::load(&options) {
+        Ok(configuration) => configuration,
+        Err(problems) => {
+            for problem in problems {
+                error!("{}", problem);
+            }
+            std::process::exit(1);
+        }
+    };
+    for warning in &configuration.warnings {
+        warn!("{}", warning);
+    }
+    if options.is_check_config() {
+        info!("configuration is valid");
+        return Ok(());
+    }
 
-    let layout_config: Arc<Mutex<LayoutConfig>> = Arc::new(Mutex::new(LayoutConfig::default()));
-    let layout = create_pin_layout(Arc::clone(&layout_config), LAYOUT_TYPE);
+    info!("Garden buttler starting ...");
+    match options.get_backend() {
+        Backend::Gpio => run_on_gpio(&options, &configuration).await,
+        Backend::Simulator => run_simulated(&options, &configuration).await,
+    }
+}
 
-    let mqtt_config = MqttConfig::default();
-    let mqtt_session: Arc<Mutex<MqttSession>> = MqttSession::from_config(mqtt_config.clone());
+#[cfg(feature = "gpio")]
+async fn run_on_gpio(options: &Options, configuration: &Configuration) -> Result<(), ()> {
+    let mut app = create_app(
+        configuration,
+        GpioPinLayout::new(&configuration.layout),
+        GPIO_VALVE_TYPE,
+        Arc::new(SystemClock {}),
+    );
+    start(&mut app, options, configuration);
+    app.listen_to_button_presses();
+    run(app).await
+}
 
-    let watering_schedule_config: WateringScheduleConfigs = WateringScheduleConfigs::default();
+#[cfg(not(feature = "gpio"))]
+async fn run_on_gpio(options: &Options, configuration: &Configuration) -> Result<(), ()> {
+    warn!("Built without gpio support, running the simulator instead.");
+    run_simulated(options, configuration).await
+}
 
-    let mut app = App::new(
+async fn run_simulated(options: &Options, configuration: &Configuration) -> Result<(), ()> {
+    let clock = ManualClock::new(chrono::Local::now().naive_local());
+    let simulator = Simulator::new(FaultInjector::from_env(), Arc::new(clock.clone()));
+    let mut app = create_app(
+        configuration,
+        FakePinLayout::with_simulator(&configuration.layout, simulator),
+        FAKE_VALVE_TYPE,
+        Arc::new(clock.clone()),
+    );
+    start(&mut app, options, configuration);
+    app.report_sensor_readings();
+    app.listen_to_simulator_console(clock);
+    run(app).await
+}
+
+fn create_app<T, U>(
+    configuration: &Configuration,
+    layout: T,
+    valve_type: PhantomData<U>,
+    clock: Arc<dyn Clock>,
+) -> App<T, U>
+where
+    T: PinLayout<U> + Send + 'static,
+    U: ToggleValve + Send + 'static,
+{
+    let layout_config: Arc<Mutex<LayoutConfig>> =
+        Arc::new(Mutex::new(configuration.layout.clone()));
+    let layout = Arc::new(Mutex::new(layout));
+
+    let mqtt_config = configuration.mqtt.clone();
+    let mqtt_session: Arc<Mutex<MqttSession>> =
+        MqttSession::from_config(mqtt_config.clone(), &layout_config.lock().unwrap());
+
+    App::new(
         layout_config,
         layout,
         mqtt_config,
         mqtt_session,
-        watering_schedule_config,
-        VALVE_TYPE,
-    );
+        configuration.watering.clone(),
+        valve_type,
+        clock,
+    )
+}
 
+fn start<T, U>(app: &mut App<T, U>, options: &Options, configuration: &Configuration)
+where
+    T: PinLayout<U> + Send + 'static,
+    U: ToggleValve + Send + 'static,
+{
     app.report_layout_config();
+    app.report_warnings(&configuration.warnings);
     app.report_pin_layout_status();
+    app.report_valve_events();
     app.report_watering_configuration();
+    app.report_device();
+    app.report_home_assistant_discovery();
 
     app.listen_to_layout_commands();
-    #[cfg(feature = "gpio")]
-    {
-        app.listen_to_button_presses();
-    }
     app.start_watering_schedules();
     app.listen_to_watering_config_commands();
+    app.reload_on_hangup(options);
+    app.listen_to_control_socket(&configuration.control);
+    #[cfg(feature = "http")]
+    {
+        if let Some(http) = &configuration.http {
+            app.serve_http(http);
+        }
+    }
 
     app.listen_to_mqtt_commands();
 
-    app.report_online();
+    // announces the device as online once connected
+    app.connect_to_mqtt();
 
-    tokio::spawn(app.wait_for_termination()).await.unwrap()
+    info!("Garden buttler started ...");
 }
 
-fn create_pin_layout<T, U>(config: Arc<Mutex<LayoutConfig>>, _: PhantomData<T>) -> Arc<Mutex<T>>
+async fn run<T, U>(app: App<T, U>) -> Result<(), ()>
 where
-    T: PinLayout<U> + 'static,
+    T: PinLayout<U> + Send + 'static,
     U: ToggleValve + Send + 'static,
 {
-    Arc::new(Mutex::new(T::new(&config.lock().unwrap())))
+    tokio::spawn(app.wait_for_termination()).await.unwrap()
 }
@@ -0,0 +1,136 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Upgrades a file from the version of its index in `Migrations` to the next one.
+type Migration = fn(&mut Map<String, Value>);
+
+/// The formats of a persisted file. Files without a `version` field are version 0, they were
+/// written before the field was introduced.
+pub struct Migrations {
+    migrations: &'static [Migration],
+}
+
+pub const LAYOUT: Migrations = Migrations {
+    migrations: &[drop_pump_status_led_on_power_pin],
+};
+
+pub const SCHEDULES: Migrations = Migrations {
+    migrations: &[unversioned],
+};
+
+impl Migrations {
+    /// The version written by this release.
+    pub fn get_version(&self) -> u64 {
+        self.migrations.len() as u64
+    }
+
+    /// Brings `value` to the current version, returns the version it had if it was older.
+    pub fn migrate(&self, value: &mut Value) -> Result<Option<u64>, String> {
+        let object = value
+            .as_object_mut()
+            .ok_or_else(|| String::from("expected an object"))?;
+        let version = object.get("version").map_or(Some(0), Value::as_u64);
+        match version {
+            Some(version) if version == self.get_version() => Ok(None),
+            Some(version) if version < self.get_version() => {
+                for migration in &self.migrations[version as usize..] {
+                    migration(object);
+                }
+                object.insert(String::from("version"), Value::from(self.get_version()));
+                Ok(Some(version))
+            }
+            Some(version) => Err(format!(
+                "version {} is newer than version {} of this release",
+                version,
+                self.get_version()
+            )),
+            None => Err(format!("invalid version {}", object["version"])),
+        }
+    }
+
+    /// Serializes `value` marked with the current version.
+    pub fn to_json<T: Serialize>(&self, value: &T) -> serde_json::Result<String> {
+        let mut json = serde_json::to_value(value)?;
+        if let Some(object) = json.as_object_mut() {
+            object.insert(String::from("version"), Value::from(self.get_version()));
+        }
+        serde_json::to_string(&json)
+    }
+}
+
+/// The layout that came with the first releases used the pump power pin for its status led as
+/// well, which fails validation since pins may only be used once. The led only ever mirrored
+/// the pump, so it is dropped.
+fn drop_pump_status_led_on_power_pin(layout: &mut Map<String, Value>) {
+    if let Some(Value::Object(pump)) = layout.get_mut("pump") {
+        if pump.contains_key("status_led") && pump.get("status_led") == pump.get("power_pin") {
+            pump.remove("status_led");
+        }
+    }
+}
+
+/// Nothing changed but the version field.
+fn unversioned(_: &mut Map<String, Value>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn pump_status_leds_on_the_power_pin_are_dropped() {
+        let mut layout = json!({"pump": {"power_pin": 5, "status_led": 5}, "valves": []});
+
+        assert_eq!(LAYOUT.migrate(&mut layout), Ok(Some(0)));
+        assert_eq!(
+            layout,
+            json!({"pump": {"power_pin": 5}, "valves": [], "version": 1})
+        );
+    }
+
+    #[test]
+    fn pump_status_leds_of_their_own_are_kept() {
+        let mut layout = json!({"pump": {"power_pin": 5, "status_led": 6}});
+
+        assert_eq!(LAYOUT.migrate(&mut layout), Ok(Some(0)));
+        assert_eq!(
+            layout,
+            json!({"pump": {"power_pin": 5, "status_led": 6}, "version": 1})
+        );
+    }
+
+    #[test]
+    fn unversioned_schedules_only_get_a_version() {
+        let mut schedules = json!({"schedules": []});
+
+        assert_eq!(SCHEDULES.migrate(&mut schedules), Ok(Some(0)));
+        assert_eq!(schedules, json!({"schedules": [], "version": 1}));
+    }
+
+    #[test]
+    fn current_versions_are_left_alone() {
+        let mut layout = json!({"pump": {"power_pin": 5, "status_led": 5}, "version": 1});
+
+        assert_eq!(LAYOUT.migrate(&mut layout), Ok(None));
+        assert_eq!(
+            layout,
+            json!({"pump": {"power_pin": 5, "status_led": 5}, "version": 1})
+        );
+    }
+
+    #[test]
+    fn newer_and_invalid_versions_are_rejected() {
+        assert_eq!(
+            SCHEDULES.migrate(&mut json!({"version": 2})),
+            Err(String::from(
+                "version 2 is newer than version 1 of this release"
+            ))
+        );
+        assert_eq!(
+            SCHEDULES.migrate(&mut json!({"version": "1"})),
+            Err(String::from("invalid version \"1\""))
+        );
+        assert!(SCHEDULES.migrate(&mut json!({"version": 1.5})).is_err());
+        assert!(SCHEDULES.migrate(&mut json!({"version": -1})).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::VecDeque;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::mqtt::configuration::{CommandAuthConfig, CommandKind, MqttConfig, TopicSchemeConfig};
+use crate::mqtt::topics::CommandTopic;
+use crate::mqtt::Error;
+
+/// Signature fields of a command envelope and the fields they cover besides the topic.
+#[derive(Debug)]
+pub struct CommandSignature {
+    pub timestamp: i64,
+    pub nonce: String,
+    pub signature: String,
+    pub correlation_id: Option<String>,
+    pub idempotency_key: Option<String>,
+    pub response_topic: Option<String>,
+    /// The envelope's payload exactly as it was sent.
+    pub payload: String,
+}
+
+/// Checks signatures and the command allowlist configured in `command_auth`.
+///
+/// With a secret configured every command has to be sent in an envelope with `timestamp` (unix
+/// seconds), a unique `nonce` and `signature`, the hex encoded HMAC-SHA256 of the topic,
+/// `timestamp`, `nonce`, `correlation_id`, `idempotency_key`, `response_topic` and `payload`
+/// joined with `\n`, e.g. `{topic}\n{timestamp}\n{nonce}\n42\n\n\n27`. Fields missing from the envelope are empty and `payload` is the envelope's payload byte for
+/// byte as sent, e.g. `27` or `{"enabled": true, ...}`.
+/// Plain payloads can not be signed, so the ON/OFF commands of home assistant and the `/set`
+/// commands of homie are rejected once a secret is configured.
+pub struct CommandAuthorizer {
+    secret: Option<Vec<u8>>,
+    max_clock_skew_secs: i64,
+    allowed_commands: Option<Vec<CommandKind>>,
+    seen_nonces: VecDeque<(i64, String)>,
+}
+
+impl CommandAuthorizer {
+    pub fn from_config(mqtt_config: &MqttConfig) -> Result<CommandAuthorizer, Error> {
+        let auth_config = mqtt_config.command_auth.clone();
+        let secret = match &auth_config {
+            Some(auth_config) => auth_config.get_secret()?,
+            None => None,
+        };
+        if secret.is_some() {
+            if mqtt_config.home_assistant.is_some() {
+                warn!("commands have to be signed, the home assistant switches will be rejected");
+            }
+            if mqtt_config.topic_scheme == Some(TopicSchemeConfig::Homie) {
+                warn!("commands have to be signed, homie /set commands will be rejected");
+            }
+        }
+        Ok(CommandAuthorizer {
+            secret: secret.map(String::into_bytes),
+            max_clock_skew_secs: auth_config
+                .as_ref()
+                .map(CommandAuthConfig::get_max_clock_skew_secs)
+                .unwrap_or(300) as i64,
+            allowed_commands: auth_config.and_then(|c| c.allowed_commands),
+            seen_nonces: VecDeque::new(),
+        })
+    }
+
+    /// Rejects unsigned, tampered and replayed commands if a secret is configured.
+    pub fn verify(
+        &mut self,
+        topic: &str,
+        signature: &Option<CommandSignature>,
+    ) -> Result<(), Error> {
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => return Ok(()),
+        };
+        let signature = signature
+            .as_ref()
+            .ok_or_else(|| Error::Unauthorized(String::from("command is not signed")))?;
+
+        let now = Utc::now().timestamp();
+        if (now - signature.timestamp).abs() > self.max_clock_skew_secs {
+            return Err(Error::Unauthorized(String::from(
+                "timestamp is out of the allowed window",
+            )));
+        }
+
+        let code = hex::decode(&signature.signature)
+            .map_err(|e| Error::Unauthorized(format!("signature is not hex = {}", e)))?;
+        let mut mac = Hmac::<Sha256>::new_varkey(secret)
+            .map_err(|_| Error::Configuration(String::from("invalid command secret")))?;
+        mac.input(get_signed_content(topic, signature).as_bytes());
+        mac.verify(&code)
+            .map_err(|_| Error::Unauthorized(String::from("signature does not match")))?;
+
+        // nonces only need to be remembered as long as their timestamp is accepted
+        let max_clock_skew_secs = self.max_clock_skew_secs;
+        self.seen_nonces
+            .retain(|(timestamp, _)| (now - timestamp).abs() <= max_clock_skew_secs);
+        if self.seen_nonces.iter().any(|(_, n)| *n == signature.nonce) {
+            return Err(Error::Unauthorized(String::from("nonce was already used")));
+        }
+        self.seen_nonces
+            .push_back((signature.timestamp, signature.nonce.clone()));
+        Ok(())
+    }
+
+    /// Commands that are not in `allowed_commands` are rejected, whether signed or not.
+    pub fn check_allowed(&self, command_topic: &CommandTopic) -> Result<(), Error> {
+        let kind = command_topic.get_kind();
+        match &self.allowed_commands {
+            Some(allowed) if !allowed.contains(&kind) => Err(Error::Forbidden(format!(
+                "{:?} is not allowed remotely",
+                kind
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn get_signed_content(topic: &str, signature: &CommandSignature) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}\n{}",
+        topic,
+        signature.timestamp,
+        signature.nonce,
+        signature.correlation_id.as_deref().unwrap_or_default(),
+        signature.idempotency_key.as_deref().unwrap_or_default(),
+        signature.response_topic.as_deref().unwrap_or_default(),
+        signature.payload
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPIC: &str = "garden/valve/open";
+    const SECRET: &str = "secret";
+
+    fn create_authorizer(command_auth: &str) -> CommandAuthorizer {
+        let config: MqttConfig = serde_json::from_str(&format!(
+            r#"{{"client_id": "garden", "broker_hostname": "localhost", "command_auth": {}}}"#,
+            command_auth
+        ))
+        .unwrap();
+        CommandAuthorizer::from_config(&config).unwrap()
+    }
+
+    fn sign(timestamp: i64, nonce: &str, payload: &str) -> CommandSignature {
+        let mut signature = CommandSignature {
+            timestamp,
+            nonce: nonce.to_string(),
+            signature: String::new(),
+            correlation_id: Some(String::from("42")),
+            idempotency_key: None,
+            response_topic: None,
+            payload: payload.to_string(),
+        };
+        let mut mac = Hmac::<Sha256>::new_varkey(SECRET.as_bytes()).unwrap();
+        mac.input(get_signed_content(TOPIC, &signature).as_bytes());
+        signature.signature = hex::encode(mac.result().code());
+        signature
+    }
+
+    fn is_unauthorized(result: Result<(), Error>) -> bool {
+        matches!(result, Err(Error::Unauthorized(_)))
+    }
+
+    #[test]
+    fn signed_content_joins_the_fields_by_lines() {
+        let signature = sign(1_600_000_000, "n1", r#"{"valve": 27}"#);
+
+        assert_eq!(
+            get_signed_content(TOPIC, &signature),
+            "garden/valve/open\n1600000000\nn1\n42\n\n\n{\"valve\": 27}"
+        );
+    }
+
+    #[test]
+    fn valid_signatures_are_accepted() {
+        let mut authorizer = create_authorizer(r#"{"secret": "secret"}"#);
+        let now = Utc::now().timestamp();
+
+        authorizer
+            .verify(TOPIC, &Some(sign(now, "n1", "27")))
+            .unwrap();
+        authorizer
+            .verify(TOPIC, &Some(sign(now - 60, "n2", r#"{ "valve" : 27 }"#)))
+            .unwrap();
+    }
+
+    #[test]
+    fn unsigned_commands_are_only_accepted_without_secret() {
+        assert!(is_unauthorized(
+            create_authorizer(r#"{"secret": "secret"}"#).verify(TOPIC, &None)
+        ));
+        create_authorizer("{}").verify(TOPIC, &None).unwrap();
+    }
+
+    #[test]
+    fn tampered_commands_are_rejected() {
+        let mut authorizer = create_authorizer(r#"{"secret": "secret"}"#);
+        let now = Utc::now().timestamp();
+
+        let mut payload = sign(now, "n1", "27");
+        payload.payload = String::from("10");
+        assert!(is_unauthorized(authorizer.verify(TOPIC, &Some(payload))));
+
+        let mut response_topic = sign(now, "n2", "27");
+        response_topic.response_topic = Some(String::from("garden/response/elsewhere"));
+        assert!(is_unauthorized(
+            authorizer.verify(TOPIC, &Some(response_topic))
+        ));
+
+        let mut idempotency_key = sign(now, "n3", "27");
+        idempotency_key.idempotency_key = Some(String::from("other"));
+        assert!(is_unauthorized(
+            authorizer.verify(TOPIC, &Some(idempotency_key))
+        ));
+
+        assert!(is_unauthorized(
+            authorizer.verify("garden/valve/close", &Some(sign(now, "n4", "27")))
+        ));
+    }
+
+    #[test]
+    fn expired_timestamps_are_rejected() {
+        let mut authorizer =
+            create_authorizer(r#"{"secret": "secret", "max_clock_skew_secs": 60}"#);
+        let now = Utc::now().timestamp();
+
+        assert!(is_unauthorized(
+            authorizer.verify(TOPIC, &Some(sign(now - 120, "n1", "27")))
+        ));
+        assert!(is_unauthorized(
+            authorizer.verify(TOPIC, &Some(sign(now + 120, "n2", "27")))
+        ));
+    }
+
+    #[test]
+    fn nonces_are_accepted_once() {
+        let mut authorizer = create_authorizer(r#"{"secret": "secret"}"#);
+        let now = Utc::now().timestamp();
+
+        authorizer
+            .verify(TOPIC, &Some(sign(now, "n1", "27")))
+            .unwrap();
+        assert!(is_unauthorized(
+            authorizer.verify(TOPIC, &Some(sign(now, "n1", "27")))
+        ));
+        assert!(is_unauthorized(
+            authorizer.verify(TOPIC, &Some(sign(now - 1, "n1", "10")))
+        ));
+    }
+
+    #[test]
+    fn commands_outside_the_allowlist_are_forbidden() {
+        let authorizer =
+            create_authorizer(r#"{"allowed_commands": ["valve-open", "valve-close"]}"#);
+
+        authorizer.check_allowed(&CommandTopic::ValveOpen).unwrap();
+        assert!(matches!(
+            authorizer.check_allowed(&CommandTopic::ScheduleDelete),
+            Err(Error::Forbidden(_))
+        ));
+        create_authorizer("{}")
+            .check_allowed(&CommandTopic::ScheduleDelete)
+            .unwrap();
+    }
+}
@@ -1,287 +1,688 @@
-use std::ops::Deref;
+use std::collections::VecDeque;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
 
 use futures::prelude::*;
 use futures::task::{Context, Poll};
 use futures::FutureExt;
-use rumqtt::{Notification, Publish, QoS};
-use tokio::sync::mpsc::Sender;
+use rumqtt::Publish;
+use serde_json::value::RawValue;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::oneshot;
 
-use crate::embedded::command::LayoutCommand;
+use crate::communication;
+use crate::communication::Request;
+use crate::embedded::command::{LayoutCommand, LayoutRequest, Origin};
 use crate::embedded::ValvePinNumber;
-use crate::mqtt::configuration::MqttConfig;
-use crate::mqtt::MqttSession;
-use crate::schedule::WateringConfigCommand;
+use crate::mqtt::auth::{CommandAuthorizer, CommandSignature};
+use crate::mqtt::response::{publish_response, PendingResponse, ResponseTarget};
+use crate::mqtt::topics::{CommandTopic, TopicClass, TopicScheme};
+use crate::mqtt::{Error, MqttSession};
 use crate::schedule::WateringScheduleConfig;
+use crate::schedule::{WateringConfigCommand, WateringConfigRequest};
 
 pub struct MqttCommandListener {
     inner: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl MqttCommandListener {
+    /// Handles every publish as soon as `MqttConnection` received it. Fails if the command secret
+    /// can not be read, commands must not be accepted unsigned then.
     pub fn new(
         mqtt_session: Arc<Mutex<MqttSession>>,
-        mqtt_config: Arc<Mutex<MqttConfig>>,
-        layout_command_sender: &Option<Sender<LayoutCommand>>,
-        watering_config_command_sender: &Option<Sender<WateringConfigCommand>>,
-    ) -> MqttCommandListener {
+        publish_receiver: Receiver<Publish>,
+        layout_command_sender: &Option<Sender<LayoutRequest>>,
+        watering_config_command_sender: &Option<Sender<WateringConfigRequest>>,
+    ) -> Result<MqttCommandListener, Error> {
         let layout_command_tx = layout_command_sender.as_ref().cloned();
         let watering_config_command_tx = watering_config_command_sender.as_ref().cloned();
 
-        subscribe_to_commands(&mqtt_session, &mqtt_config);
-
-        let mqtt_session_2 = Arc::clone(&mqtt_session);
-
-        let listener = tokio::time::interval(Duration::from_secs(1))
-            .map(move |_| mqtt_session.lock().unwrap().receiver.try_recv())
-            .inspect(|n| log_commands(n))
-            .for_each(move |n| {
-                match n {
-                    Ok(Notification::Publish(publish)) => {
-                        if is_valve_open_topic(&publish) {
-                            MqttCommandListener::send_valve_open_command(
-                                &layout_command_tx,
-                                &publish,
-                            )
-                        } else if is_valve_close_topic(&publish) {
-                            MqttCommandListener::send_valve_close_command(
-                                &layout_command_tx,
-                                &publish,
-                            )
-                        } else if is_schedule_enable_topic(&publish) {
-                            MqttCommandListener::send_schedule_enable_command(
-                                &watering_config_command_tx,
-                                &publish,
-                            )
-                        } else if is_schedule_disable_topic(&publish) {
-                            MqttCommandListener::send_schedule_disable_command(
-                                &watering_config_command_tx,
-                                &publish,
-                            )
-                        } else if is_schedule_delete_topic(&publish) {
-                            MqttCommandListener::send_schedule_delete_command(
-                                &watering_config_command_tx,
-                                &publish,
-                            )
-                        } else if is_schedule_create_topic(&publish) {
-                            MqttCommandListener::send_schedule_create_command(
-                                &watering_config_command_tx,
-                                &publish,
-                            )
-                        }
-                    }
-                    Ok(Notification::Reconnection) => {
-                        {
-                            let mut guard = mqtt_session_2.lock().unwrap();
-                            let topic =
-                                guard.config.client_id.clone() + "/garden-butler/status/health";
-                            guard
-                                .publish(topic, QoS::ExactlyOnce, true, "ONLINE")
-                                .map_err(|e| println!("error = {}", e))
-                                .unwrap_or(());
-                        }
-                        subscribe_to_commands(&mqtt_session_2, &mqtt_config);
-                    }
-                    Err(_) => {}
-                    _ => println!("other mqtt message"),
-                }
-                future::ready(())
-            })
-            .map(|_| ());
+        let mut authorizer = CommandAuthorizer::from_config(&mqtt_session.lock().unwrap().config)?;
+        subscribe_to_commands(&mqtt_session);
+
+        let recent_commands = Arc::new(Mutex::new(RecentCommands::new(RECENT_COMMANDS_CAPACITY)));
+        let listener = publish_receiver.for_each(move |publish| {
+            MqttCommandListener::handle_publish(
+                &mqtt_session,
+                &layout_command_tx,
+                &watering_config_command_tx,
+                &mut authorizer,
+                &recent_commands,
+                &publish,
+            );
+            future::ready(())
+        });
         let inner = listener.boxed();
-        MqttCommandListener { inner }
+        Ok(MqttCommandListener { inner })
     }
 
-    fn send_valve_close_command(
-        layout_command_tx: &Option<Sender<LayoutCommand>>,
+    fn handle_publish(
+        mqtt_session: &Arc<Mutex<MqttSession>>,
+        layout_command_tx: &Option<Sender<LayoutRequest>>,
+        watering_config_command_tx: &Option<Sender<WateringConfigRequest>>,
+        authorizer: &mut CommandAuthorizer,
+        recent_commands: &Arc<Mutex<RecentCommands>>,
         publish: &Publish,
     ) {
-        let s = get_valve_pin_num_from_message(&publish);
-        if let Ok(pin_num) = s {
-            if let Some(tx) = layout_command_tx {
-                match tx.clone().try_send(LayoutCommand::Close(pin_num)) {
-                    Ok(_) => {}
-                    Err(e) => println!("error sending close command = {}", e),
+        let topics = mqtt_session.lock().unwrap().topics();
+        let ParsedMessage {
+            payload,
+            response_target,
+            idempotency_key,
+            signature,
+        } = match parse_message(&publish.payload, topics.as_ref()) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("{}: command error = {}", publish.topic_name, e);
+                if let Some(target) = get_error_response_target(&publish.payload, topics.as_ref()) {
+                    publish_response(mqtt_session, &target, Err(e));
                 }
+                return;
             }
-        }
-    }
-
-    fn send_valve_open_command(
-        layout_command_tx: &Option<Sender<LayoutCommand>>,
-        publish: &Publish,
-    ) {
-        let s = get_valve_pin_num_from_message(&publish);
-        if let Ok(pin_num) = s {
-            if let Some(tx) = layout_command_tx {
-                match tx.clone().try_send(LayoutCommand::Open(pin_num)) {
-                    Ok(_) => {}
-                    Err(e) => println!("error sending open command = {}", e),
+        };
+        match authorize(
+            authorizer,
+            recent_commands,
+            &publish.topic_name,
+            &idempotency_key,
+            &signature,
+        ) {
+            Ok(true) => {}
+            Ok(false) => {
+                info!("{}: ignoring redelivered command", publish.topic_name);
+                return;
+            }
+            Err(e) => {
+                warn!("{}: command error = {}", publish.topic_name, e);
+                if let Some(target) = response_target {
+                    publish_response(mqtt_session, &target, Err(e));
                 }
+                return;
             }
         }
-    }
 
-    fn send_schedule_enable_command(
-        watering_command_tx: &Option<Sender<WateringConfigCommand>>,
-        publish: &Publish,
-    ) {
-        let schedule_config_result: Result<WateringScheduleConfig, ()> =
-            get_schedule_config_from_message(publish);
-        if let Ok(schedule_config) = schedule_config_result {
-            if let Some(tx) = watering_command_tx {
-                match tx
-                    .clone()
-                    .try_send(WateringConfigCommand::Enable(schedule_config))
-                {
-                    Ok(_) => println!("watering enable command send"),
-                    Err(e) => println!("error sending schedule enable command = {}", e),
+        let pending = MqttCommandListener::dispatch_command(
+            layout_command_tx,
+            watering_config_command_tx,
+            authorizer,
+            topics.as_ref(),
+            publish,
+            &payload,
+        );
+        let mqtt_session = Arc::clone(mqtt_session);
+        let recent_commands = Arc::clone(recent_commands);
+        let topic_name = publish.topic_name.clone();
+        tokio::spawn(async move {
+            let outcome = match pending {
+                Ok(pending) => pending.outcome().await,
+                Err(e) => Err(e),
+            };
+            forget_failed(&recent_commands, &idempotency_key, &outcome);
+            match response_target {
+                Some(target) => publish_response(&mqtt_session, &target, outcome),
+                None => {
+                    if let Err(e) = outcome {
+                        warn!("{}: command error = {}", topic_name, e);
+                    }
                 }
             }
-        }
+        });
     }
 
-    fn send_schedule_disable_command(
-        watering_command_tx: &Option<Sender<WateringConfigCommand>>,
+    fn dispatch_command(
+        layout_command_tx: &Option<Sender<LayoutRequest>>,
+        watering_config_command_tx: &Option<Sender<WateringConfigRequest>>,
+        authorizer: &CommandAuthorizer,
+        topics: &dyn TopicScheme,
         publish: &Publish,
-    ) {
-        let schedule_config_result: Result<WateringScheduleConfig, ()> =
-            get_schedule_config_from_message(publish);
-        if let Ok(schedule_config) = schedule_config_result {
-            if let Some(tx) = watering_command_tx {
-                match tx
-                    .clone()
-                    .try_send(WateringConfigCommand::Disable(schedule_config))
-                {
-                    Ok(_) => println!("watering disable command send"),
-                    Err(e) => println!("error sending schedule enable command = {}", e),
-                }
+        payload: &serde_json::Value,
+    ) -> Result<PendingResponse, Error> {
+        let command_topic = topics
+            .parse_command_topic(&publish.topic_name)
+            .ok_or_else(|| Error::UnknownCommand(publish.topic_name.clone()))?;
+        authorizer.check_allowed(&command_topic)?;
+        match command_topic {
+            CommandTopic::ValveOpen => {
+                let pin_num = get_valve_pin_num_from_payload(payload)?;
+                send_request(
+                    layout_command_tx,
+                    LayoutCommand::Open(pin_num, Origin::Mqtt),
+                )
+                .map(PendingResponse::Layout)
+            }
+            CommandTopic::ValveClose => {
+                let pin_num = get_valve_pin_num_from_payload(payload)?;
+                send_request(
+                    layout_command_tx,
+                    LayoutCommand::Close(pin_num, Origin::Mqtt),
+                )
+                .map(PendingResponse::Layout)
+            }
+            CommandTopic::ValveSwitch(pin_num) => {
+                let command = if get_switch_state_from_payload(payload)? {
+                    LayoutCommand::Open(pin_num, Origin::Mqtt)
+                } else {
+                    LayoutCommand::Close(pin_num, Origin::Mqtt)
+                };
+                send_request(layout_command_tx, command).map(PendingResponse::Layout)
+            }
+            CommandTopic::ScheduleEnable => {
+                let schedule_config = get_schedule_config_from_payload(payload)?;
+                send_request(
+                    watering_config_command_tx,
+                    WateringConfigCommand::Enable(schedule_config),
+                )
+                .map(PendingResponse::Schedule)
+            }
+            CommandTopic::ScheduleDisable => {
+                let schedule_config = get_schedule_config_from_payload(payload)?;
+                send_request(
+                    watering_config_command_tx,
+                    WateringConfigCommand::Disable(schedule_config),
+                )
+                .map(PendingResponse::Schedule)
+            }
+            CommandTopic::ScheduleDelete => {
+                let schedule_config = get_schedule_config_from_payload(payload)?;
+                send_request(
+                    watering_config_command_tx,
+                    WateringConfigCommand::Delete(schedule_config),
+                )
+                .map(PendingResponse::Schedule)
+            }
+            CommandTopic::ScheduleCreate => {
+                let schedule_config = get_schedule_config_from_payload(payload)?;
+                send_request(
+                    watering_config_command_tx,
+                    WateringConfigCommand::Create(schedule_config),
+                )
+                .map(PendingResponse::Schedule)
+            }
+            CommandTopic::ScheduleSwitch => {
+                let schedule_config = get_schedule_config_from_payload(payload)?;
+                send_schedule_switch(watering_config_command_tx, schedule_config)
+            }
+            CommandTopic::ScheduleSwitchFor(mut schedule_config) => {
+                schedule_config.enabled = get_switch_state_from_payload(payload)?;
+                send_schedule_switch(watering_config_command_tx, schedule_config)
             }
         }
     }
+}
 
-    fn send_schedule_delete_command(
-        watering_command_tx: &Option<Sender<WateringConfigCommand>>,
-        publish: &Publish,
-    ) {
-        let schedule_config_result: Result<WateringScheduleConfig, ()> =
-            get_schedule_config_from_message(publish);
-        if let Ok(schedule_config) = schedule_config_result {
-            if let Some(tx) = watering_command_tx {
-                match tx
-                    .clone()
-                    .try_send(WateringConfigCommand::Delete(schedule_config))
-                {
-                    Ok(_) => println!("watering delete command send"),
-                    Err(e) => println!("error sending schedule enable command = {}", e),
-                }
-            }
+/// False for a redelivered command. The idempotency key is checked first, since a redelivered
+/// signed command carries a nonce that was already used. The key of a rejected command is
+/// forgotten, so that it can be sent again.
+fn authorize(
+    authorizer: &mut CommandAuthorizer,
+    recent_commands: &Mutex<RecentCommands>,
+    topic: &str,
+    idempotency_key: &Option<String>,
+    signature: &Option<CommandSignature>,
+) -> Result<bool, Error> {
+    if let Some(key) = idempotency_key {
+        if recent_commands.lock().unwrap().is_duplicate(key.clone()) {
+            return Ok(false);
         }
     }
+    let result = authorizer.verify(topic, signature);
+    if let (Err(_), Some(key)) = (&result, idempotency_key) {
+        recent_commands.lock().unwrap().forget(key);
+    }
+    result.map(|_| true)
+}
 
-    fn send_schedule_create_command(
-        watering_command_tx: &Option<Sender<WateringConfigCommand>>,
-        publish: &Publish,
-    ) {
-        let schedule_config_result: Result<WateringScheduleConfig, ()> =
-            get_schedule_config_from_message(publish);
-        if let Ok(schedule_config) = schedule_config_result {
-            if let Some(tx) = watering_command_tx {
-                match tx
-                    .clone()
-                    .try_send(WateringConfigCommand::Create(schedule_config))
-                {
-                    Ok(_) => println!("watering create command send"),
-                    Err(e) => println!("error sending schedule enable command = {}", e),
-                }
-            }
-        }
+/// Only executed commands count, a failed one may be retried with the same key.
+fn forget_failed<T>(
+    recent_commands: &Mutex<RecentCommands>,
+    idempotency_key: &Option<String>,
+    outcome: &Result<T, Error>,
+) {
+    if let (Err(_), Some(key)) = (outcome, idempotency_key) {
+        recent_commands.lock().unwrap().forget(key);
     }
 }
 
-fn is_valve_open_topic(publish: &Publish) -> bool {
-    publish
-        .topic_name
-        .ends_with("/garden-butler/command/layout/open")
+fn send_schedule_switch(
+    watering_config_command_tx: &Option<Sender<WateringConfigRequest>>,
+    schedule_config: WateringScheduleConfig,
+) -> Result<PendingResponse, Error> {
+    let command = if schedule_config.is_enabled() {
+        WateringConfigCommand::Enable(schedule_config)
+    } else {
+        WateringConfigCommand::Disable(schedule_config)
+    };
+    send_request(watering_config_command_tx, command).map(PendingResponse::Schedule)
 }
 
-fn is_valve_close_topic(publish: &Publish) -> bool {
-    publish
-        .topic_name
-        .ends_with("/garden-butler/command/layout/close")
+fn send_request<C, R>(
+    command_tx: &Option<Sender<Request<C, R>>>,
+    command: C,
+) -> Result<oneshot::Receiver<R>, Error>
+where
+    C: std::fmt::Debug,
+{
+    communication::send_request(command_tx, command).map_err(Error::CommandDispatch)
 }
 
-fn is_schedule_enable_topic(publish: &Publish) -> bool {
-    publish
-        .topic_name
-        .ends_with("/garden-butler/command/watering-schedule/enable")
+fn subscribe_to_commands(mqtt_session: &Arc<Mutex<MqttSession>>) {
+    let mut session = mqtt_session.lock().unwrap();
+    let topic = session.topics().command_subscription();
+    let qos = session.config.get_qos(TopicClass::Commands);
+    info!("Subscribe to {}", topic);
+    session
+        .subscribe(topic, qos)
+        .map_err(|e| error!("error = {:?}", e))
+        .unwrap_or(());
+}
+
+impl Future for MqttCommandListener {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.poll_unpin(cx)
+    }
 }
 
-fn is_schedule_disable_topic(publish: &Publish) -> bool {
-    publish
-        .topic_name
-        .ends_with("/garden-butler/command/watering-schedule/disable")
+/// Commands are either sent as plain payload, e.g. `27`, or wrapped in an envelope to get a
+/// response published:
+/// `{"correlation_id": "42", "response_topic": "optional/topic", "payload": 27}`.
+/// Without a `response_topic` the response goes to the response topic of the `TopicScheme`,
+/// e.g. `{topic_prefix}/response/{correlation_id}`. A `response_topic` has to be below the
+/// response prefix of the scheme as well, e.g. `{topic_prefix}/response/kitchen-panel`.
+/// Envelopes that can not be parsed are answered with `INVALID_REQUEST` as long as their
+/// `correlation_id` can be read.
+/// A command is executed only once per `idempotency_key`, or per `correlation_id` if no key is
+/// given, so that a redelivery does not toggle a valve twice. A command that failed may be
+/// retried with the same key.
+/// If `command_auth` has a secret, commands have to be signed with `timestamp`, `nonce` and
+/// `signature`, see `CommandAuthorizer`.
+#[derive(Deserialize, Debug)]
+struct CommandEnvelope {
+    correlation_id: Option<String>,
+    idempotency_key: Option<String>,
+    response_topic: Option<String>,
+    timestamp: Option<i64>,
+    nonce: Option<String>,
+    signature: Option<String>,
+    /// Kept as sent, the signature covers it byte for byte.
+    payload: Box<RawValue>,
 }
 
-fn is_schedule_delete_topic(publish: &Publish) -> bool {
-    publish
-        .topic_name
-        .ends_with("/garden-butler/command/watering-schedule/delete")
+const RECENT_COMMANDS_CAPACITY: usize = 256;
+
+/// Idempotency keys of the last executed commands and of those still running.
+struct RecentCommands {
+    keys: VecDeque<String>,
+    capacity: usize,
 }
 
-fn is_schedule_create_topic(publish: &Publish) -> bool {
-    publish
-        .topic_name
-        .ends_with("/garden-butler/command/watering-schedule/create")
+impl RecentCommands {
+    fn new(capacity: usize) -> RecentCommands {
+        RecentCommands {
+            keys: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Remembers the key, true if it was already seen.
+    fn is_duplicate(&mut self, key: String) -> bool {
+        if self.keys.contains(&key) {
+            return true;
+        }
+        if self.keys.len() >= self.capacity {
+            self.keys.pop_front();
+        }
+        self.keys.push_back(key);
+        false
+    }
+
+    fn forget(&mut self, key: &str) {
+        self.keys.retain(|k| k != key);
+    }
 }
 
-fn subscribe_to_commands(
-    mqtt_session: &Arc<Mutex<MqttSession>>,
-    mqtt_config: &Arc<Mutex<MqttConfig>>,
-) {
-    let topic = format!(
-        "{}/garden-butler/command/#",
-        &mqtt_config.lock().unwrap().client_id
-    );
-    println!("Subscribe to {}", topic);
-    mqtt_session
-        .lock()
-        .unwrap()
-        .subscribe(topic, QoS::AtLeastOnce)
-        .map_err(|e| println!("error = {:?}", e))
-        .unwrap();
+struct ParsedMessage {
+    payload: serde_json::Value,
+    response_target: Option<ResponseTarget>,
+    idempotency_key: Option<String>,
+    signature: Option<CommandSignature>,
 }
 
-fn log_commands(n: &Result<Notification, crossbeam::TryRecvError>) {
-    match n {
-        Ok(r) => {
-            println!("{:?}", r);
+const ENVELOPE_FIELDS: [&str; 3] = ["correlation_id", "idempotency_key", "signature"];
+
+fn parse_message(message: &[u8], topics: &dyn TopicScheme) -> Result<ParsedMessage, Error> {
+    let payload_string =
+        std::str::from_utf8(message).map_err(|e| Error::InvalidPayload(e.to_string()))?;
+    // plain text payloads like `ON` are passed on as json string
+    let payload: serde_json::Value = serde_json::from_str(payload_string)
+        .unwrap_or_else(|_| serde_json::Value::String(payload_string.to_string()));
+    if ENVELOPE_FIELDS
+        .iter()
+        .all(|field| payload.get(field).is_none())
+    {
+        return Ok(ParsedMessage {
+            payload,
+            response_target: None,
+            idempotency_key: None,
+            signature: None,
+        });
+    }
+
+    let CommandEnvelope {
+        correlation_id,
+        idempotency_key,
+        response_topic,
+        timestamp,
+        nonce,
+        signature,
+        payload,
+    } = serde_json::from_str(payload_string).map_err(|e| Error::InvalidRequest(e.to_string()))?;
+    if let Some(topic) = &response_topic {
+        check_response_topic(topic, topics)?;
+    }
+    let signature = match (timestamp, nonce, signature) {
+        (Some(timestamp), Some(nonce), Some(signature)) => Some(CommandSignature {
+            timestamp,
+            nonce,
+            signature,
+            correlation_id: correlation_id.clone(),
+            idempotency_key: idempotency_key.clone(),
+            response_topic: response_topic.clone(),
+            payload: payload.get().to_string(),
+        }),
+        (None, None, None) => None,
+        _ => {
+            return Err(Error::Unauthorized(String::from(
+                "timestamp, nonce and signature are all required",
+            )))
         }
-        Err(_) => {}
+    };
+    let idempotency_key = idempotency_key.or_else(|| correlation_id.clone());
+    let response_target = correlation_id.map(|correlation_id| ResponseTarget {
+        topic: response_topic.unwrap_or_else(|| topics.response_topic(&correlation_id)),
+        correlation_id,
+    });
+    let payload =
+        serde_json::from_str(payload.get()).map_err(|e| Error::InvalidRequest(e.to_string()))?;
+    Ok(ParsedMessage {
+        payload,
+        response_target,
+        idempotency_key,
+        signature,
+    })
+}
+
+/// Anyone may publish commands, so responses must not end up on arbitrary, e.g. retained status,
+/// topics.
+fn check_response_topic(topic: &str, topics: &dyn TopicScheme) -> Result<(), Error> {
+    let prefix = topics.response_prefix();
+    match topic.strip_prefix(&prefix) {
+        Some(rest) if !rest.is_empty() && !rest.contains(&['+', '#'][..]) => Ok(()),
+        _ => Err(Error::InvalidRequest(format!(
+            "response_topic {} is not below {}",
+            topic, prefix
+        ))),
     }
 }
 
-impl Future for MqttCommandListener {
-    type Output = ();
+/// Where to report a message that could not be parsed. Always the default response topic, the
+/// requested one may be what is wrong.
+fn get_error_response_target(message: &[u8], topics: &dyn TopicScheme) -> Option<ResponseTarget> {
+    let payload: serde_json::Value = serde_json::from_slice(message).ok()?;
+    let correlation_id = payload.get("correlation_id")?.as_str()?.to_string();
+    Some(ResponseTarget {
+        topic: topics.response_topic(&correlation_id),
+        correlation_id,
+    })
+}
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.inner.poll_unpin(cx)
+fn get_valve_pin_num_from_payload(payload: &serde_json::Value) -> Result<ValvePinNumber, Error> {
+    let pin_num: Result<u8, Error> = match payload {
+        serde_json::Value::String(s) => {
+            u8::from_str(s.trim()).map_err(|e| Error::InvalidPayload(e.to_string()))
+        }
+        _ => serde_json::from_value(payload.clone())
+            .map_err(|e| Error::InvalidPayload(e.to_string())),
+    };
+    pin_num.map(ValvePinNumber)
+}
+
+/// Accepts ON/OFF, OPEN/CLOSE and the homie booleans `true`/`false`.
+fn get_switch_state_from_payload(payload: &serde_json::Value) -> Result<bool, Error> {
+    if let Some(state) = payload.as_bool() {
+        return Ok(state);
+    }
+    match payload.as_str().map(|s| s.trim().to_uppercase()) {
+        Some(ref s) if s == "ON" || s == "OPEN" => Ok(true),
+        Some(ref s) if s == "OFF" || s == "CLOSE" => Ok(false),
+        _ => Err(Error::InvalidPayload(format!(
+            "expected ON or OFF but got {}",
+            payload
+        ))),
     }
 }
 
-fn get_valve_pin_num_from_message(publish: &Publish) -> Result<ValvePinNumber, ()> {
-    std::str::from_utf8(publish.payload.deref())
-        .map_err(|_| ())
-        .and_then(|s| u8::from_str(s).map_err(|_| ()))
-        .map(ValvePinNumber)
+fn get_schedule_config_from_payload(
+    payload: &serde_json::Value,
+) -> Result<WateringScheduleConfig, Error> {
+    serde_json::from_value(payload.clone()).map_err(|e| Error::InvalidPayload(e.to_string()))
 }
 
-fn get_schedule_config_from_message(publish: &Publish) -> Result<WateringScheduleConfig, ()> {
-    let payload_string = std::str::from_utf8(publish.payload.deref());
-    payload_string
-        .map_err(|e| println!("{}", e))
-        .and_then(|json_str| serde_json::from_str(json_str).map_err(|e| println!("{}", e)))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::configuration::MqttConfig;
+    use crate::mqtt::topics::GardenButlerTopics;
+
+    const TOPIC: &str = "garden/valve/open";
+
+    fn create_topics() -> GardenButlerTopics {
+        let config: MqttConfig = serde_json::from_str(
+            r#"{"client_id": "garden", "broker_hostname": "localhost", "topic_prefix": "home/garden"}"#,
+        )
+        .unwrap();
+        GardenButlerTopics::new(&config)
+    }
+
+    fn parse(message: &str) -> Result<ParsedMessage, Error> {
+        parse_message(message.as_bytes(), &create_topics())
+    }
+
+    fn create_authorizer(command_auth: &str) -> CommandAuthorizer {
+        let config: MqttConfig = serde_json::from_str(&format!(
+            r#"{{"client_id": "garden", "broker_hostname": "localhost", "command_auth": {}}}"#,
+            command_auth
+        ))
+        .unwrap();
+        CommandAuthorizer::from_config(&config).unwrap()
+    }
+
+    fn key(key: &str) -> Option<String> {
+        Some(key.to_string())
+    }
+
+    fn is_remembered(recent_commands: &Mutex<RecentCommands>, key: &str) -> bool {
+        recent_commands
+            .lock()
+            .unwrap()
+            .keys
+            .iter()
+            .any(|k| k == key)
+    }
+
+    #[test]
+    fn redelivered_commands_are_dropped() {
+        let mut authorizer = create_authorizer("{}");
+        let recent_commands = Mutex::new(RecentCommands::new(RECENT_COMMANDS_CAPACITY));
+
+        let mut accept = |idempotency_key| {
+            authorize(
+                &mut authorizer,
+                &recent_commands,
+                TOPIC,
+                &idempotency_key,
+                &None,
+            )
+            .unwrap()
+        };
+        assert!(accept(key("a")));
+        assert!(!accept(key("a")));
+        assert!(accept(key("b")));
+        // commands without key can not be recognized
+        assert!(accept(None));
+        assert!(accept(None));
+    }
+
+    #[test]
+    fn keys_of_rejected_commands_are_forgotten() {
+        let mut authorizer = create_authorizer(r#"{"secret": "secret"}"#);
+        let recent_commands = Mutex::new(RecentCommands::new(RECENT_COMMANDS_CAPACITY));
+
+        assert!(matches!(
+            authorize(&mut authorizer, &recent_commands, TOPIC, &key("a"), &None),
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(!is_remembered(&recent_commands, "a"));
+    }
+
+    #[test]
+    fn keys_of_failed_commands_are_forgotten() {
+        let recent_commands = Mutex::new(RecentCommands::new(RECENT_COMMANDS_CAPACITY));
+        recent_commands
+            .lock()
+            .unwrap()
+            .is_duplicate(String::from("a"));
+        recent_commands
+            .lock()
+            .unwrap()
+            .is_duplicate(String::from("b"));
+
+        let failed: Result<(), Error> = Err(Error::CommandDispatch(String::from("closed")));
+        forget_failed(&recent_commands, &key("a"), &failed);
+        forget_failed(&recent_commands, &key("b"), &Ok(()));
+        assert!(!is_remembered(&recent_commands, "a"));
+        assert!(is_remembered(&recent_commands, "b"));
+    }
+
+    #[test]
+    fn only_the_latest_keys_are_remembered() {
+        let mut recent_commands = RecentCommands::new(2);
+
+        assert!(!recent_commands.is_duplicate(String::from("a")));
+        assert!(!recent_commands.is_duplicate(String::from("b")));
+        assert!(!recent_commands.is_duplicate(String::from("c")));
+        assert_eq!(recent_commands.keys.len(), 2);
+        assert!(recent_commands.is_duplicate(String::from("c")));
+        assert!(!recent_commands.is_duplicate(String::from("a")));
+        assert_eq!(recent_commands.keys.len(), 2);
+    }
+
+    #[test]
+    fn responses_stay_below_the_response_prefix() {
+        let topics = create_topics();
+
+        check_response_topic("home/garden/response/kitchen-panel", &topics).unwrap();
+        check_response_topic("home/garden/response/panel/42", &topics).unwrap();
+        for topic in &[
+            "home/garden/status/layout",
+            "home/garden/response",
+            "home/garden/response/",
+            "home/garden/response-evil/42",
+            "home/garden-evil/response/42",
+            "home/garden/response/#",
+            "home/garden/response/+/42",
+            "elsewhere/home/garden/response/42",
+        ] {
+            assert!(
+                matches!(
+                    check_response_topic(topic, &topics),
+                    Err(Error::InvalidRequest(_))
+                ),
+                "{} was accepted",
+                topic
+            );
+        }
+    }
+
+    #[test]
+    fn plain_payloads_are_passed_on() {
+        let message = parse("27").unwrap();
+        assert_eq!(message.payload, serde_json::json!(27));
+        assert!(message.response_target.is_none());
+        assert!(message.idempotency_key.is_none());
+        assert!(message.signature.is_none());
+
+        assert_eq!(parse("ON").unwrap().payload, serde_json::json!("ON"));
+        // json without envelope fields is a payload as well
+        assert_eq!(
+            parse(r#"{"valve": 27}"#).unwrap().payload,
+            serde_json::json!({"valve": 27})
+        );
+    }
+
+    #[test]
+    fn envelopes_are_answered_on_the_response_topic() {
+        let message = parse(r#"{"correlation_id": "42", "payload": 27}"#).unwrap();
+        assert_eq!(message.payload, serde_json::json!(27));
+        let target = message.response_target.unwrap();
+        assert_eq!(target.topic, "home/garden/response/42");
+        assert_eq!(target.correlation_id, "42");
+        assert_eq!(message.idempotency_key, key("42"));
+
+        let message = parse(
+            r#"{"correlation_id": "42", "idempotency_key": "k", "response_topic": "home/garden/response/panel", "payload": 27}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            message.response_target.unwrap().topic,
+            "home/garden/response/panel"
+        );
+        assert_eq!(message.idempotency_key, key("k"));
+    }
+
+    #[test]
+    fn envelopes_with_foreign_response_topics_are_rejected() {
+        let message = r#"{"correlation_id": "42", "response_topic": "home/garden/status/layout", "payload": 27}"#;
+
+        assert!(matches!(parse(message), Err(Error::InvalidRequest(_))));
+        // the error goes to the default response topic
+        assert_eq!(
+            get_error_response_target(message.as_bytes(), &create_topics())
+                .unwrap()
+                .topic,
+            "home/garden/response/42"
+        );
+    }
+
+    #[test]
+    fn broken_envelopes_are_rejected() {
+        assert!(matches!(
+            parse(r#"{"correlation_id": "42"}"#),
+            Err(Error::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            parse(r#"{"correlation_id": 42, "payload": 27}"#),
+            Err(Error::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            parse(r#"{"correlation_id": "42", "signature": "ab", "payload": 27}"#),
+            Err(Error::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn signatures_cover_the_payload_as_sent() {
+        let signature = parse(
+            r#"{"correlation_id": "42", "timestamp": 1600000000, "nonce": "n1", "signature": "ab", "payload": { "valve" : 27 }}"#,
+        )
+        .unwrap()
+        .signature
+        .unwrap();
+
+        assert_eq!(signature.payload, r#"{ "valve" : 27 }"#);
+        assert_eq!(signature.correlation_id, key("42"));
+        assert_eq!(signature.idempotency_key, None);
+    }
 }
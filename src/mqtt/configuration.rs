@@ -1,3 +1,8 @@
+use rumqtt::QoS;
+
+use crate::mqtt::topics::TopicClass;
+use crate::mqtt::Error;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MqttConfig {
     pub client_id: String,
@@ -5,22 +10,277 @@ pub struct MqttConfig {
     pub port: Option<u16>,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Files with the credentials, e.g. docker secrets. They take precedence over `username`
+    /// and `password`, which can also be set with `MQTT_USERNAME` and `MQTT_PASSWORD`.
+    pub username_file: Option<String>,
+    pub password_file: Option<String>,
+    pub tls_mode: Option<TlsMode>,
+    /// Root ca of the broker for `server-tls` and `mutual-tls`.
     pub cert_path: Option<String>,
+    /// Client certificate and key for `mutual-tls`.
+    pub client_cert_path: Option<String>,
+    pub client_key_path: Option<String>,
     pub status_publish_interval_secs: Option<u64>,
+    /// Upper bound of the delay between connection attempts, defaults to 300.
+    pub reconnect_max_backoff_secs: Option<u64>,
+    /// Messages kept while the broker is unreachable, defaults to 100.
+    pub offline_queue_size: Option<usize>,
+    pub home_assistant: Option<HomeAssistantConfig>,
+    pub topic_scheme: Option<TopicSchemeConfig>,
+    /// Base of all topics, e.g. `home/garden/{client_id}`. Defaults to
+    /// `{client_id}/garden-butler`, or `homie/{client_id}` for the homie scheme.
+    pub topic_prefix: Option<String>,
+    pub topic_policies: Option<TopicPolicies>,
+    pub command_auth: Option<CommandAuthConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsMode {
+    Plaintext,
+    ServerTls,
+    MutualTls,
+}
+
+/// QoS and retain flag per class of topics, e.g.
+/// `"topic_policies": {"layout_status": {"qos": "at-least-once", "retain": true}}`.
+/// The retain flag of `commands` is ignored, it only sets the QoS of the subscription.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopicPolicies {
+    pub health: Option<TopicPolicy>,
+    pub configuration: Option<TopicPolicy>,
+    pub layout_status: Option<TopicPolicy>,
+    pub schedule_status: Option<TopicPolicy>,
+    pub events: Option<TopicPolicy>,
+    pub responses: Option<TopicPolicy>,
+    pub commands: Option<TopicPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopicPolicy {
+    pub qos: Option<QosLevel>,
+    pub retain: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[allow(clippy::enum_variant_names)] // named like the mqtt levels
+pub enum QosLevel {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl QosLevel {
+    pub fn to_qos(self) -> QoS {
+        match self {
+            QosLevel::AtMostOnce => QoS::AtMostOnce,
+            QosLevel::AtLeastOnce => QoS::AtLeastOnce,
+            QosLevel::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+}
+
+/// Signing and allowlist of remote commands, see `CommandAuthorizer`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandAuthConfig {
+    /// Shared HMAC secret, commands have to be signed if set. The unsigned switches of home
+    /// assistant and homie stop working then.
+    pub secret: Option<String>,
+    pub secret_file: Option<String>,
+    pub max_clock_skew_secs: Option<u64>,
+    /// Commands accepted over mqtt, all if not set.
+    pub allowed_commands: Option<Vec<CommandKind>>,
 }
 
-impl Default for MqttConfig {
-    fn default() -> Self {
-        let mut settings = config::Config::default();
-        settings
-            .merge(config::File::new("mqtt", config::FileFormat::Json))
-            .unwrap()
-            .merge(config::Environment::with_prefix("MQTT"))
-            .unwrap();
-        let config = settings
-            .try_into::<MqttConfig>()
-            .expect("Mqtt config contains errors");
-        println!("{:?}", config);
+impl CommandAuthConfig {
+    pub fn get_secret(&self) -> Result<Option<String>, Error> {
+        read_secret(&self.secret_file, &self.secret)
+    }
+
+    pub fn get_max_clock_skew_secs(&self) -> u64 {
+        self.max_clock_skew_secs.unwrap_or(300)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommandKind {
+    ValveOpen,
+    ValveClose,
+    ValveSwitch,
+    ScheduleEnable,
+    ScheduleDisable,
+    ScheduleDelete,
+    ScheduleCreate,
+    ScheduleSwitch,
+}
+
+/// Convention for the mqtt topics, `garden-butler` when not configured.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TopicSchemeConfig {
+    GardenButler,
+    Homie,
+}
+
+impl MqttConfig {
+    pub fn get_tls_mode(&self) -> TlsMode {
+        self.tls_mode.unwrap_or(TlsMode::ServerTls)
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.port.unwrap_or(match self.get_tls_mode() {
+            TlsMode::Plaintext => 1883,
+            TlsMode::ServerTls | TlsMode::MutualTls => 8883,
+        })
+    }
+
+    pub fn get_reconnect_max_backoff_secs(&self) -> u64 {
+        self.reconnect_max_backoff_secs.unwrap_or(300)
+    }
+
+    pub fn get_offline_queue_size(&self) -> usize {
+        self.offline_queue_size.unwrap_or(100)
+    }
+
+    pub fn get_qos(&self, class: TopicClass) -> QoS {
+        self.get_topic_policy(class)
+            .and_then(|policy| policy.qos)
+            .map(QosLevel::to_qos)
+            .unwrap_or(match class {
+                TopicClass::Health => QoS::ExactlyOnce,
+                TopicClass::Configuration => QoS::AtLeastOnce,
+                TopicClass::LayoutStatus => QoS::AtMostOnce,
+                TopicClass::ScheduleStatus => QoS::ExactlyOnce,
+                TopicClass::Events => QoS::AtLeastOnce,
+                TopicClass::Responses => QoS::AtLeastOnce,
+                TopicClass::Commands => QoS::AtLeastOnce,
+            })
+    }
+
+    /// The configured QoS of `health`, the last will defaults to at least once unlike the other
+    /// health messages.
+    pub fn get_last_will_qos(&self) -> QoS {
+        self.get_topic_policy(TopicClass::Health)
+            .and_then(|policy| policy.qos)
+            .map_or(QoS::AtLeastOnce, QosLevel::to_qos)
+    }
+
+    /// The configured retain flag of the class, or the default of the message.
+    pub fn get_retain(&self, class: TopicClass, default: bool) -> bool {
+        self.get_topic_policy(class)
+            .and_then(|policy| policy.retain)
+            .unwrap_or(default)
+    }
+
+    fn get_topic_policy(&self, class: TopicClass) -> Option<&TopicPolicy> {
+        let policies = self.topic_policies.as_ref()?;
+        match class {
+            TopicClass::Health => policies.health.as_ref(),
+            TopicClass::Configuration => policies.configuration.as_ref(),
+            TopicClass::LayoutStatus => policies.layout_status.as_ref(),
+            TopicClass::ScheduleStatus => policies.schedule_status.as_ref(),
+            TopicClass::Events => policies.events.as_ref(),
+            TopicClass::Responses => policies.responses.as_ref(),
+            TopicClass::Commands => policies.commands.as_ref(),
+        }
+    }
+
+    pub fn get_ca_path(&self) -> String {
+        self.cert_path
+            .clone()
+            .unwrap_or_else(|| "/app/root-ca.crt".to_string())
+    }
+
+    pub fn get_username(&self) -> Result<Option<String>, Error> {
+        read_secret(&self.username_file, &self.username)
+    }
+
+    pub fn get_password(&self) -> Result<Option<String>, Error> {
+        read_secret(&self.password_file, &self.password)
+    }
+
+    /// The configured topic prefix with `{client_id}` replaced.
+    pub fn get_topic_prefix(&self, default: &str) -> String {
+        self.topic_prefix
+            .as_deref()
+            .unwrap_or(default)
+            .trim_end_matches('/')
+            .replace("{client_id}", &self.client_id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HomeAssistantConfig {
+    pub discovery_prefix: Option<String>,
+}
+
+impl HomeAssistantConfig {
+    pub fn get_discovery_prefix(&self) -> String {
+        self.discovery_prefix
+            .clone()
+            .unwrap_or_else(|| "homeassistant".to_string())
+    }
+}
+
+fn read_secret(file: &Option<String>, value: &Option<String>) -> Result<Option<String>, Error> {
+    match file {
+        Some(path) => std::fs::read_to_string(path)
+            .map(|secret| Some(secret.trim_end().to_string()))
+            .map_err(|e| Error::Configuration(format!("could not read {} = {}", path, e))),
+        None => Ok(value.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mqtt_config(topic_prefix: Option<&str>) -> MqttConfig {
+        let mut config: MqttConfig =
+            serde_json::from_str(r#"{"client_id": "garden", "broker_hostname": "localhost"}"#)
+                .unwrap();
+        config.topic_prefix = topic_prefix.map(String::from);
         config
     }
+
+    #[test]
+    fn topic_prefixes_default_per_scheme() {
+        let config = mqtt_config(None);
+
+        assert_eq!(
+            config.get_topic_prefix("{client_id}/garden-butler"),
+            "garden/garden-butler"
+        );
+        assert_eq!(config.get_topic_prefix("homie/{client_id}"), "homie/garden");
+    }
+
+    #[test]
+    fn client_ids_are_put_into_the_topic_prefix() {
+        assert_eq!(
+            mqtt_config(Some("home/{client_id}/watering")).get_topic_prefix("homie/{client_id}"),
+            "home/garden/watering"
+        );
+        assert_eq!(
+            mqtt_config(Some("home/{client_id}-{client_id}")).get_topic_prefix(""),
+            "home/garden-garden"
+        );
+        assert_eq!(
+            mqtt_config(Some("home/garden")).get_topic_prefix("homie/{client_id}"),
+            "home/garden"
+        );
+    }
+
+    #[test]
+    fn trailing_slashes_are_trimmed_from_the_topic_prefix() {
+        assert_eq!(
+            mqtt_config(Some("home/garden/")).get_topic_prefix(""),
+            "home/garden"
+        );
+        assert_eq!(
+            mqtt_config(Some("home/{client_id}//")).get_topic_prefix(""),
+            "home/garden"
+        );
+    }
 }
@@ -0,0 +1,155 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rumqtt::{MqttClient, Notification, Publish};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::communication::event::{HealthStatus, StatusEvent};
+use crate::mqtt::{ConnectionState, Error, MqttSession};
+
+const INITIAL_BACKOFF_SECS: u64 = 1;
+/// A connection that lasted this long was not dropped right away by the broker.
+const STABLE_CONNECTION_SECS: u64 = 60;
+
+/// Event loop of the mqtt connection. Nothing else waits for the broker, so watering, buttons and
+/// the master valve keep working while it is unreachable.
+pub struct MqttConnection {}
+
+impl MqttConnection {
+    /// Connects, hands incoming publishes to `publish_tx` as they arrive and reconnects whenever
+    /// the connection was lost, doubling the delay after every failed attempt or short-lived
+    /// connection up to `reconnect_max_backoff_secs`. The delay starts over once a connection was
+    /// stable.
+    pub async fn run(
+        mqtt_session: Arc<Mutex<MqttSession>>,
+        mut publish_tx: Option<mpsc::Sender<Publish>>,
+        mut reconnect_tx: Vec<mpsc::Sender<()>>,
+        status_event_tx: broadcast::Sender<StatusEvent>,
+    ) {
+        let max_backoff = mqtt_session
+            .lock()
+            .unwrap()
+            .config
+            .get_reconnect_max_backoff_secs();
+        let mut backoff = INITIAL_BACKOFF_SECS;
+        loop {
+            mqtt_session.lock().unwrap().connecting();
+            report_health(&mqtt_session, &status_event_tx);
+            let connection = MqttConnection::connect(&mqtt_session).await;
+            report_health(&mqtt_session, &status_event_tx);
+            let mut notifications = match connection {
+                Ok(notifications) => notifications,
+                Err(e) => {
+                    warn!("{}, retrying in {}s", e, backoff);
+                    tokio::time::delay_for(Duration::from_secs(backoff)).await;
+                    backoff = (backoff * 2).min(max_backoff);
+                    continue;
+                }
+            };
+            info!("connected to mqtt broker");
+            let connected_at = Instant::now();
+            signal_reconnect(&mut reconnect_tx);
+
+            while mqtt_session.lock().unwrap().get_connection_state() == ConnectionState::Connected
+            {
+                match notifications.recv().await {
+                    Some(Notification::Publish(publish)) => {
+                        debug!("{:?}", publish);
+                        if let Some(tx) = &mut publish_tx {
+                            let _ = tx
+                                .try_send(publish)
+                                .map_err(|e| error!("error forwarding mqtt command = {}", e));
+                        }
+                    }
+                    Some(Notification::Reconnection) => {
+                        info!("mqtt connection reestablished");
+                        mqtt_session.lock().unwrap().reconnected();
+                        report_health(&mqtt_session, &status_event_tx);
+                        signal_reconnect(&mut reconnect_tx);
+                    }
+                    Some(Notification::Disconnection) | None => {
+                        warn!("mqtt connection lost");
+                        mqtt_session.lock().unwrap().disconnected();
+                        report_health(&mqtt_session, &status_event_tx);
+                    }
+                    Some(other) => debug!("{:?}", other),
+                }
+            }
+
+            // a broker that accepts and drops the connection right away gets the same backoff
+            if connected_at.elapsed() >= Duration::from_secs(STABLE_CONNECTION_SECS) {
+                backoff = INITIAL_BACKOFF_SECS;
+            }
+            info!("reconnecting in {}s", backoff);
+            tokio::time::delay_for(Duration::from_secs(backoff)).await;
+            backoff = (backoff * 2).min(max_backoff);
+        }
+    }
+
+    /// Starting the client blocks until the broker answered and its notifications arrive on a
+    /// blocking channel, both are kept off the runtime in a thread of their own. The session is
+    /// either connected or disconnected afterwards.
+    async fn connect(
+        mqtt_session: &Arc<Mutex<MqttSession>>,
+    ) -> Result<mpsc::UnboundedReceiver<Notification>, Error> {
+        let result = MqttConnection::start_client(mqtt_session).await;
+        let mut session = mqtt_session.lock().unwrap();
+        match result {
+            Ok((client, notifications)) => {
+                session.connected(client);
+                Ok(notifications)
+            }
+            Err(e) => {
+                session.disconnected();
+                Err(e)
+            }
+        }
+    }
+
+    async fn start_client(
+        mqtt_session: &Arc<Mutex<MqttSession>>,
+    ) -> Result<(MqttClient, mpsc::UnboundedReceiver<Notification>), Error> {
+        let mqtt_options = mqtt_session.lock().unwrap().create_mqtt_options()?;
+
+        let (client_sender, client_receiver) = oneshot::channel();
+        let (notification_sender, notification_receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || match MqttClient::start(mqtt_options) {
+            Ok((client, notifications)) => {
+                if client_sender.send(Ok(client)).is_err() {
+                    return;
+                }
+                for notification in notifications.iter() {
+                    if notification_sender.send(notification).is_err() {
+                        return;
+                    }
+                }
+                // the client is gone, the receiving side sees the closed channel
+            }
+            Err(e) => {
+                let _ = client_sender.send(Err(Error::Connection(format!("{:?}", e))));
+            }
+        });
+        let client = client_receiver
+            .await
+            .map_err(|e| Error::Connection(e.to_string()))??;
+        Ok((client, notification_receiver))
+    }
+}
+
+/// Live status subscribers learn about every change of the connection state.
+fn report_health(
+    mqtt_session: &Arc<Mutex<MqttSession>>,
+    status_event_tx: &broadcast::Sender<StatusEvent>,
+) {
+    let state = mqtt_session.lock().unwrap().get_connection_state();
+    // fails only without live status subscribers
+    let _ = status_event_tx.send(StatusEvent::Health(HealthStatus::new(state)));
+}
+
+fn signal_reconnect(reconnect_tx: &mut [mpsc::Sender<()>]) {
+    for tx in reconnect_tx {
+        let _ = tx
+            .try_send(())
+            .map_err(|e| error!("error sending reconnect signal = {}", e));
+    }
+}
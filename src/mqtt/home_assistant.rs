@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use serde_json::json;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::communication::event::StatusEvent;
+use crate::embedded::configuration::LayoutConfig;
+use crate::embedded::ValvePinNumber;
+use crate::mqtt::configuration::MqttConfig;
+use crate::mqtt::topics::{
+    home_assistant_discovery_topic, GardenButlerTopics, Message, TopicClass,
+};
+use crate::mqtt::MqttSession;
+use crate::schedule::{WateringScheduleConfig, WateringScheduleConfigs};
+
+/// Publishes retained Home Assistant MQTT discovery configs so that valves, schedules and the
+/// pump show up as entities without hand written yaml.
+pub struct HomeAssistantDiscovery {}
+
+impl HomeAssistantDiscovery {
+    /// Publishes once and again every time the mqtt connection was reestablished. Schedule
+    /// updates publish the configs that changed, entities that are gone get their retained
+    /// config cleared.
+    pub async fn report(
+        layout_config: Arc<Mutex<LayoutConfig>>,
+        watering_schedule_configs: Arc<Mutex<WateringScheduleConfigs>>,
+        mqtt_session: Arc<Mutex<MqttSession>>,
+        mqtt_config: Arc<Mutex<MqttConfig>>,
+        reconnect_rx: mpsc::Receiver<()>,
+        status_event_rx: broadcast::Receiver<StatusEvent>,
+    ) {
+        let start_or_reconnect = stream::once(future::ready(()))
+            .chain(reconnect_rx)
+            .map(|_| true);
+        let schedule_updates = status_event_rx
+            .into_stream()
+            .filter_map(|event| {
+                future::ready(match event {
+                    Ok(StatusEvent::Schedules(_)) => Some(false),
+                    Ok(_) => None,
+                    // missed events may have been schedule updates
+                    Err(_) => Some(false),
+                })
+            })
+            .boxed();
+        let mut triggers = stream::select(start_or_reconnect, schedule_updates);
+        let mut published: HashMap<String, serde_json::Value> = HashMap::new();
+        while let Some(publish_all) = triggers.next().await {
+            let discovery_prefix = match &mqtt_config.lock().unwrap().home_assistant {
+                Some(home_assistant) => home_assistant.get_discovery_prefix(),
+                None => return,
+            };
+            let client_id = mqtt_config.lock().unwrap().client_id.clone();
+            let topics = GardenButlerTopics::new(&mqtt_config.lock().unwrap());
+            let discovery: HashMap<String, serde_json::Value> =
+                HomeAssistantDiscovery::create_configs(
+                    &discovery_prefix,
+                    &client_id,
+                    &topics,
+                    &layout_config.lock().unwrap(),
+                    &watering_schedule_configs.lock().unwrap(),
+                )
+                .into_iter()
+                .collect();
+
+            let mut messages: Vec<Message> = discovery
+                .iter()
+                .filter(|(topic, config)| publish_all || published.get(*topic) != Some(config))
+                .map(|(topic, config)| Message::retained(topic.clone(), config.to_string()))
+                .collect();
+            // an empty retained config removes the entity from home assistant
+            messages.extend(
+                published
+                    .keys()
+                    .filter(|topic| !discovery.contains_key(*topic))
+                    .map(|topic| Message::retained(topic.clone(), "")),
+            );
+            if messages.is_empty() {
+                continue;
+            }
+            mqtt_session
+                .lock()
+                .unwrap()
+                .publish_all(messages, TopicClass::Configuration)
+                .map_err(|e| error!("error = {:?}", e))
+                .unwrap_or_default();
+            published = discovery;
+            info!("home assistant discovery published");
+        }
+    }
+
+    fn create_configs(
+        discovery_prefix: &str,
+        client_id: &str,
+        topics: &GardenButlerTopics,
+        layout_config: &LayoutConfig,
+        watering_schedule_configs: &WateringScheduleConfigs,
+    ) -> Vec<(String, serde_json::Value)> {
+        let device = json!({
+            "identifiers": [client_id],
+            "name": format!("Garden Butler {}", client_id),
+            "manufacturer": "kaojo",
+            "model": "garden-butler",
+        });
+        let availability_topic = topics.health_topic();
+        let layout_topic = topics.layout_status_topic();
+        let schedule_topic = topics.schedule_status_topic();
+
+        let mut configs = Vec::new();
+        for valve in layout_config.get_valves() {
+            let pin = valve.get_valve_pin_num();
+            let object_id = format!("valve_{}", pin);
+            configs.push((
+                home_assistant_discovery_topic(discovery_prefix, "switch", client_id, &object_id),
+                json!({
+                    "name": format!("Valve {}", pin),
+                    "unique_id": format!("{}_{}", client_id, object_id),
+                    "device": device,
+                    "availability_topic": availability_topic,
+                    "payload_available": "ONLINE",
+                    "payload_not_available": "OFFLINE",
+                    "command_topic": topics.valve_set_topic(ValvePinNumber(pin)),
+                    "payload_on": "ON",
+                    "payload_off": "OFF",
+                    "state_topic": layout_topic,
+                    "value_template": format!(
+                        "{{% for v in value_json.valves %}}{{% if v.valve_pin_number == {} %}}\
+                         {{{{ 'ON' if v.status == 'OPEN' else 'OFF' }}}}{{% endif %}}{{% endfor %}}",
+                        pin
+                    ),
+                    "icon": "mdi:water",
+                }),
+            ));
+        }
+
+        if layout_config.get_pump().is_some() {
+            configs.push((
+                home_assistant_discovery_topic(
+                    discovery_prefix,
+                    "binary_sensor",
+                    client_id,
+                    "pump",
+                ),
+                json!({
+                    "name": "Pump",
+                    "unique_id": format!("{}_pump", client_id),
+                    "device": device,
+                    "availability_topic": availability_topic,
+                    "payload_available": "ONLINE",
+                    "payload_not_available": "OFFLINE",
+                    "state_topic": layout_topic,
+                    "value_template": "{{ value_json.pump }}",
+                    "payload_on": "ON",
+                    "payload_off": "OFF",
+                    "device_class": "running",
+                }),
+            ));
+        }
+
+        for schedule in watering_schedule_configs.get_schedules() {
+            let object_id = schedule_object_id(schedule);
+            let s = schedule.get_schedule();
+            configs.push((
+                home_assistant_discovery_topic(discovery_prefix, "switch", client_id, &object_id),
+                json!({
+                    "name": format!("Schedule {}", schedule),
+                    "unique_id": format!("{}_{}", client_id, object_id),
+                    "device": device,
+                    "availability_topic": availability_topic,
+                    "payload_available": "ONLINE",
+                    "payload_not_available": "OFFLINE",
+                    "command_topic": topics.schedule_set_topic(),
+                    "payload_on": schedule_payload(schedule, true),
+                    "payload_off": schedule_payload(schedule, false),
+                    "state_topic": schedule_topic,
+                    "value_template": format!(
+                        "{{% for s in value_json.schedules %}}{{% if s.valve == {} \
+                         and s.schedule.start_hour == {} and s.schedule.start_minute == {} \
+                         and s.schedule.end_hour == {} and s.schedule.end_minute == {} %}}\
+                         {{{{ 'ON' if s.enabled else 'OFF' }}}}{{% endif %}}{{% endfor %}}",
+                        schedule.get_valve(),
+                        s.get_start_hour(),
+                        s.get_start_minute(),
+                        s.get_end_hour(),
+                        s.get_end_minute()
+                    ),
+                    "icon": "mdi:calendar-clock",
+                }),
+            ));
+        }
+        configs
+    }
+}
+
+fn schedule_object_id(schedule: &WateringScheduleConfig) -> String {
+    let s = schedule.get_schedule();
+    format!(
+        "schedule_{}_{:02}{:02}_{:02}{:02}",
+        schedule.get_valve(),
+        s.get_start_hour(),
+        s.get_start_minute(),
+        s.get_end_hour(),
+        s.get_end_minute()
+    )
+}
+
+fn schedule_payload(schedule: &WateringScheduleConfig, enabled: bool) -> String {
+    let mut schedule = *schedule;
+    schedule.enabled = enabled;
+    serde_json::to_string(&schedule).unwrap()
+}
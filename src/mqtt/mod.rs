@@ -1,101 +1,323 @@
+use core::fmt;
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::Read;
 use std::sync::{Arc, Mutex};
 
-use rumqtt::{
-    ClientError, LastWill, MqttClient, MqttOptions, Notification, QoS, Receiver, SecurityOptions,
-};
+use rumqtt::{LastWill, MqttClient, MqttOptions, QoS, ReconnectOptions, SecurityOptions};
 
-use crate::mqtt::configuration::MqttConfig;
+use crate::embedded::configuration::LayoutConfig;
+use crate::mqtt::configuration::{MqttConfig, TlsMode};
+use crate::mqtt::topics::{Message, TopicClass, TopicScheme};
 
+pub mod auth;
 pub mod command;
 pub mod configuration;
+pub mod connection;
+pub mod home_assistant;
+pub mod response;
 pub mod status;
+pub mod topics;
 
+#[derive(Debug)]
+pub enum Error {
+    UnknownCommand(String),
+    InvalidPayload(String),
+    InvalidRequest(String),
+    CommandDispatch(String),
+    Configuration(String),
+    Connection(String),
+    NotConnected,
+    Unauthorized(String),
+    Forbidden(String),
+    Layout(crate::embedded::Error),
+    Schedule(crate::schedule::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownCommand(ref s) => write!(f, "Unknown command topic: {}", s),
+            Error::InvalidPayload(ref s) => write!(f, "Invalid payload: {}", s),
+            Error::InvalidRequest(ref s) => write!(f, "Invalid request: {}", s),
+            Error::CommandDispatch(ref s) => write!(f, "Command could not be dispatched: {}", s),
+            Error::Configuration(ref s) => write!(f, "Invalid mqtt configuration: {}", s),
+            Error::Connection(ref s) => write!(f, "Mqtt connection error: {}", s),
+            Error::NotConnected => write!(f, "Not connected to the mqtt broker"),
+            Error::Unauthorized(ref s) => write!(f, "Command rejected: {}", s),
+            Error::Forbidden(ref s) => write!(f, "Command forbidden: {}", s),
+            Error::Layout(ref e) => write!(f, "{}", e),
+            Error::Schedule(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error {
+    /// Stable identifier of the error kind for remote callers.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::UnknownCommand(_) => "UNKNOWN_COMMAND",
+            Error::InvalidPayload(_) => "INVALID_PAYLOAD",
+            Error::InvalidRequest(_) => "INVALID_REQUEST",
+            Error::CommandDispatch(_) => "COMMAND_DISPATCH",
+            Error::Configuration(_) => "CONFIGURATION",
+            Error::Connection(_) => "CONNECTION",
+            Error::NotConnected => "NOT_CONNECTED",
+            Error::Unauthorized(_) => "UNAUTHORIZED",
+            Error::Forbidden(_) => "FORBIDDEN",
+            Error::Layout(ref e) => e.code(),
+            Error::Schedule(ref e) => e.code(),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<crate::embedded::Error> for Error {
+    fn from(e: crate::embedded::Error) -> Error {
+        Error::Layout(e)
+    }
+}
+
+impl From<crate::schedule::Error> for Error {
+    fn from(e: crate::schedule::Error) -> Error {
+        Error::Schedule(e)
+    }
+}
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+/// Connection to the broker. Starts disconnected, `MqttConnection` connects it in the background.
+/// While offline, published messages are queued up to `offline_queue_size` and subscriptions are
+/// remembered, both are sent once the connection is established.
 pub struct MqttSession {
-    pub client: MqttClient,
-    pub receiver: Receiver<Notification>,
+    client: Option<MqttClient>,
+    state: ConnectionState,
     pub config: MqttConfig,
+    topics: Arc<dyn TopicScheme>,
+    subscriptions: Vec<(String, QoS)>,
+    pending: VecDeque<PendingMessage>,
 }
 
-impl MqttSession {
-    pub fn from_config(config: MqttConfig) -> Arc<Mutex<MqttSession>> {
-        let config_clone = config.clone();
-
-        let mqtt_options = MqttSession::create_mqtt_options(config_clone);
+struct PendingMessage {
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    payload: Vec<u8>,
+}
 
-        let (client, receiver) = MqttClient::start(mqtt_options).unwrap();
+impl MqttSession {
+    pub fn from_config(
+        config: MqttConfig,
+        layout_config: &LayoutConfig,
+    ) -> Arc<Mutex<MqttSession>> {
+        let topics = topics::create_topic_scheme(&config, layout_config);
 
         Arc::new(Mutex::new(MqttSession {
-            client,
-            receiver,
+            client: None,
+            state: ConnectionState::Disconnected,
             config,
+            topics,
+            subscriptions: Vec::new(),
+            pending: VecDeque::new(),
         }))
     }
 
-    fn create_mqtt_options(config_clone: MqttConfig) -> MqttOptions {
-        let cert_path = config_clone
-            .cert_path
-            .unwrap_or_else(|| "/app/root-ca.crt".to_string());
-        let cert = MqttSession::read_cert(cert_path);
-        MqttOptions::new(
+    pub fn create_mqtt_options(&self) -> Result<MqttOptions, Error> {
+        MqttSession::create_options(self.config.clone(), self.topics.last_will())
+    }
+
+    pub fn connecting(&mut self) {
+        self.state = ConnectionState::Connecting;
+    }
+
+    /// Takes over a freshly started client, restores the subscriptions and sends everything that
+    /// was queued while offline.
+    pub fn connected(&mut self, client: MqttClient) {
+        self.client = Some(client);
+        self.reconnected();
+    }
+
+    /// The client reestablished its connection, the broker lost our session.
+    pub fn reconnected(&mut self) {
+        self.state = ConnectionState::Connected;
+
+        let messages = self.topics.online();
+        let _ = self
+            .publish_all(messages, TopicClass::Health)
+            .map_err(|e| error!("error = {}", e));
+        for (topic, qos) in self.subscriptions.clone() {
+            let _ = self
+                .subscribe(topic, qos)
+                .map_err(|e| error!("error = {}", e));
+        }
+        let pending: Vec<PendingMessage> = self.pending.drain(..).collect();
+        info!("sending {} messages queued while offline", pending.len());
+        for message in pending {
+            let _ = self
+                .publish(message.topic, message.qos, message.retain, message.payload)
+                .map_err(|e| error!("error = {}", e));
+        }
+    }
+
+    pub fn disconnected(&mut self) {
+        self.client = None;
+        self.state = ConnectionState::Disconnected;
+    }
+
+    pub fn get_connection_state(&self) -> ConnectionState {
+        self.state
+    }
+
+    fn create_options(config_clone: MqttConfig, last_will: Message) -> Result<MqttOptions, Error> {
+        let security_opts = match (config_clone.get_username()?, config_clone.get_password()?) {
+            (None, None) => SecurityOptions::None,
+            (username, password) => SecurityOptions::UsernamePassword(
+                username.unwrap_or_else(|| "".to_string()),
+                password.unwrap_or_else(|| "".to_string()),
+            ),
+        };
+        let mut mqtt_options = MqttOptions::new(
             config_clone.client_id.clone(),
-            config_clone.broker_hostname,
-            config_clone.port.unwrap_or(8883),
+            config_clone.broker_hostname.clone(),
+            config_clone.get_port(),
         )
-        .set_security_opts(SecurityOptions::UsernamePassword(
-            config_clone.username.unwrap_or_else(|| "".to_string()),
-            config_clone.password.unwrap_or_else(|| "".to_string()),
-        ))
-        .set_ca(cert)
-        .set_last_will(device_offline_last_will(config_clone.client_id))
+        .set_security_opts(security_opts)
+        .set_last_will(LastWill {
+            topic: last_will.topic,
+            message: last_will.payload,
+            qos: config_clone.get_last_will_qos(),
+            retain: config_clone.get_retain(TopicClass::Health, last_will.retain),
+        })
         .set_clean_session(true)
         .set_connection_timeout(60)
+        // reconnects are done by `MqttConnection` with a backoff
+        .set_reconnect_opts(ReconnectOptions::Never);
+
+        match config_clone.get_tls_mode() {
+            TlsMode::Plaintext => {}
+            TlsMode::ServerTls => {
+                mqtt_options = mqtt_options.set_ca(read_file(&config_clone.get_ca_path())?);
+            }
+            TlsMode::MutualTls => {
+                let client_cert =
+                    read_required_file(&config_clone.client_cert_path, "client_cert_path")?;
+                let client_key =
+                    read_required_file(&config_clone.client_key_path, "client_key_path")?;
+                mqtt_options = mqtt_options
+                    .set_ca(read_file(&config_clone.get_ca_path())?)
+                    .set_client_auth(client_cert, client_key);
+            }
+        }
+        Ok(mqtt_options)
+    }
+
+    pub fn topics(&self) -> Arc<dyn TopicScheme> {
+        Arc::clone(&self.topics)
     }
 
-    fn read_cert(cert_path: String) -> Vec<u8> {
-        let mut cert_file =
-            File::open(cert_path).expect("Could not open root ca for mqtt connection.");
-        let mut cert = Vec::new();
-        // read the whole file
-        cert_file
-            .read_to_end(&mut cert)
-            .expect("Could not read cert file");
-        cert
+    /// Topics that depend on the layout, e.g. the homie nodes, follow a reloaded layout.
+    pub fn set_layout(&mut self, layout_config: &LayoutConfig) {
+        self.topics = topics::create_topic_scheme(&self.config, layout_config);
     }
 
-    pub fn get_client_id(&self) -> &str {
-        &self.config.client_id
+    /// Publishes the messages with the QoS and retain policy of their class and returns the last
+    /// error.
+    pub fn publish_all(&mut self, messages: Vec<Message>, class: TopicClass) -> Result<(), Error> {
+        let qos = self.config.get_qos(class);
+        let mut result = Ok(());
+        for message in messages {
+            let retain = self.config.get_retain(class, message.retain);
+            if let Err(e) = self.publish(message.topic, qos, retain, message.payload) {
+                result = Err(e);
+            }
+        }
+        result
     }
 
-    pub fn publish<S, V, B>(
-        &mut self,
-        topic: S,
-        qos: QoS,
-        retained: B,
-        payload: V,
-    ) -> Result<(), ClientError>
+    fn publish<S, V, B>(&mut self, topic: S, qos: QoS, retained: B, payload: V) -> Result<(), Error>
     where
         S: Into<String>,
         V: Into<Vec<u8>>,
         B: Into<bool>,
     {
-        self.client.publish(topic, qos, retained, payload)
+        let message = PendingMessage {
+            topic: topic.into(),
+            qos,
+            retain: retained.into(),
+            payload: payload.into(),
+        };
+        let result = match &mut self.client {
+            Some(client) => client
+                .publish(
+                    message.topic.clone(),
+                    message.qos,
+                    message.retain,
+                    message.payload.clone(),
+                )
+                .map_err(|e| Error::Connection(e.to_string())),
+            None => Err(Error::NotConnected),
+        };
+        if result.is_err() {
+            self.enqueue(message);
+        }
+        result
+    }
+
+    /// Keeps the newest messages, a retained message replaces an older one on the same topic.
+    fn enqueue(&mut self, message: PendingMessage) {
+        if message.retain {
+            self.pending
+                .retain(|pending| !(pending.retain && pending.topic == message.topic));
+        }
+        if self.pending.len() >= self.config.get_offline_queue_size() {
+            self.pending.pop_front();
+        }
+        if self.config.get_offline_queue_size() > 0 {
+            self.pending.push_back(message);
+        }
     }
 
-    pub fn subscribe<S>(&mut self, topic: S, qos: QoS) -> Result<(), ClientError>
+    /// Remembered for later connections, sent right away when connected.
+    pub fn subscribe<S>(&mut self, topic: S, qos: QoS) -> Result<(), Error>
     where
         S: Into<String>,
     {
-        self.client.subscribe(topic, qos)
+        let topic = topic.into();
+        if !self.subscriptions.iter().any(|(t, _)| *t == topic) {
+            self.subscriptions.push((topic.clone(), qos));
+        }
+        match &mut self.client {
+            Some(client) => client
+                .subscribe(topic, qos)
+                .map_err(|e| Error::Connection(e.to_string())),
+            None => Ok(()),
+        }
     }
 }
 
-fn device_offline_last_will(client_id: String) -> LastWill {
-    LastWill {
-        topic: format!("{}/garden-butler/status/health", client_id),
-        message: String::from("OFFLINE"),
-        qos: QoS::AtLeastOnce,
-        retain: true,
+fn read_file(path: &str) -> Result<Vec<u8>, Error> {
+    let mut file = File::open(path)
+        .map_err(|e| Error::Configuration(format!("could not open {} = {}", path, e)))?;
+    let mut content = Vec::new();
+    // read the whole file
+    file.read_to_end(&mut content)
+        .map_err(|e| Error::Configuration(format!("could not read {} = {}", path, e)))?;
+    Ok(content)
+}
+
+fn read_required_file(path: &Option<String>, name: &str) -> Result<Vec<u8>, Error> {
+    match path {
+        Some(path) => read_file(path),
+        None => Err(Error::Configuration(format!(
+            "{} is required for mutual-tls",
+            name
+        ))),
     }
 }
@@ -0,0 +1,89 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::embedded::LayoutStatus;
+use crate::mqtt::topics::{Message, TopicClass};
+use crate::mqtt::{Error, MqttSession};
+use crate::schedule::WateringScheduleConfigs;
+
+/// Where the outcome of a command is published. Taken from the command envelope.
+#[derive(Debug, Clone)]
+pub struct ResponseTarget {
+    pub topic: String,
+    pub correlation_id: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CommandResponse {
+    correlation_id: String,
+    success: bool,
+    error_code: Option<String>,
+    error: Option<String>,
+    state: Option<serde_json::Value>,
+}
+
+impl CommandResponse {
+    pub fn new(correlation_id: String, outcome: Result<serde_json::Value, Error>) -> Self {
+        match outcome {
+            Ok(state) => CommandResponse {
+                correlation_id,
+                success: true,
+                error_code: None,
+                error: None,
+                state: Some(state),
+            },
+            Err(e) => CommandResponse {
+                correlation_id,
+                success: false,
+                error_code: Some(e.code().to_string()),
+                error: Some(e.to_string()),
+                state: None,
+            },
+        }
+    }
+}
+
+/// Outcome of a dispatched command that is still being executed by its listener.
+pub enum PendingResponse {
+    Layout(oneshot::Receiver<Result<LayoutStatus, crate::embedded::Error>>),
+    Schedule(oneshot::Receiver<Result<WateringScheduleConfigs, crate::schedule::Error>>),
+}
+
+impl PendingResponse {
+    pub async fn outcome(self) -> Result<serde_json::Value, Error> {
+        match self {
+            PendingResponse::Layout(receiver) => {
+                let status = receiver.await.map_err(|_| command_dropped())??;
+                Ok(serde_json::to_value(&status).unwrap())
+            }
+            PendingResponse::Schedule(receiver) => {
+                let schedules = receiver.await.map_err(|_| command_dropped())??;
+                Ok(serde_json::to_value(&schedules).unwrap())
+            }
+        }
+    }
+}
+
+fn command_dropped() -> Error {
+    Error::CommandDispatch(String::from("command was dropped before it was executed"))
+}
+
+pub fn publish_response(
+    mqtt_session: &Arc<Mutex<MqttSession>>,
+    target: &ResponseTarget,
+    outcome: Result<serde_json::Value, Error>,
+) {
+    let response = CommandResponse::new(target.correlation_id.clone(), outcome);
+    let message = serde_json::to_string(&response).unwrap();
+    mqtt_session
+        .lock()
+        .unwrap()
+        .publish_all(
+            vec![Message::event(target.topic.clone(), message)],
+            TopicClass::Responses,
+        )
+        .map(|_| debug!("command response published to {}", target.topic))
+        .map_err(|e| error!("error = {:?}", e))
+        .unwrap_or_default()
+}
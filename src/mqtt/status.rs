@@ -2,14 +2,15 @@ use core::time::Duration;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 
-use chrono::Local;
 use futures::prelude::*;
-use rumqtt::QoS;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 
+use crate::communication::event::StatusEvent;
+use crate::embedded::command::ValveEvent;
 use crate::embedded::configuration::LayoutConfig;
 use crate::embedded::{LayoutStatus, PinLayout, ToggleValve};
 use crate::mqtt::configuration::MqttConfig;
+use crate::mqtt::topics::TopicClass;
 use crate::mqtt::MqttSession;
 use crate::schedule::WateringScheduleConfigs;
 use tokio::time::Interval;
@@ -22,6 +23,7 @@ impl PinLayoutStatus {
         mqtt_session: Arc<Mutex<MqttSession>>,
         mqtt_config: Arc<Mutex<MqttConfig>>,
         report_status_rx: mpsc::Receiver<()>,
+        status_event_tx: broadcast::Sender<StatusEvent>,
     ) where
         T: PinLayout<U> + Send + 'static,
         U: ToggleValve + Send + 'static,
@@ -32,7 +34,9 @@ impl PinLayoutStatus {
         while let Some(_) = interval_or_receiver.next().await {
             let status = PinLayoutStatus::get_current_layout_status(&layout);
             PinLayoutStatus::log_status(&status);
-            PinLayoutStatus::publish_status(&mqtt_session, &mqtt_config, &status);
+            PinLayoutStatus::publish_status(&mqtt_session, &status);
+            // fails only without live status subscribers
+            let _ = status_event_tx.send(StatusEvent::LayoutStatus(status));
         }
     }
 
@@ -45,34 +49,48 @@ impl PinLayoutStatus {
     }
 
     fn log_status(status: &LayoutStatus) {
-        println!(
-            "{}: {:?}",
-            Local::now().format("%Y-%m-%d][%H:%M:%S"),
-            status
-        )
+        info!("{:?}", status)
     }
 
-    fn publish_status(
-        mqtt_session: &Arc<Mutex<MqttSession>>,
-        mqtt_config: &Arc<Mutex<MqttConfig>>,
-        status: &LayoutStatus,
-    ) {
-        let topic = format!(
-            "{}/garden-butler/status/layout",
-            mqtt_config.lock().unwrap().client_id
-        );
-        let message = serde_json::to_string(&status).unwrap();
-        match mqtt_session
-            .lock()
-            .unwrap()
-            .publish(topic, QoS::AtMostOnce, true, message)
-        {
-            Ok(_) => println!("layout status published"),
-            Err(e) => println!("mqtt publish error = {:?}", e),
+    fn publish_status(mqtt_session: &Arc<Mutex<MqttSession>>, status: &LayoutStatus) {
+        let mut session = mqtt_session.lock().unwrap();
+        let messages = session.topics().layout_status(status);
+        match session.publish_all(messages, TopicClass::LayoutStatus) {
+            Ok(_) => debug!("layout status published"),
+            Err(e) => error!("mqtt publish error = {:?}", e),
         }
     }
 }
 
+/// Publishes valve transitions as they happen instead of waiting for the next status interval.
+pub struct ValveEventStatus {}
+
+impl ValveEventStatus {
+    pub async fn report(
+        mqtt_session: Arc<Mutex<MqttSession>>,
+        valve_event_rx: mpsc::Receiver<ValveEvent>,
+        status_event_tx: broadcast::Sender<StatusEvent>,
+    ) {
+        valve_event_rx
+            .for_each(|event| {
+                info!("{:?}", event);
+                let mut session = mqtt_session.lock().unwrap();
+                let topics = session.topics();
+                let _ = session
+                    .publish_all(topics.valve_state(&event), TopicClass::LayoutStatus)
+                    .map_err(|e| error!("error = {:?}", e));
+                session
+                    .publish_all(topics.valve_event(&event), TopicClass::Events)
+                    .map(|_| debug!("valve event published"))
+                    .map_err(|e| error!("error = {:?}", e))
+                    .unwrap_or_default();
+                let _ = status_event_tx.send(StatusEvent::ValveEvent(event));
+                future::ready(())
+            })
+            .await
+    }
+}
+
 pub struct WateringScheduleConfigStatus {}
 
 impl WateringScheduleConfigStatus {
@@ -81,51 +99,81 @@ impl WateringScheduleConfigStatus {
         mqtt_session: Arc<Mutex<MqttSession>>,
         mqtt_config: Arc<Mutex<MqttConfig>>,
         report_status_rx: mpsc::Receiver<()>,
+        status_event_tx: broadcast::Sender<StatusEvent>,
     ) {
         let interval = get_publish_interval(&mqtt_config).map(|_| ());
         let mut interval_or_receiver = stream::select(interval, report_status_rx.map(|_| ()));
 
         while let Some(_) = interval_or_receiver.next().await {
             let guard = watering_schedule_configs.lock().unwrap();
-            WateringScheduleConfigStatus::publish_status(&mqtt_session, &mqtt_config, guard.deref())
+            WateringScheduleConfigStatus::publish_status(&mqtt_session, guard.deref());
+            let _ = status_event_tx.send(StatusEvent::Schedules(guard.deref().clone()));
         }
     }
 
-    fn publish_status(
-        mqtt_session: &Arc<Mutex<MqttSession>>,
-        mqtt_config: &Arc<Mutex<MqttConfig>>,
-        status: &WateringScheduleConfigs,
-    ) {
-        let topic = format!(
-            "{}/garden-butler/status/watering-schedule",
-            mqtt_config.lock().unwrap().client_id
-        );
-        let message = serde_json::to_string(status).unwrap();
-
+    fn publish_status(mqtt_session: &Arc<Mutex<MqttSession>>, status: &WateringScheduleConfigs) {
         let mut session = mqtt_session.lock().unwrap();
+        let messages = session.topics().schedule_status(status);
         session
-            .publish(topic, QoS::ExactlyOnce, true, message)
-            .map(|_| println!("watering configuration published"))
-            .map_err(|e| println!("error = {:?}", e))
+            .publish_all(messages, TopicClass::ScheduleStatus)
+            .map(|_| debug!("watering configuration published"))
+            .map_err(|e| error!("error = {:?}", e))
             .unwrap_or_default()
     }
 }
 
+/// Describes the device again on every connect, the broker may have lost the retained messages.
+pub struct DeviceStatus {}
+
+impl DeviceStatus {
+    pub async fn report(
+        layout_config: Arc<Mutex<LayoutConfig>>,
+        watering_schedule_configs: Arc<Mutex<WateringScheduleConfigs>>,
+        mqtt_session: Arc<Mutex<MqttSession>>,
+        mut reconnect_rx: mpsc::Receiver<()>,
+    ) {
+        while reconnect_rx.next().await.is_some() {
+            let mut session = mqtt_session.lock().unwrap();
+            let messages = session.topics().device(
+                layout_config.lock().unwrap().deref(),
+                watering_schedule_configs.lock().unwrap().deref(),
+            );
+            session
+                .publish_all(messages, TopicClass::Configuration)
+                .map(|_| debug!("device published"))
+                .map_err(|e| error!("error = {:?}", e))
+                .unwrap_or_default()
+        }
+    }
+}
+
 pub struct LayoutConfigStatus {}
 
 impl LayoutConfigStatus {
     pub async fn report(layout: Arc<Mutex<LayoutConfig>>, mqtt_session: Arc<Mutex<MqttSession>>) {
         let mut session = mqtt_session.lock().unwrap();
-        let topic = format!(
-            "{}/garden-butler/status/layout-config",
-            session.get_client_id()
-        );
-        let guard = layout.lock().unwrap();
-        let message = serde_json::to_string(guard.deref()).unwrap();
+        let messages = session
+            .topics()
+            .layout_config(layout.lock().unwrap().deref());
+        session
+            .publish_all(messages, TopicClass::Configuration)
+            .map(|_| debug!("layout configuration published"))
+            .map_err(|e| error!("error = {:?}", e))
+            .unwrap_or_default()
+    }
+}
+
+/// Problems the butler worked around, e.g. a broken state file replaced by its backup.
+pub struct WarningStatus {}
+
+impl WarningStatus {
+    pub async fn report(warnings: Vec<String>, mqtt_session: Arc<Mutex<MqttSession>>) {
+        let mut session = mqtt_session.lock().unwrap();
+        let messages = session.topics().warnings(&warnings);
         session
-            .publish(topic, QoS::ExactlyOnce, true, message)
-            .map(|_| println!("layout configuration published"))
-            .map_err(|e| println!("error = {:?}", e))
+            .publish_all(messages, TopicClass::Health)
+            .map(|_| debug!("warnings published"))
+            .map_err(|e| error!("error = {:?}", e))
             .unwrap_or_default()
     }
 }
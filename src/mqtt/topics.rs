@@ -0,0 +1,839 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::embedded::command::{ValveEvent, ValveTransition};
+use crate::embedded::configuration::LayoutConfig;
+use crate::embedded::{LayoutStatus, PumpStatus, ValvePinNumber, ValveStatus};
+use crate::mqtt::configuration::{CommandKind, MqttConfig, TopicSchemeConfig};
+use crate::schedule::{WateringScheduleConfig, WateringScheduleConfigs};
+
+/// A single mqtt message created by a `TopicScheme`.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+impl Message {
+    pub fn event<T: Into<String>, P: Into<String>>(topic: T, payload: P) -> Message {
+        Message {
+            topic: topic.into(),
+            payload: payload.into(),
+            retain: false,
+        }
+    }
+
+    pub fn retained<T: Into<String>, P: Into<String>>(topic: T, payload: P) -> Message {
+        Message {
+            topic: topic.into(),
+            payload: payload.into(),
+            retain: true,
+        }
+    }
+}
+
+/// Commands a topic addresses. The payload is interpreted by the `MqttCommandListener`.
+#[derive(Debug, Clone)]
+pub enum CommandTopic {
+    ValveOpen,
+    ValveClose,
+    /// Switches a single valve with an ON/OFF payload.
+    ValveSwitch(ValvePinNumber),
+    ScheduleEnable,
+    ScheduleDisable,
+    ScheduleDelete,
+    ScheduleCreate,
+    /// Enables or disables the schedule given in the payload depending on its `enabled` flag.
+    ScheduleSwitch,
+    /// Enables or disables the schedule addressed by the topic with an ON/OFF payload.
+    ScheduleSwitchFor(WateringScheduleConfig),
+}
+
+impl CommandTopic {
+    pub fn get_kind(&self) -> CommandKind {
+        match self {
+            CommandTopic::ValveOpen => CommandKind::ValveOpen,
+            CommandTopic::ValveClose => CommandKind::ValveClose,
+            CommandTopic::ValveSwitch(_) => CommandKind::ValveSwitch,
+            CommandTopic::ScheduleEnable => CommandKind::ScheduleEnable,
+            CommandTopic::ScheduleDisable => CommandKind::ScheduleDisable,
+            CommandTopic::ScheduleDelete => CommandKind::ScheduleDelete,
+            CommandTopic::ScheduleCreate => CommandKind::ScheduleCreate,
+            CommandTopic::ScheduleSwitch | CommandTopic::ScheduleSwitchFor(_) => {
+                CommandKind::ScheduleSwitch
+            }
+        }
+    }
+}
+
+/// Layout of the mqtt topics. Everything that is published or subscribed is built here so that
+/// the command listener and the status reporters do not depend on a particular convention.
+pub trait TopicScheme: Send + Sync {
+    fn command_subscription(&self) -> String;
+    fn parse_command_topic(&self, topic: &str) -> Option<CommandTopic>;
+    /// Responses are only published below this prefix, whatever topic a command asks for.
+    fn response_prefix(&self) -> String;
+    fn response_topic(&self, correlation_id: &str) -> String;
+    fn last_will(&self) -> Message;
+    /// Published first whenever the connection was established.
+    fn online(&self) -> Vec<Message>;
+    /// Everything that describes the device, published after the messages queued while offline
+    /// whenever the connection was established.
+    fn device(
+        &self,
+        layout_config: &LayoutConfig,
+        schedules: &WateringScheduleConfigs,
+    ) -> Vec<Message>;
+    fn layout_config(&self, layout_config: &LayoutConfig) -> Vec<Message>;
+    fn layout_status(&self, status: &LayoutStatus) -> Vec<Message>;
+    fn schedule_status(&self, schedules: &WateringScheduleConfigs) -> Vec<Message>;
+    /// Retained state of the valve after the event.
+    fn valve_state(&self, event: &ValveEvent) -> Vec<Message>;
+    fn valve_event(&self, event: &ValveEvent) -> Vec<Message>;
+    /// Retained json list of problems that were worked around, an empty list clears the ones of
+    /// a previous start.
+    fn warnings(&self, warnings: &[String]) -> Vec<Message>;
+}
+
+/// Topics with their own QoS and retain policy in `MqttConfig`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TopicClass {
+    Health,
+    /// Layout config, homie attributes and home assistant discovery.
+    Configuration,
+    LayoutStatus,
+    ScheduleStatus,
+    Events,
+    Responses,
+    Commands,
+}
+
+pub fn create_topic_scheme(
+    mqtt_config: &MqttConfig,
+    layout_config: &LayoutConfig,
+) -> Arc<dyn TopicScheme> {
+    match mqtt_config.topic_scheme {
+        Some(TopicSchemeConfig::Homie) => Arc::new(HomieTopics::new(mqtt_config, layout_config)),
+        Some(TopicSchemeConfig::GardenButler) | None => {
+            Arc::new(GardenButlerTopics::new(mqtt_config))
+        }
+    }
+}
+
+/// The original layout with json payloads below `{client_id}/garden-butler` or the configured
+/// topic prefix.
+pub struct GardenButlerTopics {
+    base: String,
+}
+
+impl GardenButlerTopics {
+    pub fn new(mqtt_config: &MqttConfig) -> GardenButlerTopics {
+        GardenButlerTopics {
+            base: mqtt_config.get_topic_prefix("{client_id}/garden-butler"),
+        }
+    }
+
+    pub fn health_topic(&self) -> String {
+        format!("{}/status/health", self.base)
+    }
+
+    pub fn layout_status_topic(&self) -> String {
+        format!("{}/status/layout", self.base)
+    }
+
+    pub fn schedule_status_topic(&self) -> String {
+        format!("{}/status/watering-schedule", self.base)
+    }
+
+    pub fn valve_set_topic(&self, valve_pin_number: ValvePinNumber) -> String {
+        format!(
+            "{}/command/layout/valve/{}/set",
+            self.base, valve_pin_number.0
+        )
+    }
+
+    fn valve_status_topic(&self, valve_pin_number: ValvePinNumber) -> String {
+        format!("{}/status/valve/{}", self.base, valve_pin_number.0)
+    }
+
+    pub fn schedule_set_topic(&self) -> String {
+        format!("{}/command/watering-schedule/set", self.base)
+    }
+
+    fn command_prefix(&self) -> String {
+        format!("{}/command/", self.base)
+    }
+}
+
+impl TopicScheme for GardenButlerTopics {
+    fn command_subscription(&self) -> String {
+        format!("{}#", self.command_prefix())
+    }
+
+    fn parse_command_topic(&self, topic: &str) -> Option<CommandTopic> {
+        let command = topic.strip_prefix(&self.command_prefix())?;
+        match command {
+            "layout/open" => Some(CommandTopic::ValveOpen),
+            "layout/close" => Some(CommandTopic::ValveClose),
+            "watering-schedule/enable" => Some(CommandTopic::ScheduleEnable),
+            "watering-schedule/disable" => Some(CommandTopic::ScheduleDisable),
+            "watering-schedule/delete" => Some(CommandTopic::ScheduleDelete),
+            "watering-schedule/create" => Some(CommandTopic::ScheduleCreate),
+            "watering-schedule/set" => Some(CommandTopic::ScheduleSwitch),
+            _ => {
+                let pin = command
+                    .strip_prefix("layout/valve/")?
+                    .strip_suffix("/set")?;
+                u8::from_str(pin)
+                    .ok()
+                    .map(|pin| CommandTopic::ValveSwitch(ValvePinNumber(pin)))
+            }
+        }
+    }
+
+    fn response_prefix(&self) -> String {
+        format!("{}/response/", self.base)
+    }
+
+    fn response_topic(&self, correlation_id: &str) -> String {
+        format!("{}{}", self.response_prefix(), correlation_id)
+    }
+
+    fn last_will(&self) -> Message {
+        Message::retained(self.health_topic(), "OFFLINE")
+    }
+
+    fn online(&self) -> Vec<Message> {
+        vec![Message::retained(self.health_topic(), "ONLINE")]
+    }
+
+    fn device(
+        &self,
+        layout_config: &LayoutConfig,
+        _schedules: &WateringScheduleConfigs,
+    ) -> Vec<Message> {
+        self.layout_config(layout_config)
+    }
+
+    fn layout_config(&self, layout_config: &LayoutConfig) -> Vec<Message> {
+        vec![Message::retained(
+            format!("{}/status/layout-config", self.base),
+            serde_json::to_string(layout_config).unwrap(),
+        )]
+    }
+
+    fn layout_status(&self, status: &LayoutStatus) -> Vec<Message> {
+        let mut messages = vec![Message::retained(
+            self.layout_status_topic(),
+            serde_json::to_string(status).unwrap(),
+        )];
+        messages.extend(status.get_valves().iter().map(|v| {
+            Message::retained(
+                self.valve_status_topic(v.get_valve_pin_number()),
+                valve_status_payload(is_open(v.get_status())),
+            )
+        }));
+        messages
+    }
+
+    fn schedule_status(&self, schedules: &WateringScheduleConfigs) -> Vec<Message> {
+        vec![Message::retained(
+            self.schedule_status_topic(),
+            serde_json::to_string(schedules).unwrap(),
+        )]
+    }
+
+    fn valve_state(&self, event: &ValveEvent) -> Vec<Message> {
+        vec![Message::retained(
+            self.valve_status_topic(event.valve),
+            valve_status_payload(event.transition == ValveTransition::OPENED),
+        )]
+    }
+
+    fn valve_event(&self, event: &ValveEvent) -> Vec<Message> {
+        vec![Message::event(
+            format!("{}/events", self.base),
+            serde_json::to_string(event).unwrap(),
+        )]
+    }
+
+    fn warnings(&self, warnings: &[String]) -> Vec<Message> {
+        vec![Message::retained(
+            format!("{}/status/warnings", self.base),
+            serde_json::to_string(warnings).unwrap(),
+        )]
+    }
+}
+
+/// Homie 4.0 convention (https://homieiot.github.io/) under `homie/{client_id}` or the configured
+/// topic prefix. Every valve is a node with a settable boolean `open` property, every schedule a
+/// node with a settable boolean `enabled` property. Schedules are created and deleted through the
+/// `schedules` node with the usual json payloads. Valve events are published to the non-retained
+/// `valve` property of the `events` node. Responses are not part of the convention and go to
+/// `responses/{correlation_id}` below the device topic without being announced as a node,
+/// warnings go to `warnings` alike.
+/// The device is `init` from the connect until all its attributes are published, then `ready`.
+pub struct HomieTopics {
+    client_id: String,
+    base: String,
+    valves: Vec<ValvePinNumber>,
+    has_pump: bool,
+    has_master_valve: bool,
+}
+
+impl HomieTopics {
+    pub fn new(mqtt_config: &MqttConfig, layout_config: &LayoutConfig) -> HomieTopics {
+        HomieTopics {
+            client_id: mqtt_config.client_id.clone(),
+            base: mqtt_config.get_topic_prefix("homie/{client_id}"),
+            valves: layout_config
+                .get_valves()
+                .iter()
+                .map(|v| ValvePinNumber(v.get_valve_pin_num()))
+                .collect(),
+            has_pump: layout_config.get_pump().is_some(),
+            has_master_valve: layout_config.get_master_valve().is_some(),
+        }
+    }
+
+    fn device_topic(&self, attribute: &str) -> String {
+        format!("{}/{}", self.base, attribute)
+    }
+
+    fn property_topic(&self, node: &str, property: &str) -> String {
+        format!("{}/{}/{}", self.base, node, property)
+    }
+
+    fn layout_nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<String> = self.valves.iter().map(|v| valve_node(*v)).collect();
+        if self.has_pump {
+            nodes.push(String::from("pump"));
+        }
+        if self.has_master_valve {
+            nodes.push(String::from("master-valve"));
+        }
+        nodes.push(String::from("schedules"));
+        nodes.push(String::from("events"));
+        nodes
+    }
+
+    fn node(&self, node: &str, name: &str, node_type: &str, properties: &[&str]) -> Vec<Message> {
+        vec![
+            Message::retained(self.property_topic(node, "$name"), name),
+            Message::retained(self.property_topic(node, "$type"), node_type),
+            Message::retained(
+                self.property_topic(node, "$properties"),
+                properties.join(","),
+            ),
+        ]
+    }
+
+    fn property(
+        &self,
+        node: &str,
+        property: &str,
+        name: &str,
+        datatype: &str,
+        settable: bool,
+        retained: bool,
+    ) -> Vec<Message> {
+        let attribute = |a: &str| self.property_topic(node, &format!("{}/{}", property, a));
+        vec![
+            Message::retained(attribute("$name"), name),
+            Message::retained(attribute("$datatype"), datatype),
+            Message::retained(attribute("$settable"), settable.to_string()),
+            Message::retained(attribute("$retained"), retained.to_string()),
+        ]
+    }
+}
+
+impl TopicScheme for HomieTopics {
+    fn command_subscription(&self) -> String {
+        format!("{}/+/+/set", self.base)
+    }
+
+    fn parse_command_topic(&self, topic: &str) -> Option<CommandTopic> {
+        let prefix = format!("{}/", self.base);
+        let parts: Vec<&str> = topic.strip_prefix(&prefix)?.split('/').collect();
+        match parts.as_slice() {
+            ["schedules", "create", "set"] => Some(CommandTopic::ScheduleCreate),
+            ["schedules", "delete", "set"] => Some(CommandTopic::ScheduleDelete),
+            [node, "open", "set"] => {
+                let pin = node.strip_prefix("valve-")?;
+                u8::from_str(pin)
+                    .ok()
+                    .map(|pin| CommandTopic::ValveSwitch(ValvePinNumber(pin)))
+            }
+            [node, "enabled", "set"] => {
+                parse_schedule_node(node).map(CommandTopic::ScheduleSwitchFor)
+            }
+            _ => None,
+        }
+    }
+
+    fn response_prefix(&self) -> String {
+        format!("{}/responses/", self.base)
+    }
+
+    fn response_topic(&self, correlation_id: &str) -> String {
+        format!("{}{}", self.response_prefix(), correlation_id)
+    }
+
+    fn last_will(&self) -> Message {
+        Message::retained(self.device_topic("$state"), "lost")
+    }
+
+    fn online(&self) -> Vec<Message> {
+        vec![Message::retained(self.device_topic("$state"), "init")]
+    }
+
+    fn device(
+        &self,
+        layout_config: &LayoutConfig,
+        schedules: &WateringScheduleConfigs,
+    ) -> Vec<Message> {
+        let mut messages = self.layout_config(layout_config);
+        // announces the schedule nodes and `$nodes`
+        messages.extend(self.schedule_status(schedules));
+        messages.push(Message::retained(self.device_topic("$state"), "ready"));
+        messages
+    }
+
+    fn layout_config(&self, _layout_config: &LayoutConfig) -> Vec<Message> {
+        let mut messages = vec![
+            Message::retained(self.device_topic("$homie"), "4.0.0"),
+            Message::retained(
+                self.device_topic("$name"),
+                format!("Garden Butler {}", self.client_id),
+            ),
+        ];
+        for valve in &self.valves {
+            let node = valve_node(*valve);
+            messages.extend(self.node(&node, &format!("Valve {}", valve.0), "valve", &["open"]));
+            messages.extend(self.property(&node, "open", "Open", "boolean", true, true));
+        }
+        if self.has_pump {
+            messages.extend(self.node("pump", "Pump", "pump", &["running"]));
+            messages.extend(self.property("pump", "running", "Running", "boolean", false, true));
+        }
+        if self.has_master_valve {
+            messages.extend(self.node("master-valve", "Master valve", "valve", &["open"]));
+            messages.extend(self.property("master-valve", "open", "Open", "boolean", false, true));
+        }
+        messages.extend(self.node(
+            "schedules",
+            "Watering schedules",
+            "schedules",
+            &["create", "delete"],
+        ));
+        messages.extend(self.property("schedules", "create", "Create", "string", true, false));
+        messages.extend(self.property("schedules", "delete", "Delete", "string", true, false));
+        messages.extend(self.node("events", "Events", "events", &["valve"]));
+        messages.extend(self.property("events", "valve", "Valve", "string", false, false));
+        messages
+    }
+
+    fn layout_status(&self, status: &LayoutStatus) -> Vec<Message> {
+        let mut messages: Vec<Message> = status
+            .get_valves()
+            .iter()
+            .map(|v| {
+                Message::retained(
+                    self.property_topic(&valve_node(v.get_valve_pin_number()), "open"),
+                    is_open(v.get_status()).to_string(),
+                )
+            })
+            .collect();
+        if let Some(pump) = status.get_pump() {
+            let running = match pump {
+                PumpStatus::ON => true,
+                PumpStatus::OFF => false,
+            };
+            messages.push(Message::retained(
+                self.property_topic("pump", "running"),
+                running.to_string(),
+            ));
+        }
+        if let Some(master_valve) = status.get_master_valve() {
+            messages.push(Message::retained(
+                self.property_topic("master-valve", "open"),
+                is_open(master_valve).to_string(),
+            ));
+        }
+        messages
+    }
+
+    fn schedule_status(&self, schedules: &WateringScheduleConfigs) -> Vec<Message> {
+        let mut nodes = self.layout_nodes();
+        let mut messages = Vec::new();
+        for schedule in schedules.get_schedules() {
+            let node = schedule_node(schedule);
+            messages.extend(self.node(
+                &node,
+                &format!("Schedule {}", schedule),
+                "schedule",
+                &["enabled"],
+            ));
+            messages.extend(self.property(&node, "enabled", "Enabled", "boolean", true, true));
+            messages.push(Message::retained(
+                self.property_topic(&node, "enabled"),
+                schedule.is_enabled().to_string(),
+            ));
+            nodes.push(node);
+        }
+        messages.push(Message::retained(
+            self.device_topic("$nodes"),
+            nodes.join(","),
+        ));
+        messages
+    }
+    fn valve_state(&self, event: &ValveEvent) -> Vec<Message> {
+        vec![Message::retained(
+            self.property_topic(&valve_node(event.valve), "open"),
+            (event.transition == ValveTransition::OPENED).to_string(),
+        )]
+    }
+
+    fn valve_event(&self, event: &ValveEvent) -> Vec<Message> {
+        vec![Message::event(
+            self.property_topic("events", "valve"),
+            serde_json::to_string(event).unwrap(),
+        )]
+    }
+
+    fn warnings(&self, warnings: &[String]) -> Vec<Message> {
+        vec![Message::retained(
+            self.device_topic("warnings"),
+            serde_json::to_string(warnings).unwrap(),
+        )]
+    }
+}
+
+/// Discovery topics live below the home assistant discovery prefix, not the topic prefix.
+pub fn home_assistant_discovery_topic(
+    discovery_prefix: &str,
+    component: &str,
+    client_id: &str,
+    object_id: &str,
+) -> String {
+    format!(
+        "{}/{}/{}/{}/config",
+        discovery_prefix, component, client_id, object_id
+    )
+}
+
+fn is_open(status: &ValveStatus) -> bool {
+    match status {
+        ValveStatus::OPEN => true,
+        ValveStatus::CLOSED => false,
+    }
+}
+
+fn valve_status_payload(open: bool) -> &'static str {
+    if open {
+        "OPEN"
+    } else {
+        "CLOSED"
+    }
+}
+
+fn valve_node(valve_pin_number: ValvePinNumber) -> String {
+    format!("valve-{}", valve_pin_number.0)
+}
+
+/// E.g. `schedule-27-0600-0630` for valve 27 from 06:00 to 06:30.
+fn schedule_node(schedule: &WateringScheduleConfig) -> String {
+    format!("schedule-{}", schedule.get_id())
+}
+
+fn parse_schedule_node(node: &str) -> Option<WateringScheduleConfig> {
+    WateringScheduleConfig::from_id(node.strip_prefix("schedule-")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::embedded::command::Origin;
+
+    fn mqtt_config(topic_scheme: &str) -> MqttConfig {
+        serde_json::from_str(&format!(
+            r#"{{"client_id": "garden", "broker_hostname": "localhost", "topic_scheme": "{}"}}"#,
+            topic_scheme
+        ))
+        .unwrap()
+    }
+
+    fn layout_config() -> LayoutConfig {
+        serde_json::from_str(
+            r#"{
+                "valves": [{"valve": 27, "button": 22}, {"valve": 10, "button": 9}],
+                "pump": {"power_pin": 5},
+                "master_valve": {"valve": 4}
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn schedules() -> WateringScheduleConfigs {
+        serde_json::from_str(
+            r#"{"schedules": [{
+                "schedule": {"start_hour": 6, "start_minute": 0, "end_hour": 6, "end_minute": 30},
+                "valve": 27,
+                "enabled": false
+            }]}"#,
+        )
+        .unwrap()
+    }
+
+    fn layout_status() -> LayoutStatus {
+        serde_json::from_str(
+            r#"{
+                "valves": [
+                    {"valve_pin_number": 27, "status": "OPEN"},
+                    {"valve_pin_number": 10, "status": "CLOSED"}
+                ],
+                "master_valve": "CLOSED",
+                "pump": "ON"
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn valve_opened() -> ValveEvent {
+        ValveEvent {
+            valve: ValvePinNumber(27),
+            transition: ValveTransition::OPENED,
+            origin: Origin::Schedule,
+            timestamp: NaiveDate::from_ymd(2020, 6, 1).and_hms(6, 0, 0),
+        }
+    }
+
+    fn garden_butler() -> GardenButlerTopics {
+        GardenButlerTopics::new(&mqtt_config("garden-butler"))
+    }
+
+    fn homie() -> HomieTopics {
+        HomieTopics::new(&mqtt_config("homie"), &layout_config())
+    }
+
+    fn payload<'a>(messages: &'a [Message], topic: &str) -> Option<&'a str> {
+        messages
+            .iter()
+            .find(|m| m.topic == topic)
+            .map(|m| m.payload.as_str())
+    }
+
+    #[test]
+    fn topic_prefixes_reach_both_schemes() {
+        for scheme in &["garden-butler", "homie"] {
+            let mut config = mqtt_config(scheme);
+            config.topic_prefix = Some(String::from("home/{client_id}/"));
+            let topics = create_topic_scheme(&config, &layout_config());
+
+            assert!(topics.command_subscription().starts_with("home/garden/"));
+            assert!(topics.response_prefix().starts_with("home/garden/"));
+            assert!(topics.last_will().topic.starts_with("home/garden/"));
+            assert!(topics
+                .device(&layout_config(), &schedules())
+                .iter()
+                .all(|m| m.topic.starts_with("home/garden/")));
+        }
+        let homie = create_topic_scheme(&mqtt_config("homie"), &layout_config());
+        assert_eq!(homie.last_will().topic, "homie/garden/$state");
+    }
+
+    #[test]
+    fn garden_butler_command_topics_are_parsed() {
+        let topics = garden_butler();
+        let parse = |command: &str| {
+            topics.parse_command_topic(&format!("garden/garden-butler/command/{}", command))
+        };
+
+        assert_eq!(
+            topics.command_subscription(),
+            "garden/garden-butler/command/#"
+        );
+        assert!(matches!(
+            parse("layout/open"),
+            Some(CommandTopic::ValveOpen)
+        ));
+        assert!(matches!(
+            parse("layout/close"),
+            Some(CommandTopic::ValveClose)
+        ));
+        assert!(matches!(
+            parse("layout/valve/27/set"),
+            Some(CommandTopic::ValveSwitch(ValvePinNumber(27)))
+        ));
+        assert!(matches!(
+            parse("watering-schedule/create"),
+            Some(CommandTopic::ScheduleCreate)
+        ));
+        assert!(matches!(
+            parse("watering-schedule/set"),
+            Some(CommandTopic::ScheduleSwitch)
+        ));
+        assert!(parse("layout/valve/300/set").is_none());
+        assert!(parse("layout/valve/27").is_none());
+        assert!(parse("layout/reboot").is_none());
+        assert!(topics
+            .parse_command_topic("elsewhere/command/layout/open")
+            .is_none());
+    }
+
+    #[test]
+    fn homie_command_topics_are_parsed() {
+        let topics = homie();
+        let parse = |topic: &str| topics.parse_command_topic(&format!("homie/garden/{}", topic));
+
+        assert_eq!(topics.command_subscription(), "homie/garden/+/+/set");
+        assert!(matches!(
+            parse("valve-27/open/set"),
+            Some(CommandTopic::ValveSwitch(ValvePinNumber(27)))
+        ));
+        match parse("schedule-27-0600-0630/enabled/set") {
+            Some(CommandTopic::ScheduleSwitchFor(schedule)) => {
+                assert_eq!(schedule.get_id(), "27-0600-0630")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            parse("schedules/create/set"),
+            Some(CommandTopic::ScheduleCreate)
+        ));
+        assert!(matches!(
+            parse("schedules/delete/set"),
+            Some(CommandTopic::ScheduleDelete)
+        ));
+        assert!(parse("valve-27/open").is_none());
+        assert!(parse("valve-x/open/set").is_none());
+        assert!(parse("pump/running/set").is_none());
+        assert!(parse("schedule-27-06-0630/enabled/set").is_none());
+    }
+
+    #[test]
+    fn schedule_nodes_round_trip() {
+        let schedule = schedules().get_schedules()[0];
+
+        assert_eq!(schedule_node(&schedule), "schedule-27-0600-0630");
+        let parsed = parse_schedule_node(&schedule_node(&schedule)).unwrap();
+        assert_eq!(parsed.get_id(), schedule.get_id());
+        assert!(parse_schedule_node("valve-27").is_none());
+        assert!(parse_schedule_node("schedule-27-0600").is_none());
+    }
+
+    #[test]
+    fn homie_devices_are_ready_once_described() {
+        let topics = homie();
+
+        assert_eq!(topics.online()[0].topic, "homie/garden/$state");
+        assert_eq!(topics.online()[0].payload, "init");
+        assert_eq!(topics.last_will().payload, "lost");
+
+        let messages = topics.device(&layout_config(), &schedules());
+        assert_eq!(messages[0].topic, "homie/garden/$homie");
+        let last = messages.last().unwrap();
+        assert_eq!(
+            (last.topic.as_str(), last.payload.as_str()),
+            ("homie/garden/$state", "ready")
+        );
+        assert!(messages.iter().all(|m| m.retain));
+        assert_eq!(
+            payload(&messages, "homie/garden/$nodes"),
+            Some("valve-27,valve-10,pump,master-valve,schedules,events,schedule-27-0600-0630")
+        );
+        assert_eq!(
+            payload(&messages, "homie/garden/valve-27/$properties"),
+            Some("open")
+        );
+        assert_eq!(
+            payload(&messages, "homie/garden/valve-27/open/$settable"),
+            Some("true")
+        );
+        assert_eq!(
+            payload(&messages, "homie/garden/pump/running/$settable"),
+            Some("false")
+        );
+        assert_eq!(
+            payload(&messages, "homie/garden/schedule-27-0600-0630/enabled"),
+            Some("false")
+        );
+    }
+
+    #[test]
+    fn homie_status_is_published_as_properties() {
+        let topics = homie();
+
+        let messages = topics.layout_status(&layout_status());
+        assert_eq!(
+            payload(&messages, "homie/garden/valve-27/open"),
+            Some("true")
+        );
+        assert_eq!(
+            payload(&messages, "homie/garden/valve-10/open"),
+            Some("false")
+        );
+        assert_eq!(
+            payload(&messages, "homie/garden/pump/running"),
+            Some("true")
+        );
+        assert_eq!(
+            payload(&messages, "homie/garden/master-valve/open"),
+            Some("false")
+        );
+        assert_eq!(
+            payload(
+                &topics.valve_state(&valve_opened()),
+                "homie/garden/valve-27/open"
+            ),
+            Some("true")
+        );
+        let events = topics.valve_event(&valve_opened());
+        assert_eq!(events[0].topic, "homie/garden/events/valve");
+        assert!(!events[0].retain);
+        assert_eq!(topics.response_topic("42"), "homie/garden/responses/42");
+    }
+
+    #[test]
+    fn garden_butler_status_is_published_as_json() {
+        let topics = garden_butler();
+
+        assert_eq!(topics.online()[0].payload, "ONLINE");
+        assert_eq!(
+            topics.last_will().topic,
+            "garden/garden-butler/status/health"
+        );
+        assert_eq!(topics.last_will().payload, "OFFLINE");
+
+        let messages = topics.layout_status(&layout_status());
+        let status: serde_json::Value =
+            serde_json::from_str(payload(&messages, "garden/garden-butler/status/layout").unwrap())
+                .unwrap();
+        assert_eq!(status["pump"], "ON");
+        assert_eq!(
+            payload(&messages, "garden/garden-butler/status/valve/27"),
+            Some("OPEN")
+        );
+        assert_eq!(
+            payload(&messages, "garden/garden-butler/status/valve/10"),
+            Some("CLOSED")
+        );
+        assert_eq!(
+            payload(
+                &topics.valve_state(&valve_opened()),
+                "garden/garden-butler/status/valve/27"
+            ),
+            Some("OPEN")
+        );
+        let events = topics.valve_event(&valve_opened());
+        assert_eq!(events[0].topic, "garden/garden-butler/events");
+        assert!(events[0].payload.contains("\"origin\":\"schedule\""));
+        assert_eq!(
+            topics.response_topic("42"),
+            "garden/garden-butler/response/42"
+        );
+    }
+}
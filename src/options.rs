@@ -0,0 +1,288 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use log::LevelFilter;
+
+pub const USAGE: &str = "usage: garden-butler [options]
+
+options:
+    --config <file>         unified config file with [layout], [mqtt], [watering], [http] and
+                            [control] sections, garden-butler.toml or .json in the config dir
+                            by default. The split files are used for missing sections.
+    --config-dir <dir>      directory of layout.json, mqtt.json, watering-schedules.json,
+                            http.json and control.json, the working directory by default
+    --layout <file>         layout config, instead of the one in the config dir
+    --mqtt <file>           mqtt config, instead of the one in the config dir
+    --schedules <file>      initial watering schedules, instead of the ones in the config dir
+    --http <file>           http config, instead of the one in the config dir
+    --control <file>        control socket config, instead of the one in the config dir
+    --state-dir <dir>       where changed watering schedules are saved,
+                            e.g. /var/lib/garden-butler, the schedules file itself by default
+    --backend <backend>     gpio or simulator, gpio by default
+    --simulate              short for --backend simulator
+    --log-level <level>     off, error, warn, info, debug or trace, info by default
+    --check-config          loads the configuration without upgrading any file and exits
+    -h, --help              prints this help
+
+Send SIGHUP to apply changes of the layout and the watering schedules without a restart.";
+
+const CONFIG_FILES: [&str; 2] = ["garden-butler.toml", "garden-butler.json"];
+const LAYOUT_FILE: &str = "layout.json";
+const MQTT_FILE: &str = "mqtt.json";
+const SCHEDULES_FILE: &str = "watering-schedules.json";
+const HTTP_FILE: &str = "http.json";
+const CONTROL_FILE: &str = "control.json";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Backend {
+    Gpio,
+    Simulator,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gpio" => Ok(Backend::Gpio),
+            "simulator" => Ok(Backend::Simulator),
+            _ => Err(format!("unknown backend {}, expected gpio or simulator", s)),
+        }
+    }
+}
+
+/// Command line of the butler, see `USAGE`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    config_file: Option<PathBuf>,
+    config_dir: PathBuf,
+    layout_file: Option<PathBuf>,
+    mqtt_file: Option<PathBuf>,
+    schedules_file: Option<PathBuf>,
+    http_file: Option<PathBuf>,
+    control_file: Option<PathBuf>,
+    state_dir: Option<PathBuf>,
+    backend: Backend,
+    log_level: LevelFilter,
+    check_config: bool,
+    help: bool,
+}
+
+impl Options {
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut options = Options {
+            config_file: None,
+            config_dir: PathBuf::from("."),
+            layout_file: None,
+            mqtt_file: None,
+            schedules_file: None,
+            http_file: None,
+            control_file: None,
+            state_dir: None,
+            backend: Backend::Gpio,
+            log_level: LevelFilter::Info,
+            check_config: false,
+            help: false,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    options.config_file = Some(PathBuf::from(next_value(&mut args, &arg)?))
+                }
+                "--config-dir" => options.config_dir = PathBuf::from(next_value(&mut args, &arg)?),
+                "--layout" => {
+                    options.layout_file = Some(PathBuf::from(next_value(&mut args, &arg)?))
+                }
+                "--mqtt" => options.mqtt_file = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+                "--schedules" => {
+                    options.schedules_file = Some(PathBuf::from(next_value(&mut args, &arg)?))
+                }
+                "--http" => options.http_file = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+                "--control" => {
+                    options.control_file = Some(PathBuf::from(next_value(&mut args, &arg)?))
+                }
+                "--state-dir" => {
+                    options.state_dir = Some(PathBuf::from(next_value(&mut args, &arg)?))
+                }
+                "--backend" => options.backend = Backend::from_str(&next_value(&mut args, &arg)?)?,
+                "--simulate" => options.backend = Backend::Simulator,
+                "--log-level" => {
+                    let level = next_value(&mut args, &arg)?;
+                    options.log_level = LevelFilter::from_str(&level)
+                        .map_err(|_| format!("unknown log level {}", level))?;
+                }
+                "--check-config" => options.check_config = true,
+                "-h" | "--help" => options.help = true,
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+        Ok(options)
+    }
+
+    /// The unified config file, if any.
+    pub fn get_config_file(&self) -> Option<PathBuf> {
+        self.config_file.clone().or_else(|| {
+            CONFIG_FILES
+                .iter()
+                .map(|file| self.config_dir.join(file))
+                .find(|file| file.exists())
+        })
+    }
+    pub fn get_layout_file(&self) -> PathBuf {
+        self.layout_file
+            .clone()
+            .unwrap_or_else(|| self.config_dir.join(LAYOUT_FILE))
+    }
+    pub fn get_mqtt_file(&self) -> PathBuf {
+        self.mqtt_file
+            .clone()
+            .unwrap_or_else(|| self.config_dir.join(MQTT_FILE))
+    }
+    /// The schedules to start with, until they are changed and saved to the state file.
+    pub fn get_schedules_file(&self) -> PathBuf {
+        self.schedules_file
+            .clone()
+            .unwrap_or_else(|| self.config_dir.join(SCHEDULES_FILE))
+    }
+    pub fn has_state_dir(&self) -> bool {
+        self.state_dir.is_some()
+    }
+    /// Where changed schedules are saved and read from on the next start.
+    pub fn get_schedules_state_file(&self) -> PathBuf {
+        match &self.state_dir {
+            Some(dir) => dir.join(SCHEDULES_FILE),
+            None => self.get_schedules_file(),
+        }
+    }
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub fn get_http_file(&self) -> PathBuf {
+        self.http_file
+            .clone()
+            .unwrap_or_else(|| self.config_dir.join(HTTP_FILE))
+    }
+    pub fn get_control_file(&self) -> PathBuf {
+        self.control_file
+            .clone()
+            .unwrap_or_else(|| self.config_dir.join(CONTROL_FILE))
+    }
+    pub fn get_backend(&self) -> Backend {
+        self.backend
+    }
+    pub fn get_log_level(&self) -> LevelFilter {
+        self.log_level
+    }
+    pub fn is_check_config(&self) -> bool {
+        self.check_config
+    }
+    pub fn is_help(&self) -> bool {
+        self.help
+    }
+}
+
+/// Another option is not a value, `--layout --check-config` lacks the layout file.
+fn next_value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> Result<String, String> {
+    match args.next() {
+        Some(value) if !value.starts_with("--") => Ok(value),
+        _ => Err(format!("{} needs a value", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn parse(line: &str) -> Result<Options, String> {
+        Options::from_args(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn defaults_without_arguments() {
+        let options = parse("").unwrap();
+
+        assert_eq!(options.get_backend(), Backend::Gpio);
+        assert_eq!(options.get_log_level(), LevelFilter::Info);
+        assert!(!options.is_check_config());
+        assert!(!options.is_help());
+        assert!(!options.has_state_dir());
+        assert_eq!(options.get_layout_file(), Path::new("./layout.json"));
+        assert_eq!(
+            options.get_schedules_state_file(),
+            Path::new("./watering-schedules.json")
+        );
+    }
+
+    #[test]
+    fn files_follow_the_config_dir_unless_given() {
+        let options = parse(
+            "--config-dir /etc/garden-butler --mqtt /tmp/mqtt.json --state-dir /var/lib/garden-butler",
+        )
+        .unwrap();
+
+        assert_eq!(
+            options.get_layout_file(),
+            Path::new("/etc/garden-butler/layout.json")
+        );
+        assert_eq!(options.get_mqtt_file(), Path::new("/tmp/mqtt.json"));
+        assert_eq!(
+            options.get_schedules_file(),
+            Path::new("/etc/garden-butler/watering-schedules.json")
+        );
+        assert_eq!(
+            options.get_schedules_state_file(),
+            Path::new("/var/lib/garden-butler/watering-schedules.json")
+        );
+        assert_eq!(
+            options.get_control_file(),
+            Path::new("/etc/garden-butler/control.json")
+        );
+    }
+
+    #[test]
+    fn check_config_backend_and_log_level() {
+        let options = parse("--check-config --simulate --log-level debug").unwrap();
+        assert!(options.is_check_config());
+        assert_eq!(options.get_backend(), Backend::Simulator);
+        assert_eq!(options.get_log_level(), LevelFilter::Debug);
+
+        let options = parse("--backend gpio --check-config").unwrap();
+        assert!(options.is_check_config());
+        assert_eq!(options.get_backend(), Backend::Gpio);
+
+        assert!(parse("-h").unwrap().is_help());
+    }
+
+    #[test]
+    fn unknown_arguments_are_rejected() {
+        for line in &[
+            "--verbose",
+            "--check-config --dry-run",
+            "-c",
+            "layout.json",
+            "--backend raspberry",
+            "--log-level loud",
+        ] {
+            assert!(parse(line).is_err(), "{} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn missing_values_are_rejected() {
+        for line in &[
+            "--config",
+            "--config-dir",
+            "--layout",
+            "--state-dir",
+            "--backend",
+            "--log-level",
+            "--layout --check-config",
+        ] {
+            assert_eq!(
+                parse(line).unwrap_err(),
+                format!("{} needs a value", line.split_whitespace().next().unwrap())
+            );
+        }
+    }
+}
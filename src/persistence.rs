@@ -0,0 +1,130 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Previous versions kept next to a saved file, e.g. `watering-schedules.json.1` is the newest.
+const BACKUP_COUNT: usize = 3;
+
+/// Replaces the file so that a power cut leaves either the old or the new version behind, never
+/// a truncated one. The old version becomes the newest backup.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    replace(path, contents, true)
+}
+
+/// Puts the backup in place of a broken file without rotating, so that the good backups are not
+/// pushed out by it. The broken file is kept aside as e.g. `watering-schedules.json.broken`.
+pub fn restore_backup(path: &Path, backup: &Path) -> io::Result<PathBuf> {
+    let contents = fs::read(backup)?;
+    let broken_path = with_suffix(path, "broken");
+    fs::copy(path, &broken_path)?;
+    replace(path, &contents, false)?;
+    Ok(broken_path)
+}
+
+/// Backups of the file, newest first, whether they exist or not.
+pub fn get_backup_files(path: &Path) -> Vec<PathBuf> {
+    (1..=BACKUP_COUNT)
+        .map(|i| with_suffix(path, &i.to_string()))
+        .collect()
+}
+
+fn replace(path: &Path, contents: &[u8], keep_backup: bool) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    fs::create_dir_all(&dir)?;
+
+    let temp_path = with_suffix(path, "tmp");
+    let mut temp_file = fs::File::create(&temp_path)?;
+    temp_file.write_all(contents)?;
+    temp_file.sync_all()?;
+
+    if keep_backup && path.exists() {
+        rotate_backups(path)?;
+    }
+    fs::rename(&temp_path, path)?;
+    // the rename itself is only durable once the directory is
+    fs::File::open(&dir)?.sync_all()
+}
+
+fn rotate_backups(path: &Path) -> io::Result<()> {
+    let backups = get_backup_files(path);
+    for i in (1..backups.len()).rev() {
+        if backups[i - 1].exists() {
+            fs::rename(&backups[i - 1], &backups[i])?;
+        }
+    }
+    // copied rather than moved, so that the file is never missing
+    fs::copy(path, &backups[0]).map(|_| ())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for every test, tests run in parallel.
+    fn create_temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("garden-butler-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn backups_are_rotated_newest_first() {
+        let dir = create_temp_dir("rotation");
+        let file = dir.join("schedules.json");
+
+        for version in 1..=5 {
+            write_atomically(&file, version.to_string().as_bytes()).unwrap();
+        }
+        assert_eq!(read(&file), "5");
+        let backups = get_backup_files(&file);
+        assert_eq!(
+            backups.iter().map(|b| read(b)).collect::<Vec<_>>(),
+            vec!["4", "3", "2"]
+        );
+        assert!(!dir.join("schedules.json.4").exists());
+        assert!(!dir.join("schedules.json.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restored_backups_are_not_rotated() {
+        let dir = create_temp_dir("restore");
+        let file = dir.join("schedules.json");
+        for version in 1..=3 {
+            write_atomically(&file, version.to_string().as_bytes()).unwrap();
+        }
+        fs::write(&file, "broken").unwrap();
+
+        let broken = restore_backup(&file, &get_backup_files(&file)[0]).unwrap();
+        assert_eq!(read(&broken), "broken");
+        assert_eq!(read(&file), "2");
+        assert_eq!(read(&get_backup_files(&file)[0]), "2");
+
+        write_atomically(&file, b"4").unwrap();
+        assert_eq!(
+            get_backup_files(&file)
+                .iter()
+                .map(|b| read(b))
+                .collect::<Vec<_>>(),
+            vec!["2", "2", "1"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
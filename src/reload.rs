@@ -0,0 +1,187 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::Sender;
+
+use crate::communication;
+use crate::configuration::Configuration;
+use crate::embedded::command::{LayoutCommand, LayoutRequest, Origin};
+use crate::embedded::configuration::LayoutConfig;
+use crate::embedded::{Error, PinLayout, ToggleValve, ValvePinNumber};
+use crate::mqtt::status::{LayoutConfigStatus, WarningStatus};
+use crate::mqtt::MqttSession;
+use crate::options::Options;
+use crate::schedule::{WateringScheduleConfigs, WateringScheduler};
+
+/// Hands a changed layout to the pin layout, whatever hardware it drives.
+type ReconfigureLayout = Box<dyn Fn(&LayoutConfig) -> Result<(), Error> + Send + Sync>;
+
+/// Applies changes to the layout and the watering schedules on disk when the butler receives a
+/// SIGHUP, e.g. from `systemctl reload` or `kill -HUP`. A configuration with problems is rejected
+/// as a whole and the running one is kept. The mqtt, http and control sections are only read at
+/// start.
+pub struct ConfigReloader {
+    options: Options,
+    reconfigure_layout: ReconfigureLayout,
+    layout_config: Arc<Mutex<LayoutConfig>>,
+    watering_schedule_config: Arc<Mutex<WateringScheduleConfigs>>,
+    watering_scheduler: Arc<Mutex<WateringScheduler>>,
+    mqtt_session: Arc<Mutex<MqttSession>>,
+    layout_command_sender: Option<Sender<LayoutRequest>>,
+    layout_status_sender: Sender<()>,
+    watering_config_status_sender: Sender<()>,
+    reconnect_senders: Vec<Sender<()>>,
+}
+
+impl ConfigReloader {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<T, U>(
+        options: Options,
+        layout: Arc<Mutex<T>>,
+        layout_config: Arc<Mutex<LayoutConfig>>,
+        watering_schedule_config: Arc<Mutex<WateringScheduleConfigs>>,
+        watering_scheduler: Arc<Mutex<WateringScheduler>>,
+        mqtt_session: Arc<Mutex<MqttSession>>,
+        layout_command_sender: &Option<Sender<LayoutRequest>>,
+        layout_status_sender: Sender<()>,
+        watering_config_status_sender: Sender<()>,
+        reconnect_senders: &[Sender<()>],
+    ) -> ConfigReloader
+    where
+        T: PinLayout<U> + Send + 'static,
+        U: ToggleValve + Send + 'static,
+    {
+        ConfigReloader {
+            options,
+            reconfigure_layout: Box::new(move |config| layout.lock().unwrap().reconfigure(config)),
+            layout_config,
+            watering_schedule_config,
+            watering_scheduler,
+            mqtt_session,
+            layout_command_sender: layout_command_sender.as_ref().cloned(),
+            layout_status_sender,
+            watering_config_status_sender,
+            reconnect_senders: reconnect_senders.to_vec(),
+        }
+    }
+
+    pub async fn listen_to_hangups(mut self) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                error!("could not listen to SIGHUP = {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            info!("SIGHUP received, reloading the configuration");
+            match Configuration::load(&self.options) {
+                Ok(configuration) => {
+                    for warning in &configuration.warnings {
+                        warn!("{}", warning);
+                    }
+                    WarningStatus::report(configuration.warnings, Arc::clone(&self.mqtt_session))
+                        .await;
+                    self.reload_layout(configuration.layout).await;
+                    self.reload_schedules(configuration.watering);
+                }
+                Err(problems) => {
+                    for problem in problems {
+                        error!("{}", problem);
+                    }
+                    warn!("configuration not reloaded, the running one is kept");
+                }
+            }
+        }
+    }
+
+    async fn reload_layout(&mut self, layout_config: LayoutConfig) {
+        let changed_valves = {
+            let running = self.layout_config.lock().unwrap();
+            if *running == layout_config {
+                debug!("layout unchanged");
+                return;
+            }
+            running.get_changed_valves(&layout_config)
+        };
+
+        // through the listener, so that closing is published like any other valve event
+        for valve in changed_valves {
+            let command = LayoutCommand::Close(ValvePinNumber(valve), Origin::Reload);
+            let result = match communication::send_request(&self.layout_command_sender, command) {
+                Ok(response) => response
+                    .await
+                    .map_err(|_| String::from("command was dropped"))
+                    .and_then(|result| result.map_err(|e| e.to_string())),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("could not close valve {} before the reload = {}", valve, e);
+            }
+        }
+
+        if let Err(e) = (self.reconfigure_layout)(&layout_config) {
+            error!(
+                "layout could not be reconfigured, keeping the running one = {}",
+                e
+            );
+            return;
+        }
+        self.mqtt_session.lock().unwrap().set_layout(&layout_config);
+        *self.layout_config.lock().unwrap() = layout_config;
+
+        LayoutConfigStatus::report(
+            Arc::clone(&self.layout_config),
+            Arc::clone(&self.mqtt_session),
+        )
+        .await;
+        let _ = self
+            .layout_status_sender
+            .try_send(())
+            .map_err(|e| error!("error sending signal for layout status update = {}", e));
+        // describes the device and republishes the discovery like after a reconnect
+        for reconnect_sender in &mut self.reconnect_senders {
+            let _ = reconnect_sender
+                .try_send(())
+                .map_err(|e| error!("error sending signal for device update = {}", e));
+        }
+        info!("layout reloaded");
+    }
+
+    /// Stops the schedules that were removed or disabled and starts the new and enabled ones.
+    fn reload_schedules(&mut self, watering_schedule_config: WateringScheduleConfigs) {
+        {
+            let mut running = self.watering_schedule_config.lock().unwrap();
+            if running.get_schedules() == watering_schedule_config.get_schedules() {
+                debug!("watering schedules unchanged");
+                return;
+            }
+            let mut scheduler = self.watering_scheduler.lock().unwrap();
+            for schedule in running
+                .get_schedules()
+                .iter()
+                .filter(|s| s.is_enabled() && !watering_schedule_config.get_schedules().contains(s))
+            {
+                let _ = scheduler
+                    .stop_schedule(schedule)
+                    .map_err(|e| warn!("{}", e));
+            }
+            for schedule in watering_schedule_config
+                .get_schedules()
+                .iter()
+                .filter(|s| s.is_enabled() && !running.get_schedules().contains(s))
+            {
+                let _ = scheduler
+                    .start_schedule(schedule)
+                    .map_err(|e| warn!("{}", e));
+            }
+            *running = watering_schedule_config;
+        }
+
+        let _ = self
+            .watering_config_status_sender
+            .try_send(())
+            .map_err(|e| error!("schedule status send error = {}", e));
+        info!("watering schedules reloaded");
+    }
+}
@@ -0,0 +1,82 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{Local, NaiveDateTime};
+use futures::prelude::*;
+use tokio::sync::watch;
+
+/// Source of the local time for the scheduler. Watering tasks check the time whenever `ticks`
+/// yields, so a test clock can drive them without waiting in real time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> NaiveDateTime;
+    fn ticks(&self) -> Pin<Box<dyn Stream<Item = ()> + Send>>;
+}
+
+pub struct SystemClock {}
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+
+    fn ticks(&self) -> Pin<Box<dyn Stream<Item = ()> + Send>> {
+        tokio::time::interval(Duration::from_secs(1))
+            .map(|_| ())
+            .boxed()
+    }
+}
+
+/// Clock that only moves when `advance` or `set` is called. The simulator lets it follow the
+/// system time with `run_in_real_time` so that the console can jump ahead.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<NaiveDateTime>>,
+    tick_sender: Arc<Mutex<watch::Sender<NaiveDateTime>>>,
+    tick_receiver: watch::Receiver<NaiveDateTime>,
+}
+
+impl ManualClock {
+    pub fn new(start: NaiveDateTime) -> ManualClock {
+        let (tick_sender, tick_receiver) = watch::channel(start);
+        ManualClock {
+            now: Arc::new(Mutex::new(start)),
+            tick_sender: Arc::new(Mutex::new(tick_sender)),
+            tick_receiver,
+        }
+    }
+
+    pub fn advance(&self, duration: chrono::Duration) {
+        let now = *self.now.lock().unwrap() + duration;
+        self.set(now);
+    }
+
+    pub async fn run_in_real_time(self) {
+        let mut ticks = SystemClock {}.ticks();
+        // the first tick is immediate
+        ticks.next().await;
+        while ticks.next().await.is_some() {
+            self.advance(chrono::Duration::seconds(1));
+        }
+    }
+
+    pub fn set(&self, now: NaiveDateTime) {
+        *self.now.lock().unwrap() = now;
+        let _ = self
+            .tick_sender
+            .lock()
+            .unwrap()
+            .broadcast(now)
+            .map_err(|e| error!("manual clock tick error = {:?}", e));
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.now.lock().unwrap()
+    }
+
+    fn ticks(&self) -> Pin<Box<dyn Stream<Item = ()> + Send>> {
+        self.tick_receiver.clone().map(|_| ()).boxed()
+    }
+}
@@ -3,7 +3,9 @@ use std::sync::{Arc, Mutex};
 use futures::StreamExt;
 use tokio::sync::mpsc;
 
-use crate::schedule::{WateringScheduleConfig, WateringScheduleConfigs, WateringScheduler};
+use crate::communication::Request;
+use crate::embedded::configuration::LayoutConfig;
+use crate::schedule::{Error, WateringScheduleConfig, WateringScheduleConfigs, WateringScheduler};
 
 #[derive(Debug, Copy, Clone)]
 pub enum WateringConfigCommand {
@@ -13,27 +15,38 @@ pub enum WateringConfigCommand {
     Create(WateringScheduleConfig),
 }
 
+/// Answered with all schedules after the command was executed.
+pub type WateringConfigRequest =
+    Request<WateringConfigCommand, Result<WateringScheduleConfigs, Error>>;
+
 pub struct WateringConfigCommandListener {}
 
 impl WateringConfigCommandListener {
     pub async fn listen_to_commands(
         watering_config: Arc<Mutex<WateringScheduleConfigs>>,
         watering_schedule: Arc<Mutex<WateringScheduler>>,
-        mut receiver: mpsc::Receiver<WateringConfigCommand>,
+        layout_config: Arc<Mutex<LayoutConfig>>,
+        mut receiver: mpsc::Receiver<WateringConfigRequest>,
         mut watering_config_status_tx: mpsc::Sender<()>,
     ) {
-        while let Some(command) = receiver.next().await {
-            println!("{:?}", command);
-            let result: Result<(), ()> =
-                handle_command(&watering_config, &watering_schedule, command);
-            match result {
+        while let Some(request) = receiver.next().await {
+            let command = request.command;
+            info!("{:?}", command);
+            let result: Result<(), Error> = handle_command(
+                &watering_config,
+                &watering_schedule,
+                &layout_config,
+                command,
+            );
+            match &result {
                 Ok(_) => {
                     let _ = watering_config_status_tx
                         .try_send(())
-                        .map_err(|e| println!("schedule command status send error: {}", e));
+                        .map_err(|e| error!("schedule command status send error: {}", e));
                 }
-                Err(_) => println!(),
+                Err(e) => warn!("{:?}: command execution error = {}", command, e),
             }
+            request.respond(result.map(|_| watering_config.lock().unwrap().clone()));
         }
     }
 }
@@ -41,27 +54,31 @@ impl WateringConfigCommandListener {
 fn handle_command(
     watering_config: &Arc<Mutex<WateringScheduleConfigs>>,
     watering_schedule: &Arc<Mutex<WateringScheduler>>,
+    layout_config: &Arc<Mutex<LayoutConfig>>,
     command: WateringConfigCommand,
-) -> Result<(), ()> {
+) -> Result<(), Error> {
     match command {
         WateringConfigCommand::Enable(schedule) => {
-            let result: Result<WateringScheduleConfig, ()> =
+            let result: Result<WateringScheduleConfig, Error> =
                 watering_config.lock().unwrap().enable_schedule(&schedule);
             result.and_then(|s| watering_schedule.lock().unwrap().start_schedule(&s))
         }
         WateringConfigCommand::Disable(schedule) => {
-            let result: Result<WateringScheduleConfig, ()> =
+            let result: Result<WateringScheduleConfig, Error> =
                 watering_config.lock().unwrap().disable_schedule(&schedule);
             result.and_then(|s| watering_schedule.lock().unwrap().stop_schedule(&s))
         }
         WateringConfigCommand::Delete(schedule) => {
-            let result: Result<WateringScheduleConfig, ()> =
+            let result: Result<WateringScheduleConfig, Error> =
                 watering_config.lock().unwrap().delete_schedule(&schedule);
             result.and_then(|s| watering_schedule.lock().unwrap().stop_schedule(&s))
         }
         WateringConfigCommand::Create(schedule) => {
-            let result: Result<WateringScheduleConfig, ()> =
-                watering_config.lock().unwrap().create_schedule(schedule);
+            let layout_config = layout_config.lock().unwrap().clone();
+            let result: Result<WateringScheduleConfig, Error> = watering_config
+                .lock()
+                .unwrap()
+                .create_schedule(schedule, &layout_config);
             result.and_then(|s| watering_schedule.lock().unwrap().start_schedule(&s))
         }
     }
@@ -1,8 +1,19 @@
-use std::io::Write;
+use core::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
 
-#[derive(Serialize, Deserialize, Debug)]
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+
+use crate::embedded::configuration::LayoutConfig;
+use crate::schedule::Error;
+use crate::{migration, persistence};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WateringScheduleConfigs {
     pub schedules: Vec<WateringScheduleConfig>,
+    /// Where changes are saved, nothing is saved without.
+    #[serde(skip)]
+    file: Option<PathBuf>,
 }
 
 impl WateringScheduleConfigs {
@@ -10,13 +21,30 @@ impl WateringScheduleConfigs {
         &self.schedules
     }
 
+    /// The next `count` waterings of all enabled schedules after `now`, earliest first.
+    pub fn get_next_runs(&self, now: NaiveDateTime, count: usize) -> Vec<ScheduledRun> {
+        let enabled: Vec<&WateringScheduleConfig> =
+            self.schedules.iter().filter(|s| s.is_enabled()).collect();
+        // every schedule runs once a day, so `count` days after today are enough
+        let mut runs: Vec<ScheduledRun> = (0..=count as i64)
+            .flat_map(|days| {
+                let day = now.date() + Duration::days(days);
+                enabled.iter().map(move |s| s.get_run_on(day))
+            })
+            .filter(|run| run.start > now)
+            .collect();
+        runs.sort_by_key(|run| run.start);
+        runs.truncate(count);
+        runs
+    }
+
     pub fn enable_schedule(
         &mut self,
         schedule: &WateringScheduleConfig,
-    ) -> Result<WateringScheduleConfig, ()> {
+    ) -> Result<WateringScheduleConfig, Error> {
         let existing_schedule: Option<&mut WateringScheduleConfig> = self.find_schedule(schedule);
         match existing_schedule {
-            None => Err(()),
+            None => Err(Error::ScheduleNotFound(*schedule)),
             Some(s) => {
                 s.enabled = true;
                 self.save()?;
@@ -28,10 +56,10 @@ impl WateringScheduleConfigs {
     pub fn disable_schedule(
         &mut self,
         schedule: &WateringScheduleConfig,
-    ) -> Result<WateringScheduleConfig, ()> {
+    ) -> Result<WateringScheduleConfig, Error> {
         let existing_schedule: Option<&mut WateringScheduleConfig> = self.find_schedule(schedule);
         match existing_schedule {
-            None => Err(()),
+            None => Err(Error::ScheduleNotFound(*schedule)),
             Some(s) => {
                 s.enabled = false;
                 self.save()?;
@@ -43,10 +71,10 @@ impl WateringScheduleConfigs {
     pub fn delete_schedule(
         &mut self,
         schedule: &WateringScheduleConfig,
-    ) -> Result<WateringScheduleConfig, ()> {
+    ) -> Result<WateringScheduleConfig, Error> {
         let index = self.find_schedule_index(schedule);
         match index {
-            None => Err(()),
+            None => Err(Error::ScheduleNotFound(*schedule)),
             Some(i) => {
                 self.schedules.remove(i);
                 self.save()?;
@@ -57,15 +85,18 @@ impl WateringScheduleConfigs {
     pub fn create_schedule(
         &mut self,
         schedule: WateringScheduleConfig,
-    ) -> Result<WateringScheduleConfig, ()> {
-        let existing_schedule: Option<&mut WateringScheduleConfig> = self.find_schedule(&schedule);
-        match existing_schedule {
+        layout_config: &LayoutConfig,
+    ) -> Result<WateringScheduleConfig, Error> {
+        match check_schedule(&schedule, &self.schedules, layout_config)
+            .into_iter()
+            .next()
+        {
             None => {
-                self.schedules.push(schedule.clone());
+                self.schedules.push(schedule);
                 self.save()?;
                 Ok(schedule)
             }
-            Some(_) => Err(()),
+            Some(e) => Err(e),
         }
     }
 
@@ -83,32 +114,65 @@ impl WateringScheduleConfigs {
             .position(|item| item.valve == schedule.valve && item.schedule == schedule.schedule)
     }
 
-    fn save(&self) -> Result<(), ()> {
-        let json_string = serde_json::to_string(self).map_err(|_| ())?;
-        let mut file = std::fs::File::create("watering-schedules.json").map_err(|_| ())?;
-        file.write(json_string.as_bytes())
-            .map(|_| ())
-            .map_err(|_| ())
+    fn save(&self) -> Result<(), Error> {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let json_string = migration::SCHEDULES
+            .to_json(self)
+            .map_err(|e| Error::Persistence(e.to_string()))?;
+        persistence::write_atomically(path, json_string.as_bytes())
+            .map_err(|e| Error::Persistence(e.to_string()))
+    }
+}
+
+impl WateringScheduleConfigs {
+    /// Changes are saved to `file` from now on.
+    pub fn with_file(mut self, file: PathBuf) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    /// Every schedule is checked like a created one against the schedules before it.
+    pub fn validate(&self, layout_config: &LayoutConfig) -> Vec<String> {
+        self.schedules
+            .iter()
+            .enumerate()
+            .flat_map(|(i, schedule)| check_schedule(schedule, &self.schedules[..i], layout_config))
+            .map(|e| e.to_string())
+            .collect()
     }
 }
 
-impl Default for WateringScheduleConfigs {
-    fn default() -> Self {
-        let mut settings = config::Config::default();
-        settings
-            .merge(config::File::new(
-                "watering-schedules",
-                config::FileFormat::Json,
-            ))
-            .unwrap()
-            .merge(config::Environment::with_prefix("WATERING"))
-            .unwrap();
-        let watering_configs = settings
-            .try_into::<WateringScheduleConfigs>()
-            .expect("Watering schedules config contains errors");
-        println!("{:?}", watering_configs);
-        watering_configs
+/// Problems `schedule` has on its own and next to the `others`. A schedule must water a valve of
+/// the layout and end after it starts. It must not touch another schedule of the same valve,
+/// closing the valve at the end of one would race opening it at the start of the next.
+fn check_schedule(
+    schedule: &WateringScheduleConfig,
+    others: &[WateringScheduleConfig],
+    layout_config: &LayoutConfig,
+) -> Vec<Error> {
+    let mut errors = Vec::new();
+    let problems = schedule.schedule.validate();
+    if !problems.is_empty() {
+        errors.push(Error::InvalidSchedule(*schedule, problems.join(", ")));
+    }
+    if !layout_config
+        .get_valves()
+        .iter()
+        .any(|v| v.get_valve_pin_num() == schedule.valve)
+    {
+        errors.push(Error::UnknownValve(*schedule));
+    }
+    for other in others.iter().filter(|other| other.valve == schedule.valve) {
+        if other.schedule == schedule.schedule {
+            errors.push(Error::DuplicateSchedule(*schedule));
+        } else if problems.is_empty() && other.schedule.overlaps(&schedule.schedule) {
+            errors.push(Error::OverlappingSchedule(*schedule, *other));
+        }
     }
+    errors
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -118,10 +182,77 @@ pub struct WateringScheduleConfig {
     pub enabled: bool,
 }
 
+impl fmt::Display for WateringScheduleConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "valve {} {:02}:{:02}-{:02}:{:02}",
+            self.valve,
+            self.schedule.start_hour,
+            self.schedule.start_minute,
+            self.schedule.end_hour,
+            self.schedule.end_minute
+        )
+    }
+}
+
 impl WateringScheduleConfig {
+    pub fn new(valve: u8, schedule: ScheduleConfig, enabled: bool) -> Self {
+        WateringScheduleConfig {
+            schedule,
+            valve,
+            enabled,
+        }
+    }
+    /// Identifies the schedule by valve and time window, e.g. `27-0600-0630` for valve 27 from
+    /// 06:00 to 06:30.
+    pub fn get_id(&self) -> String {
+        format!(
+            "{}-{:02}{:02}-{:02}{:02}",
+            self.valve,
+            self.schedule.start_hour,
+            self.schedule.start_minute,
+            self.schedule.end_hour,
+            self.schedule.end_minute
+        )
+    }
+    /// Parses an id of `get_id`, the schedule is enabled.
+    pub fn from_id(id: &str) -> Option<WateringScheduleConfig> {
+        let parts: Vec<&str> = id.split('-').collect();
+        match parts.as_slice() {
+            [valve, start, end] if start.len() == 4 && end.len() == 4 => {
+                let schedule = ScheduleConfig::new(
+                    u8::from_str(&start[..2]).ok()?,
+                    u8::from_str(&start[2..]).ok()?,
+                    u8::from_str(&end[..2]).ok()?,
+                    u8::from_str(&end[2..]).ok()?,
+                );
+                Some(WateringScheduleConfig::new(
+                    u8::from_str(valve).ok()?,
+                    schedule,
+                    true,
+                ))
+            }
+            _ => None,
+        }
+    }
     pub fn get_schedule(&self) -> &ScheduleConfig {
         &self.schedule
     }
+    /// Watering of the schedule starting on `day`, it may end on the next day.
+    pub fn get_run_on(&self, day: NaiveDate) -> ScheduledRun {
+        let start = day.and_time(self.schedule.get_start_time());
+        let mut end = day.and_time(self.schedule.get_end_time());
+        if end <= start {
+            end += Duration::days(1);
+        }
+        ScheduledRun {
+            id: self.get_id(),
+            valve: self.valve,
+            start,
+            end,
+        }
+    }
     pub fn get_valve(&self) -> u8 {
         self.valve
     }
@@ -139,6 +270,14 @@ pub struct ScheduleConfig {
 }
 
 impl ScheduleConfig {
+    pub fn new(start_hour: u8, start_minute: u8, end_hour: u8, end_minute: u8) -> Self {
+        ScheduleConfig {
+            start_hour,
+            start_minute,
+            end_hour,
+            end_minute,
+        }
+    }
     pub fn get_start_hour(&self) -> &u8 {
         &self.start_hour
     }
@@ -151,4 +290,130 @@ impl ScheduleConfig {
     pub fn get_end_minute(&self) -> &u8 {
         &self.end_minute
     }
+    pub fn get_start_time(&self) -> NaiveTime {
+        NaiveTime::from_hms(self.start_hour as u32, self.start_minute as u32, 0)
+    }
+    pub fn get_end_time(&self) -> NaiveTime {
+        NaiveTime::from_hms(self.end_hour as u32, self.end_minute as u32, 0)
+    }
+    /// Both times must exist and the end has to come after the start, watering over midnight
+    /// takes a schedule before and one after it.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.start_hour > 23 || self.start_minute > 59 {
+            problems.push(format!(
+                "start {:02}:{:02} is not a time of day",
+                self.start_hour, self.start_minute
+            ));
+        }
+        if self.end_hour > 23 || self.end_minute > 59 {
+            problems.push(format!(
+                "end {:02}:{:02} is not a time of day",
+                self.end_hour, self.end_minute
+            ));
+        }
+        if problems.is_empty() && self.get_end_time() <= self.get_start_time() {
+            problems.push(format!(
+                "ends at {} before it starts at {}",
+                self.get_end_time().format("%H:%M"),
+                self.get_start_time().format("%H:%M")
+            ));
+        }
+        problems
+    }
+    /// Whether the windows share a minute, including one ending when the other starts.
+    fn overlaps(&self, other: &ScheduleConfig) -> bool {
+        other.validate().is_empty()
+            && self.get_start_time() <= other.get_end_time()
+            && other.get_start_time() <= self.get_end_time()
+    }
+}
+
+/// A single upcoming watering of a schedule.
+#[derive(Serialize, Debug, Clone)]
+pub struct ScheduledRun {
+    pub id: String,
+    pub valve: u8,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout_config() -> LayoutConfig {
+        serde_json::from_str(
+            r#"{"valves": [{"valve": 27, "button": 22}, {"valve": 10, "button": 9}]}"#,
+        )
+        .unwrap()
+    }
+
+    fn schedule(valve: u8, start: (u8, u8), end: (u8, u8)) -> WateringScheduleConfig {
+        WateringScheduleConfig::new(
+            valve,
+            ScheduleConfig::new(start.0, start.1, end.0, end.1),
+            true,
+        )
+    }
+
+    fn check(schedule: WateringScheduleConfig, others: &[WateringScheduleConfig]) -> Vec<String> {
+        check_schedule(&schedule, others, &layout_config())
+            .iter()
+            .map(|e| e.code().to_string())
+            .collect::<Vec<String>>()
+    }
+
+    #[test]
+    fn schedules_of_the_same_valve_must_not_overlap() {
+        let evening = schedule(27, (18, 0), (18, 30));
+
+        assert_eq!(
+            check(schedule(27, (18, 15), (18, 45)), &[evening]),
+            vec!["OVERLAPPING_SCHEDULE"]
+        );
+        assert_eq!(
+            check(schedule(27, (18, 30), (19, 0)), &[evening]),
+            vec!["OVERLAPPING_SCHEDULE"]
+        );
+        assert!(check(schedule(27, (18, 31), (19, 0)), &[evening]).is_empty());
+        assert!(check(schedule(10, (18, 15), (18, 45)), &[evening]).is_empty());
+    }
+
+    #[test]
+    fn schedules_must_end_after_they_start() {
+        for (start, end) in &[((18, 0), (18, 0)), ((23, 30), (0, 30)), ((24, 0), (18, 0))] {
+            assert_eq!(
+                check(schedule(27, *start, *end), &[]),
+                vec!["INVALID_SCHEDULE"]
+            );
+        }
+        assert!(check(schedule(27, (0, 0), (23, 59)), &[]).is_empty());
+    }
+
+    #[test]
+    fn duplicate_schedules_are_rejected() {
+        let evening = schedule(27, (18, 0), (18, 30));
+
+        assert_eq!(check(evening, &[evening]), vec!["DUPLICATE_SCHEDULE"]);
+    }
+
+    #[test]
+    fn schedules_must_water_a_valve_of_the_layout() {
+        assert_eq!(
+            check(schedule(99, (18, 0), (18, 30)), &[]),
+            vec!["UNKNOWN_VALVE"]
+        );
+    }
+
+    #[test]
+    fn runs_over_midnight_end_on_the_next_day() {
+        let run = schedule(27, (23, 30), (0, 30)).get_run_on(NaiveDate::from_ymd(2020, 6, 1));
+
+        assert_eq!(
+            run.start,
+            NaiveDate::from_ymd(2020, 6, 1).and_hms(23, 30, 0)
+        );
+        assert_eq!(run.end, NaiveDate::from_ymd(2020, 6, 2).and_hms(0, 30, 0));
+    }
 }
@@ -1,8 +1,65 @@
-pub use self::command::{WateringConfigCommand, WateringConfigCommandListener};
-pub use self::configuration::{ScheduleConfig, WateringScheduleConfig, WateringScheduleConfigs};
+use core::fmt;
+
+pub use self::clock::{Clock, ManualClock, SystemClock};
+pub use self::command::{
+    WateringConfigCommand, WateringConfigCommandListener, WateringConfigRequest,
+};
+pub use self::configuration::{ScheduledRun, WateringScheduleConfig, WateringScheduleConfigs};
 pub use self::watering::WateringScheduler;
 
+mod clock;
 mod command;
 mod configuration;
 mod watering;
 mod watering_task;
+
+#[derive(Debug)]
+pub enum Error {
+    ScheduleNotFound(WateringScheduleConfig),
+    DuplicateSchedule(WateringScheduleConfig),
+    OverlappingSchedule(WateringScheduleConfig, WateringScheduleConfig),
+    InvalidSchedule(WateringScheduleConfig, String),
+    UnknownValve(WateringScheduleConfig),
+    ScheduleNotRunning(WateringScheduleConfig),
+    Persistence(String),
+    Scheduler(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ScheduleNotFound(ref s) => write!(f, "Schedule not found: {}", s),
+            Error::DuplicateSchedule(ref s) => write!(f, "Schedule already exists: {}", s),
+            Error::OverlappingSchedule(ref s, ref other) => {
+                write!(f, "Schedule {} overlaps schedule {}", s, other)
+            }
+            Error::InvalidSchedule(ref s, ref problem) => {
+                write!(f, "Invalid schedule {}: {}", s, problem)
+            }
+            Error::UnknownValve(ref s) => {
+                write!(f, "Schedule {} waters a valve not in the layout", s)
+            }
+            Error::ScheduleNotRunning(ref s) => write!(f, "Schedule is not running: {}", s),
+            Error::Persistence(ref s) => write!(f, "Could not persist schedules: {}", s),
+            Error::Scheduler(ref s) => write!(f, "Scheduler: {}", s),
+        }
+    }
+}
+
+impl Error {
+    /// Stable identifier of the error kind for remote callers.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::ScheduleNotFound(_) => "SCHEDULE_NOT_FOUND",
+            Error::DuplicateSchedule(_) => "DUPLICATE_SCHEDULE",
+            Error::OverlappingSchedule(_, _) => "OVERLAPPING_SCHEDULE",
+            Error::InvalidSchedule(_, _) => "INVALID_SCHEDULE",
+            Error::UnknownValve(_) => "UNKNOWN_VALVE",
+            Error::ScheduleNotRunning(_) => "SCHEDULE_NOT_RUNNING",
+            Error::Persistence(_) => "PERSISTENCE",
+            Error::Scheduler(_) => "SCHEDULER",
+        }
+    }
+}
+
+impl std::error::Error for Error {}
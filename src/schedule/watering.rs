@@ -6,44 +6,45 @@ use futures::prelude::*;
 use tokio::sync::mpsc::Sender;
 
 use crate::communication::get_ctrl_c_future;
-use crate::embedded::command::LayoutCommand;
+use crate::embedded::command::LayoutRequest;
 use crate::embedded::ValvePinNumber;
+use crate::schedule::clock::Clock;
 use crate::schedule::configuration::WateringScheduleConfigs;
 use crate::schedule::watering_task::WateringTask;
-use crate::schedule::{ScheduleConfig, WateringScheduleConfig};
+use crate::schedule::{Error, WateringScheduleConfig};
 
 pub struct WateringScheduler {
     senders: Arc<Mutex<HashMap<WateringScheduleConfig, Sender<()>>>>,
     ctrl_c_receiver: tokio::sync::watch::Receiver<String>,
-    command_sender: Sender<LayoutCommand>,
+    command_sender: Sender<LayoutRequest>,
+    clock: Arc<dyn Clock>,
 }
 
 impl WateringScheduler {
     pub fn new(
-        command_sender: Sender<LayoutCommand>,
+        command_sender: Sender<LayoutRequest>,
         ctrl_c_receiver: tokio::sync::watch::Receiver<String>,
+        clock: Arc<dyn Clock>,
     ) -> WateringScheduler {
         let senders = Arc::new(Mutex::new(HashMap::new()));
         WateringScheduler {
             senders,
             command_sender,
             ctrl_c_receiver,
+            clock,
         }
     }
 
-    pub fn start_schedule(&mut self, schedule: &WateringScheduleConfig) -> Result<(), ()> {
+    pub fn start_schedule(&mut self, schedule: &WateringScheduleConfig) -> Result<(), Error> {
         self.spawn_schedule_task(schedule);
         Ok(())
     }
 
-    pub fn stop_schedule(&mut self, schedule: &WateringScheduleConfig) -> Result<(), ()> {
+    pub fn stop_schedule(&mut self, schedule: &WateringScheduleConfig) -> Result<(), Error> {
         let sender = self.senders.lock().unwrap().remove(&schedule);
         match sender {
-            None => Err(()),
-            Some(mut s) => {
-                s.try_send(()).map_err(|e| println!("error = {:?}", e))?;
-                Ok(())
-            }
+            None => Err(Error::ScheduleNotRunning(*schedule)),
+            Some(mut s) => s.try_send(()).map_err(|e| Error::Scheduler(e.to_string())),
         }
     }
 
@@ -56,7 +57,7 @@ impl WateringScheduler {
     }
 
     fn spawn_schedule_task(&mut self, schedule: &WateringScheduleConfig) {
-        println!(
+        info!(
             "Creating watering schedule for valve {}",
             schedule.get_valve()
         );
@@ -65,6 +66,7 @@ impl WateringScheduler {
             self.command_sender.clone(),
             *schedule,
             self.ctrl_c_receiver.clone(),
+            Arc::clone(&self.clock),
         )
         .boxed()
         .fuse();
@@ -74,27 +76,18 @@ impl WateringScheduler {
 
 async fn create_schedule(
     senders: Arc<Mutex<HashMap<WateringScheduleConfig, tokio::sync::mpsc::Sender<()>>>>,
-    command_sender: Sender<LayoutCommand>,
+    command_sender: Sender<LayoutRequest>,
     schedule_config: WateringScheduleConfig,
     ctrl_c_receiver: tokio::sync::watch::Receiver<String>,
+    clock: Arc<dyn Clock>,
 ) {
     let number = ValvePinNumber(schedule_config.get_valve());
 
-    let start_time: NaiveTime = get_schedule_start_time(&schedule_config.get_schedule());
-    let end_time = get_schedule_end_time(&schedule_config.get_schedule());
+    let start_time: NaiveTime = schedule_config.get_schedule().get_start_time();
+    let end_time = schedule_config.get_schedule().get_end_time();
 
-    let mut start_task = WateringTask::new(
-        LayoutCommand::Open(number),
-        start_time,
-        command_sender.clone(),
-    )
-    .fuse();
-    let mut end_task = WateringTask::new(
-        LayoutCommand::Close(number),
-        end_time,
-        command_sender.clone(),
-    )
-    .fuse();
+    let mut watering_task =
+        WateringTask::new(number, start_time, end_time, command_sender, clock).fuse();
 
     let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
     senders.lock().unwrap().insert(schedule_config, sender);
@@ -102,25 +95,8 @@ async fn create_schedule(
     let mut ctrl_c_receiver_future = get_ctrl_c_future(ctrl_c_receiver);
 
     select! {
-        _ = start_task => {},
-        _ = end_task => {},
+        _ = watering_task => {},
         _ = receiver_future => {}, // TODO test shutoffsenders
         _ = ctrl_c_receiver_future => {}, // TODO test shutoffsenders
     };
 }
-
-fn get_schedule_start_time(config: &ScheduleConfig) -> NaiveTime {
-    NaiveTime::from_hms(
-        *config.get_start_hour() as u32,
-        *config.get_start_minute() as u32,
-        0,
-    )
-}
-
-fn get_schedule_end_time(config: &ScheduleConfig) -> NaiveTime {
-    NaiveTime::from_hms(
-        *config.get_end_hour() as u32,
-        *config.get_end_minute() as u32,
-        0,
-    )
-}
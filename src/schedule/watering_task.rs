@@ -1,35 +1,50 @@
 use std::pin::Pin;
-use std::time::Duration;
+use std::sync::Arc;
 
-use chrono::{Local, NaiveTime, Timelike};
+use chrono::NaiveTime;
 use futures::prelude::*;
 use futures::task::{Context, Poll};
 use futures::FutureExt;
 use tokio::sync::mpsc::Sender;
 
-use crate::embedded::command::LayoutCommand;
+use crate::communication::Request;
+use crate::embedded::command::{LayoutCommand, LayoutRequest, Origin};
+use crate::embedded::ValvePinNumber;
+use crate::schedule::clock::Clock;
 
+/// Opens the valve when the clock enters the watering window and closes it when the clock leaves
+/// it. Following the window rather than its start and end keeps the valve right when the wall
+/// clock jumps, e.g. at the first NTP sync of a Pi without a real time clock.
 pub struct WateringTask {
     inner: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl WateringTask {
     pub fn new(
-        layout_command: LayoutCommand,
-        execution_time: NaiveTime,
-        mut command_sender: Sender<LayoutCommand>,
+        valve: ValvePinNumber,
+        start_time: NaiveTime,
+        end_time: NaiveTime,
+        mut command_sender: Sender<LayoutRequest>,
+        clock: Arc<dyn Clock>,
     ) -> WateringTask {
-        let task = tokio::time::interval(Duration::from_secs(1))
-            .filter(move |_| {
-                let now = Local::now().time();
-                let now_seconds_precision =
-                    NaiveTime::from_hms(now.hour(), now.minute(), now.second());
-                future::ready(now_seconds_precision.eq(&execution_time))
+        // a window that is already running is only closed
+        let mut was_inside = is_inside(clock.now().time(), start_time, end_time);
+        let task = clock
+            .ticks()
+            .filter_map(move |_| {
+                let inside = is_inside(clock.now().time(), start_time, end_time);
+                let command = match (was_inside, inside) {
+                    (false, true) => Some(LayoutCommand::Open(valve, Origin::Schedule)),
+                    (true, false) => Some(LayoutCommand::Close(valve, Origin::Schedule)),
+                    _ => None,
+                };
+                was_inside = inside;
+                future::ready(command)
             })
-            .for_each(move |_| {
+            .for_each(move |command| {
                 command_sender
-                    .try_send(layout_command)
-                    .map_err(|e| println!("error = {}", e))
+                    .try_send(Request::new(command))
+                    .map_err(|e| error!("error = {}", e))
                     .unwrap_or(());
                 future::ready(())
             })
@@ -38,6 +53,15 @@ impl WateringTask {
     }
 }
 
+/// True if `time` lies in `[start, end)`, a window that ends before it starts runs over midnight.
+fn is_inside(time: NaiveTime, start: NaiveTime, end: NaiveTime) -> bool {
+    if start <= end {
+        start <= time && time < end
+    } else {
+        start <= time || time < end
+    }
+}
+
 impl Future for WateringTask {
     type Output = ();
 
@@ -45,3 +69,159 @@ impl Future for WateringTask {
         self.inner.poll_unpin(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{NaiveDate, NaiveDateTime};
+    use tokio::sync::mpsc::{self, Receiver};
+
+    use crate::schedule::ManualClock;
+
+    use super::*;
+
+    const VALVE: ValvePinNumber = ValvePinNumber(27);
+
+    fn at(day: u32, h: u32, m: u32, s: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2020, 6, day).and_hms(h, m, s)
+    }
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms(h, m, 0)
+    }
+
+    async fn next_command(receiver: &mut Receiver<LayoutRequest>) -> Option<LayoutCommand> {
+        tokio::time::timeout(Duration::from_millis(100), receiver.recv())
+            .await
+            .ok()
+            .flatten()
+            .map(|request| request.command)
+    }
+
+    fn spawn_task(
+        start: NaiveTime,
+        end: NaiveTime,
+        clock: &ManualClock,
+    ) -> Receiver<LayoutRequest> {
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(WateringTask::new(
+            VALVE,
+            start,
+            end,
+            sender,
+            Arc::new(clock.clone()),
+        ));
+        receiver
+    }
+
+    #[tokio::test]
+    async fn valve_opens_at_the_start_and_closes_at_the_end() {
+        let clock = ManualClock::new(at(1, 18, 3, 58));
+        let mut commands = spawn_task(time(18, 4), time(18, 34), &clock);
+
+        clock.set(at(1, 18, 3, 59));
+        assert!(next_command(&mut commands).await.is_none());
+        clock.set(at(1, 18, 4, 0));
+        assert!(matches!(
+            next_command(&mut commands).await,
+            Some(LayoutCommand::Open(VALVE, Origin::Schedule))
+        ));
+        clock.set(at(1, 18, 33, 59));
+        assert!(next_command(&mut commands).await.is_none());
+
+        clock.set(at(1, 18, 34, 0));
+        assert!(matches!(
+            next_command(&mut commands).await,
+            Some(LayoutCommand::Close(VALVE, Origin::Schedule))
+        ));
+        clock.set(at(1, 18, 34, 1));
+        assert!(next_command(&mut commands).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn skipped_ticks_still_open_the_valve_once() {
+        let clock = ManualClock::new(at(1, 18, 3, 59));
+        let mut commands = spawn_task(time(18, 4), time(18, 34), &clock);
+
+        clock.set(at(1, 18, 4, 1));
+        assert!(next_command(&mut commands).await.is_some());
+        clock.set(at(1, 18, 4, 2));
+        assert!(next_command(&mut commands).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn windows_run_again_the_next_day() {
+        let clock = ManualClock::new(at(1, 23, 59, 59));
+        let mut commands = spawn_task(time(0, 0), time(0, 30), &clock);
+
+        clock.set(at(2, 0, 0, 0));
+        assert!(next_command(&mut commands).await.is_some());
+        clock.set(at(2, 0, 30, 0));
+        assert!(next_command(&mut commands).await.is_some());
+        clock.set(at(2, 23, 59, 59));
+        assert!(next_command(&mut commands).await.is_none());
+        clock.set(at(3, 0, 0, 1));
+        assert!(next_command(&mut commands).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn clock_jumps_over_a_window_leave_the_valve_closed() {
+        let clock = ManualClock::new(at(1, 18, 0, 0));
+        let mut commands = spawn_task(time(18, 4), time(18, 34), &clock);
+
+        clock.set(at(1, 19, 0, 0));
+        assert!(next_command(&mut commands).await.is_none());
+        // e.g. the first NTP sync after a boot without a real time clock
+        clock.set(at(3, 17, 0, 0));
+        assert!(next_command(&mut commands).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn clock_jumps_out_of_a_window_close_the_valve() {
+        let clock = ManualClock::new(at(1, 18, 3, 59));
+        let mut commands = spawn_task(time(18, 4), time(18, 34), &clock);
+
+        clock.set(at(1, 18, 4, 0));
+        assert!(matches!(
+            next_command(&mut commands).await,
+            Some(LayoutCommand::Open(VALVE, Origin::Schedule))
+        ));
+        clock.set(at(2, 17, 0, 0));
+        assert!(matches!(
+            next_command(&mut commands).await,
+            Some(LayoutCommand::Close(VALVE, Origin::Schedule))
+        ));
+        assert!(next_command(&mut commands).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn running_windows_are_only_closed() {
+        let clock = ManualClock::new(at(1, 18, 10, 0));
+        let mut commands = spawn_task(time(18, 4), time(18, 34), &clock);
+
+        clock.set(at(1, 18, 10, 1));
+        assert!(next_command(&mut commands).await.is_none());
+        clock.set(at(1, 18, 34, 0));
+        assert!(matches!(
+            next_command(&mut commands).await,
+            Some(LayoutCommand::Close(VALVE, Origin::Schedule))
+        ));
+    }
+
+    #[test]
+    fn windows_include_the_start_but_not_the_end() {
+        assert!(!is_inside(time(18, 3), time(18, 4), time(18, 34)));
+        assert!(is_inside(time(18, 4), time(18, 4), time(18, 34)));
+        assert!(is_inside(time(18, 33), time(18, 4), time(18, 34)));
+        assert!(!is_inside(time(18, 34), time(18, 4), time(18, 34)));
+    }
+
+    #[test]
+    fn windows_may_run_over_midnight() {
+        assert!(is_inside(time(23, 45), time(23, 30), time(0, 30)));
+        assert!(is_inside(time(0, 15), time(23, 30), time(0, 30)));
+        assert!(!is_inside(time(0, 30), time(23, 30), time(0, 30)));
+        assert!(!is_inside(time(12, 0), time(23, 30), time(0, 30)));
+    }
+}